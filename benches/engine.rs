@@ -0,0 +1,189 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use payments::{
+    amount::Amount,
+    engine::{self, EngineConfig},
+    state::EngineState,
+    transaction::{Mutation, TransactionType},
+};
+use std::{fmt::Write, fs, io};
+
+/// The number of rows in the synthetic input.
+const ROWS: usize = 10_000_000;
+/// The number of clients the rows are spread over.
+const CLIENTS: usize = 1_000;
+/// The number of rows in the input of each scenario, which is smaller than [ROWS] since the
+/// scenarios are also held in memory as mutations.
+const SCENARIO_ROWS: usize = 1_000_000;
+
+/// Generates a synthetic input in which every client alternates between depositing and withdrawing
+/// the same amount, so that every row is valid.
+fn synthetic_input(rows: usize) -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 0..rows {
+        let client = tx % CLIENTS;
+        let kind = match (tx / CLIENTS) % 2 {
+            0 => "deposit",
+            _ => "withdrawal",
+        };
+        writeln!(input, "{kind},{client},{tx},1.0").unwrap();
+    }
+    input
+}
+
+/// The kinds of traffic that the engine is measured with.
+#[derive(Clone, Copy)]
+enum Scenario {
+    /// Every row is a deposit.
+    Deposits,
+    /// Every client alternates between depositing and withdrawing the same amount.
+    Mixed,
+    /// Every client deposits, disputes the deposit and resolves it again, so that two out of three
+    /// rows look up a recorded transaction.
+    Disputes,
+}
+
+impl Scenario {
+    const ALL: [(Scenario, &'static str); 3] = [
+        (Scenario::Deposits, "deposits"),
+        (Scenario::Mixed, "mixed"),
+        (Scenario::Disputes, "disputes"),
+    ];
+
+    /// The kind of the mutation in row `row`, and the transaction that it applies to.
+    fn row(self, row: usize) -> (TransactionType, usize) {
+        match self {
+            Scenario::Deposits => (TransactionType::Deposit, row),
+            Scenario::Mixed => match (row / CLIENTS) % 2 {
+                0 => (TransactionType::Deposit, row),
+                _ => (TransactionType::Withdrawal, row),
+            },
+            // A cycle takes three rows of every client, all referencing the deposit in the first.
+            Scenario::Disputes => {
+                let deposit = row - (row / CLIENTS % 3) * CLIENTS;
+                let kind = match (row / CLIENTS) % 3 {
+                    0 => TransactionType::Deposit,
+                    1 => TransactionType::Dispute,
+                    _ => TransactionType::Resolve,
+                };
+                (kind, deposit)
+            }
+        }
+    }
+
+    /// Generates `rows` rows of this scenario as csv.
+    fn csv(self, rows: usize) -> String {
+        let mut input = String::from("type,client,tx,amount\n");
+        for row in 0..rows {
+            let (kind, tx) = self.row(row);
+            let client = row % CLIENTS;
+            let (kind, amount) = match kind {
+                TransactionType::Deposit => ("deposit", "1.0"),
+                TransactionType::Withdrawal => ("withdrawal", "1.0"),
+                TransactionType::Dispute => ("dispute", ""),
+                _ => ("resolve", ""),
+            };
+            writeln!(input, "{kind},{client},{tx},{amount}").unwrap();
+        }
+        input
+    }
+
+    /// Generates `rows` rows of this scenario as mutations, which leaves out the parsing.
+    fn mutations(self, rows: usize) -> Vec<Mutation> {
+        let mutation = |row| {
+            let (kind, tx) = self.row(row);
+            let has_amount = matches!(kind, TransactionType::Deposit | TransactionType::Withdrawal);
+            Mutation {
+                id: tx as u32,
+                kind,
+                client: (row % CLIENTS) as u32,
+                amount: has_amount.then(|| Amount(10_000)),
+                currency: None,
+                to_client: None,
+            }
+        };
+        (0..rows).map(mutation).collect()
+    }
+}
+
+/// Measures the throughput of the engine for each [Scenario], both for csv input, and for
+/// mutations that are already in memory, which tells apart the cost of parsing from the cost of
+/// applying the mutations.
+fn bench_scenarios(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scenarios");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SCENARIO_ROWS as u64));
+    let config = EngineConfig::default();
+    for (scenario, name) in Scenario::ALL {
+        let input = scenario.csv(SCENARIO_ROWS);
+        group.bench_with_input(BenchmarkId::new("csv", name), &input, |b, input| {
+            b.iter(|| engine::process(input.as_bytes(), io::sink(), &config).unwrap())
+        });
+        let mutations = scenario.mutations(SCENARIO_ROWS);
+        group.bench_with_input(
+            BenchmarkId::new("mutations", name),
+            &mutations,
+            |b, mutations| {
+                b.iter_batched(
+                    || (EngineState::default(), mutations.clone()),
+                    |(mut state, mutations)| {
+                        engine::ingest_source(&mut state, mutations.into_iter(), &config).unwrap()
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Measures the throughput of the engine for an increasing number of threads.
+fn bench_threads(c: &mut Criterion) {
+    let input = synthetic_input(ROWS);
+    let mut group = c.benchmark_group("threads");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ROWS as u64));
+    for threads in [1, 2, 4, 8] {
+        let config = EngineConfig {
+            threads,
+            ..Default::default()
+        };
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &config,
+            |b, config| b.iter(|| engine::process(input.as_bytes(), io::sink(), config).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+/// Measures the throughput of the engine when reading a large file with the default buffer size and
+/// with a buffer of 1 MiB.
+fn bench_buffer_size(c: &mut Criterion) {
+    let path = std::env::temp_dir().join("payments-bench-buffer-size.csv");
+    fs::write(&path, synthetic_input(ROWS)).unwrap();
+    let mut group = c.benchmark_group("buffer_size");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ROWS as u64));
+    for buffer_size in [engine::DEFAULT_BUFFER_SIZE, 1024 * 1024] {
+        let config = EngineConfig {
+            buffer_size,
+            ..Default::default()
+        };
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &config,
+            |b, config| {
+                b.iter(|| {
+                    let input =
+                        io::BufReader::with_capacity(buffer_size, fs::File::open(&path).unwrap());
+                    engine::process(input, io::sink(), config).unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+    fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, bench_scenarios, bench_threads, bench_buffer_size);
+criterion_main!(benches);
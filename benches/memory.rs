@@ -0,0 +1,81 @@
+//! Compares the peak heap usage of the engine with an unbounded and a bounded transaction history.
+//! Criterion measures time rather than memory, so this is a plain binary that counts the
+//! allocations itself.
+
+use payments::{
+    engine::{self, EngineConfig},
+    state::EngineState,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The number of rows in the synthetic input.
+const ROWS: usize = 1_000_000;
+/// The number of clients the rows are spread over.
+const CLIENTS: usize = 1_000;
+
+/// An allocator that keeps track of the number of bytes that are allocated, and of the highest
+/// number of bytes that were allocated at once.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Generates a synthetic input of deposits only, so that every row is recorded in the history.
+fn synthetic_input(rows: usize) -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 0..rows {
+        writeln!(input, "deposit,{},{tx},1.0", tx % CLIENTS).unwrap();
+    }
+    input
+}
+
+/// Returns the peak number of bytes that were allocated while ingesting `input`, on top of what was
+/// allocated before.
+fn peak_usage(input: &str, config: &EngineConfig) -> usize {
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let mut state = EngineState::default();
+    engine::ingest(&mut state, input.as_bytes(), config).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed);
+    drop(state);
+    peak - baseline
+}
+
+fn main() {
+    let input = synthetic_input(ROWS);
+    for limit in [None, Some(10_000)] {
+        let config = EngineConfig {
+            history_limit: limit,
+            ..Default::default()
+        };
+        let peak = peak_usage(&input, &config);
+        let limit = limit.map_or("unbounded".to_string(), |limit| limit.to_string());
+        println!(
+            "history {limit:>9}: peak heap usage of {:.1} MiB",
+            peak as f64 / (1 << 20) as f64
+        );
+    }
+}
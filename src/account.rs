@@ -1,143 +1,1002 @@
-use crate::transaction::{Mutation, Transaction, TransactionStatus, TransactionType, Transactions};
-use eyre::{eyre, Result};
-use std::collections::HashMap;
+use crate::{
+    amount::{Amount, Currency},
+    error::TransactionError,
+    transaction::{Mutation, Transaction, TransactionStatus, TransactionType, Transactions},
+};
+use std::{collections::HashMap, fmt};
+
+/// Accounts are identified by their client and their currency, since a client has a separate
+/// account for every currency that it holds.
+type Key = (u32, Option<Currency>);
 
 /// A collection of all the accounts we have accumulated so far, indexable by account id.
-#[derive(Default)]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct Accounts {
-    /// A map from account id to the account info struct.
-    accounts: HashMap<u16, Account>,
+    /// A map from client and currency to the account info struct. In a snapshot this is a list of
+    /// accounts, since the key can not be a JSON object key, but the map of older snapshots, which
+    /// is keyed by the client alone, can still be read.
+    #[serde(serialize_with = "serialize_accounts")]
+    #[serde(deserialize_with = "deserialize_accounts")]
+    accounts: HashMap<Key, Account>,
 }
 
 impl Accounts {
-    pub fn account_for_id(&mut self, client: u16) -> &mut Account {
-        self.accounts.entry(client).or_insert(Account::new(client))
+    /// Looks up the account of `client` in `currency` without creating it. This returns `None` for
+    /// accounts that no transaction has been applied to yet.
+    pub fn get(&self, client: u32, currency: Option<Currency>) -> Option<&Account> {
+        self.accounts.get(&(client, currency))
+    }
+
+    /// Iterates over the accounts in no particular order, without consuming the collection.
+    pub fn iter(&self) -> std::collections::hash_map::Values<'_, Key, Account> {
+        self.accounts.values()
+    }
+
+    /// Removes all accounts, but keeps the allocated memory, so that the collection can be reused
+    /// for an unrelated input.
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+    }
+
+    pub fn account_for_id(&mut self, client: u32, currency: Option<Currency>) -> &mut Account {
+        (self.accounts.entry((client, currency)))
+            .or_insert_with(|| Account::new(client).in_currency(currency))
+    }
+
+    /// Applies `trx`, which was read from row `row` of the input, to the account of its client,
+    /// following `policy`. An account is only created when the mutation is applied, so a row that
+    /// is ignored or fails, like a dispute for an unknown transaction, does not leave an empty
+    /// account behind. When the mutation is applied, the row is recorded as the
+    /// [Account::last_row]. A transfer applies to the accounts of both of its clients, see
+    /// [Accounts::transfer].
+    pub fn mutate(
+        &mut self,
+        trx: Mutation,
+        row: u64,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        if trx.kind == TransactionType::Transfer {
+            return self.transfer(trx, row, trxs);
+        }
+        let key = (trx.client, trx.account_currency(trxs));
+        let is_new = !self.accounts.contains_key(&key);
+        let account = self.account_for_id(key.0, key.1);
+        let result = account.mutate_with(trx, trxs, policy);
+        match result {
+            Ok(Outcome::Applied) => account.last_row = row,
+            _ if is_new => {
+                self.accounts.remove(&key);
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Moves the amount of the transfer `trx` from the account of its client to the account of its
+    /// [Mutation::to_client], in the currency of the transfer. Both sides are worked out before
+    /// either account is changed, so when the source does not have the amount available, or either
+    /// account is locked, the transfer fails as a whole. Unlike a withdrawal, the source may never
+    /// be overdrawn. The transfer is recorded under the client that it is from.
+    fn transfer(
+        &mut self,
+        trx: Mutation,
+        row: u64,
+        trxs: &mut Transactions,
+    ) -> Result<Outcome, TransactionError> {
+        let (tx, from, currency) = (trx.id, trx.client, trx.currency);
+        let to = trx
+            .to_client
+            .ok_or(TransactionError::MissingRecipient { tx })?;
+        if from == to {
+            return Err(TransactionError::SelfTransfer { tx, client: from });
+        }
+        let transfer: Transaction = trx.try_into()?;
+        if trxs.check_replay(&transfer)? {
+            return Ok(Outcome::Ignored(IgnoreReason::Replayed));
+        }
+        let account = |client| match self.accounts.get(&(client, currency)) {
+            Some(account) => *account,
+            None => Account::new(client).in_currency(currency),
+        };
+        let (source, recipient) = (account(from), account(to));
+        if let Some(locked) = [source, recipient].iter().find(|account| account.locked) {
+            let client = locked.client;
+            return Err(TransactionError::AccountLocked { tx, client });
+        }
+        let amount = transfer.amount;
+        let short = || TransactionError::InsufficientFunds {
+            tx,
+            client: from,
+            amount,
+        };
+        let overflow = || TransactionError::Overflow {
+            tx,
+            client: to,
+            amount,
+        };
+        let source = Account {
+            available: source.available.checked_sub(amount).ok_or_else(short)?,
+            total: source.total.checked_sub(amount).ok_or_else(short)?,
+            last_row: row,
+            ..source
+        };
+        let recipient = Account {
+            available: recipient
+                .available
+                .checked_add(amount)
+                .ok_or_else(overflow)?,
+            total: recipient.total.checked_add(amount).ok_or_else(overflow)?,
+            last_row: row,
+            ..recipient
+        };
+        tracing::debug!(%amount, from, to, "Transferred");
+        self.accounts.insert((from, currency), source);
+        self.accounts.insert((to, currency), recipient);
+        trxs.record(transfer);
+        Ok(Outcome::Applied)
+    }
+
+    /// Adds up the totals of all accounts, in smallest increments. This can not overflow, since it
+    /// would take more accounts than there are clients and currencies.
+    pub fn sum_of_totals(&self) -> i128 {
+        self.iter().map(|account| i128::from(account.total.0)).sum()
+    }
+
+    /// Moves all accounts from `other` into this collection. The two collections are expected to
+    /// contain distinct accounts, if they do not, the accounts from `other` take precedence.
+    pub fn merge(&mut self, other: Accounts) {
+        self.accounts.extend(other.accounts);
+    }
+}
+
+fn serialize_accounts<S>(accounts: &HashMap<Key, Account>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_seq(accounts.values())
+}
+
+fn deserialize_accounts<'de, D>(deserializer: D) -> Result<HashMap<Key, Account>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Snapshot {
+        List(Vec<Account>),
+        // The keys are the clients, which are also in the accounts themselves.
+        ByClient(HashMap<String, Account>),
+    }
+    let accounts = match serde::Deserialize::deserialize(deserializer)? {
+        Snapshot::List(accounts) => accounts,
+        Snapshot::ByClient(accounts) => accounts.into_values().collect(),
+    };
+    let accounts = accounts
+        .into_iter()
+        .map(|acc| ((acc.client, acc.currency), acc));
+    Ok(accounts.collect())
+}
+
+impl Extend<Account> for Accounts {
+    fn extend<T: IntoIterator<Item = Account>>(&mut self, iter: T) {
+        let accounts =
+            (iter.into_iter()).map(|account| ((account.client, account.currency), account));
+        self.accounts.extend(accounts);
     }
 }
 
 impl IntoIterator for Accounts {
     type Item = Account;
 
-    type IntoIter = std::collections::hash_map::IntoValues<u16, Account>;
+    type IntoIter = std::collections::hash_map::IntoValues<Key, Account>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.accounts.into_values()
     }
 }
 
-/// A users account state. Since we are working with money, we do not store amounts as floats, but
-/// rather we store the amount of smallest possible increments as an unsigned integer. This amount
-/// if 0.0001 currency, since we are expected to maintain a precision of 4 decimals.
+impl<'a> IntoIterator for &'a Accounts {
+    type Item = &'a Account;
+
+    type IntoIter = std::collections::hash_map::Values<'a, Key, Account>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// The result of successfully mutating an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The mutation was applied to the account.
+    Applied,
+    /// The mutation was ignored, because we assume it is an error on our partners side. The
+    /// reason describes why it was ignored.
+    Ignored(IgnoreReason),
+}
+
+/// The reasons why a mutation is ignored. These are kept apart, since they point at different
+/// problems on our partners side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreReason {
+    /// A withdrawal, or the dispute of a deposit, exceeds the available funds.
+    InsufficientFunds,
+    /// A dispute, resolve or chargeback references a transaction that we do not know, which is
+    /// likely a bug on our partners side.
+    UnknownTransaction,
+    /// A dispute references a transaction that is already under dispute, which is likely a
+    /// duplicate row.
+    AlreadyDisputed,
+    /// A dispute, resolve or chargeback references a transaction that was charged back, which is
+    /// final.
+    ChargedBack,
+    /// A resolve or chargeback references a transaction that is not under dispute, or for a
+    /// chargeback, that was not resolved either.
+    NotDisputed,
+    /// A deposit, withdrawal, interest payment or fee is identical to one that was already
+    /// recorded, which happens when a pipeline delivers the same rows again.
+    Replayed,
+    /// The row was applied before, according to the log of applied rows of the state that is
+    /// resumed. The account never returns this itself.
+    AlreadyApplied,
+    /// An unlock is for an account that is not locked, so there is nothing to reactivate.
+    NotLocked,
+}
+
+impl fmt::Display for IgnoreReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            IgnoreReason::InsufficientFunds => "insufficient funds",
+            IgnoreReason::UnknownTransaction => "unknown transaction",
+            IgnoreReason::AlreadyDisputed => "transaction is already disputed",
+            IgnoreReason::ChargedBack => "transaction was charged back",
+            IgnoreReason::NotDisputed => "transaction is not disputed",
+            IgnoreReason::Replayed => "replay of a recorded transaction",
+            IgnoreReason::AlreadyApplied => "row was applied to the resumed state before",
+            IgnoreReason::NotLocked => "account is not locked",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// What to do with the dispute of a deposit when the client no longer has the deposited funds
+/// available, because they were withdrawn in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnfundedDispute {
+    /// The dispute is an error, which is the default.
+    Fail,
+    /// The deposited amount is held regardless, which draws `available` below zero, so that the
+    /// funds are clawed back from whatever the client deposits next.
+    Hold,
+    /// The dispute is ignored, leaving the account unchanged.
+    Ignore,
+}
+
+impl Default for UnfundedDispute {
+    fn default() -> Self {
+        UnfundedDispute::Fail
+    }
+}
+
+/// What to do with a chargeback that takes more out of the account than the client has left,
+/// because the funds were spent before the transaction was charged back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UncoveredChargeback {
+    /// The chargeback is an error, which is the default.
+    Fail,
+    /// The chargeback is applied regardless, which draws the balances of the account below zero,
+    /// regardless of the overdraft limit.
+    Overdraw,
+    /// Like [UncoveredChargeback::Overdraw], but the account is marked for review as well.
+    Review,
+}
+
+impl Default for UncoveredChargeback {
+    fn default() -> Self {
+        UncoveredChargeback::Fail
+    }
+}
+
+/// Which kinds of transactions may be disputed. Whether disputing a withdrawal makes sense depends
+/// on the partner, so this is part of the [Policy].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disputable {
+    /// Only deposits may be disputed.
+    Deposits,
+    /// Deposits and withdrawals may be disputed, which is the default.
+    DepositsAndWithdrawals,
+    /// Every transaction with an amount may be disputed, except for a transfer. Interest and
+    /// positive adjustments are disputed like a deposit, and fees and negative adjustments like a
+    /// withdrawal.
+    All,
+}
+
+impl Disputable {
+    /// Whether a transaction of `kind` may be disputed.
+    pub fn allows(self, kind: TransactionType) -> bool {
+        use TransactionType::*;
+        match self {
+            Disputable::Deposits => kind == Deposit,
+            Disputable::DepositsAndWithdrawals => matches!(kind, Deposit | Withdrawal),
+            Disputable::All => matches!(kind, Deposit | Withdrawal | Interest | Fee | Adjustment),
+        }
+    }
+}
+
+impl Default for Disputable {
+    fn default() -> Self {
+        Disputable::DepositsAndWithdrawals
+    }
+}
+
+/// The direction in which a transaction moved funds, which decides how it is disputed, resolved
+/// and charged back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Movement {
+    /// The funds moved into the account, like a deposit.
+    Credit,
+    /// The funds moved out of the account, like a withdrawal.
+    Debit,
+}
+
+impl Movement {
+    /// The direction of `trx`, along with the amount that it moved, which is never negative.
+    pub(crate) fn of(trx: &Transaction) -> (Movement, Amount) {
+        match trx.kind {
+            TransactionType::Withdrawal | TransactionType::Fee => (Movement::Debit, trx.amount),
+            TransactionType::Adjustment if trx.amount < Amount::ZERO => {
+                (Movement::Debit, Amount(-trx.amount.0))
+            }
+            _ => (Movement::Credit, trx.amount),
+        }
+    }
+}
+
+/// Settings that change how mutations are applied to an account. The default policy is the
+/// strictest one, except that a withdrawal with insufficient funds is ignored rather than an error,
+/// as the specification demands.
+#[derive(Debug, Default, Clone)]
+pub struct Policy {
+    /// How far a withdrawal may draw `available` below zero. This is zero by default, so a client
+    /// can not withdraw more than is available.
+    pub overdraft_limit: Amount,
+    /// When set, a withdrawal with insufficient funds is an error. Otherwise it is ignored, leaving
+    /// the account unchanged and the transaction unrecorded.
+    pub strict_withdrawals: bool,
+    /// When set, a chargeback may have an amount, which charges back only that part of the
+    /// transaction. Otherwise a chargeback always charges back the entire transaction.
+    pub partial_chargebacks: bool,
+    /// When set, a resolve or chargeback that references a transaction that is not under dispute
+    /// is an error. Otherwise it is ignored, since it usually is a mistake on our partners side,
+    /// but in some feeds it means that the dispute itself went missing.
+    pub strict_disputes: bool,
+    /// Which kinds of transactions may be disputed. Disputing any other kind is an error.
+    pub disputable: Disputable,
+    /// What to do with the dispute of a deposit whose funds are no longer available.
+    pub unfunded_disputes: UnfundedDispute,
+    /// What to do with a chargeback of funds that the client no longer has.
+    pub uncovered_chargebacks: UncoveredChargeback,
+    /// The most that may be held on a single account, or `None` for no limit. Many disputes of the
+    /// same client in a short time may be a coordinated attack, so a dispute that takes `held`
+    /// beyond the limit is an error.
+    pub held_limit: Option<Amount>,
+    /// When set, a dispute beyond the [Policy::held_limit] is applied regardless, and marks the
+    /// account for review instead of being an error.
+    pub review_excess_held: bool,
+}
+
+/// A users account state. All balances are stored as an [Amount](crate::amount::Amount), that is,
+/// as an integer number of the smallest possible increments of currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Account {
-    pub client: u16,
-    /// The amount of (currency * 10_000) available for trading and withdrawing.
-    pub available: u32,
-    /// The amount of (currency * 10_000) that is locked due to disputed transactions.
-    pub held: u32,
-    /// The amount of currency, expressed in f
-    pub total: u32,
+    pub client: u32,
+    /// The amount available for trading and withdrawing.
+    pub available: Amount,
+    /// The amount that is locked due to disputed transactions.
+    pub held: Amount,
+    /// The total amount of currency, which is always equal to `available + held`.
+    pub total: Amount,
     pub locked: bool,
+    /// The number of transactions of this client that are currently disputed.
+    #[serde(default)]
+    pub open_disputes: u32,
+    /// Whether a transaction of this client has ever been disputed, regardless of how the dispute
+    /// ended. This flag is never cleared, so that risky clients can be flagged.
+    #[serde(default)]
+    pub ever_disputed: bool,
+    /// Whether a dispute held more on this account than the [Policy::held_limit], which needs to be
+    /// reviewed by hand. This flag is never cleared either.
+    #[serde(default)]
+    pub under_review: bool,
+    /// The number of the row that last changed this account, counting the rows of the input that
+    /// it was read from from one. This is zero for an account that no row changed yet, such as an
+    /// account from a snapshot of an older version.
+    #[serde(default)]
+    pub last_row: u64,
+    /// The number of deposits that were made into this account.
+    #[serde(default)]
+    pub deposits: u64,
+    /// The number of withdrawals that were made from this account.
+    #[serde(default)]
+    pub withdrawals: u64,
+    /// The total amount that was deposited into this account.
+    #[serde(default)]
+    pub deposited: Amount,
+    /// The currency of the balances, or `None` when the input does not have currencies.
+    #[serde(default)]
+    pub currency: Option<Currency>,
 }
 
 impl Account {
-    pub fn new(client: u16) -> Self {
+    pub fn new(client: u32) -> Self {
         Self {
             client,
-            available: 0,
-            held: 0,
-            total: 0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
+            open_disputes: 0,
+            ever_disputed: false,
+            under_review: false,
+            last_row: 0,
+            deposits: 0,
+            withdrawals: 0,
+            deposited: Amount::ZERO,
+            currency: None,
         }
     }
 
-    /// Mutates an account
-    pub fn mutate(&mut self, trx: Mutation, trxs: &mut Transactions) -> Result<()> {
-        if self.locked {
-            let err = eyre!("Attempt to mutate account {}, which is locked", self.client);
-            return Err(err);
+    /// Sets the currency of the balances of a new account.
+    pub fn in_currency(self, currency: Option<Currency>) -> Self {
+        Self { currency, ..self }
+    }
+
+    /// Mutates an account with the default [Policy]. A locked account only accepts unlocks.
+    pub fn mutate(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+    ) -> Result<Outcome, TransactionError> {
+        self.mutate_with(trx, trxs, &Policy::default())
+    }
+
+    /// Mutates an account, following `policy`. A locked account only accepts unlocks.
+    pub fn mutate_with(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        let _span = tracing::trace_span!("mutate", client = self.client, tx = trx.id).entered();
+        if self.locked && trx.kind != TransactionType::Unlock {
+            let (tx, client) = (trx.id, self.client);
+            return Err(TransactionError::AccountLocked { tx, client });
         }
-        match trx.kind {
+        let outcome = match trx.kind {
             TransactionType::Deposit => self.process_deposit(trx, trxs),
-            TransactionType::Withdrawal => self.process_withdrawal(trx, trxs),
-            TransactionType::Dispute => self.process_dispute(trx.id, trxs),
-            TransactionType::Resolve => self.process_resolve(trx.id, trxs),
-            TransactionType::Chargeback => self.process_chargeback(trx.id, trxs),
+            TransactionType::Withdrawal => self.process_withdrawal(trx, trxs, policy),
+            TransactionType::Dispute => self.process_dispute(trx.id, trxs, policy),
+            TransactionType::Resolve => self.process_resolve(trx.id, trxs, policy),
+            TransactionType::Chargeback => self.process_chargeback(trx, trxs, policy),
+            TransactionType::Unlock => self.process_unlock(),
+            TransactionType::Interest => self.process_interest(trx, trxs),
+            TransactionType::Fee => self.process_fee(trx, trxs),
+            TransactionType::Adjustment => self.process_adjustment(trx, trxs, policy),
+            TransactionType::Transfer => {
+                Err(TransactionError::TransferWithoutAccounts { tx: trx.id })
+            }
+        }?;
+        self.check_invariant()?;
+        Ok(outcome)
+    }
+
+    /// Applies the mutations one after the other with the default [Policy], which allows driving an
+    /// account without going through a csv. This stops at the first mutation that fails, leaving
+    /// the mutations before it applied. Ignored mutations do not stop it.
+    pub fn apply_all<I>(
+        &mut self,
+        mutations: I,
+        trxs: &mut Transactions,
+    ) -> Result<(), TransactionError>
+    where
+        I: IntoIterator<Item = Mutation>,
+    {
+        for trx in mutations {
+            self.mutate(trx, trxs)?;
         }
+        Ok(())
     }
 
-    fn process_deposit(&mut self, trx: Mutation, trxs: &mut Transactions) -> Result<()> {
-        let trx: Transaction = trx.try_into()?;
-        self.available += trx.amount;
-        self.total += trx.amount;
-        trxs.insert(trx.id, trx);
+    /// Verifies that `total` is equal to `available + held`. Every mutation must maintain this, so
+    /// a violation means that there is a bug in the engine, which we would rather surface
+    /// immediately than write out a wrong balance.
+    fn check_invariant(&self) -> Result<(), TransactionError> {
+        let (available, held, total) = (self.available, self.held, self.total);
+        if available.checked_add(held) != Some(total) {
+            return Err(TransactionError::InconsistentAccount {
+                client: self.client,
+                available,
+                held,
+                total,
+            });
+        }
         Ok(())
     }
 
-    fn process_withdrawal(&mut self, trx: Mutation, trxs: &mut Transactions) -> Result<()> {
+    fn process_deposit(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+    ) -> Result<Outcome, TransactionError> {
         let trx: Transaction = trx.try_into()?;
+        if trxs.check_replay(&trx)? {
+            return Ok(Outcome::Ignored(IgnoreReason::Replayed));
+        }
         let id = trx.id;
-        let err = || {
-            let amount = trx.amount as f64 / 10_000.0;
-            eyre!("Error on trx {id}: Can't withdraw {amount}")
+        let (client, amount) = (self.client, trx.amount);
+        let overflow = || TransactionError::Overflow {
+            tx: id,
+            client,
+            amount,
         };
-        let available = self.available.checked_sub(trx.amount).ok_or_else(err)?;
-        let total = self.total.checked_sub(trx.amount).ok_or_else(err)?;
+        let available = self
+            .available
+            .checked_add(trx.amount)
+            .ok_or_else(overflow)?;
+        let total = self.total.checked_add(trx.amount).ok_or_else(overflow)?;
+        let deposited = self
+            .deposited
+            .checked_add(trx.amount)
+            .ok_or_else(overflow)?;
+        let deposits = self.deposits.checked_add(1).ok_or_else(overflow)?;
         (self.available, self.total) = (available, total);
-        trxs.insert(id, trx.try_into()?);
-        Ok(())
+        (self.deposited, self.deposits) = (deposited, deposits);
+        tracing::debug!(amount = %trx.amount, available = %self.available, "Deposited");
+        trxs.record(trx);
+        Ok(Outcome::Applied)
+    }
+
+    /// Withdrawing takes the amount out of `available` and `total`. When the policy allows an
+    /// overdraft, `available` may go negative, up to the overdraft limit. A withdrawal beyond that
+    /// is ignored, unless the policy demands strict withdrawals.
+    fn process_withdrawal(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        let trx: Transaction = trx.try_into()?;
+        if trxs.check_replay(&trx)? {
+            return Ok(Outcome::Ignored(IgnoreReason::Replayed));
+        }
+        let (id, limit) = (trx.id, policy.overdraft_limit);
+        let (client, amount) = (self.client, trx.amount);
+        let short = || TransactionError::InsufficientFunds {
+            tx: id,
+            client,
+            amount,
+        };
+        let available = match self.available.checked_overdraw(trx.amount, limit) {
+            Some(available) => available,
+            None if policy.strict_withdrawals => return Err(short()),
+            None => return Ok(Outcome::Ignored(IgnoreReason::InsufficientFunds)),
+        };
+        let total = self.total.checked_overdraw(trx.amount, limit);
+        let total = total.ok_or_else(short)?;
+        let overflow = || TransactionError::Overflow {
+            tx: id,
+            client,
+            amount,
+        };
+        let withdrawals = self.withdrawals.checked_add(1).ok_or_else(overflow)?;
+        (self.available, self.total) = (available, total);
+        self.withdrawals = withdrawals;
+        tracing::debug!(amount = %trx.amount, available = %self.available, "Withdrew");
+        trxs.record(trx);
+        Ok(Outcome::Applied)
     }
 
-    fn process_dispute(&mut self, id: u32, trxs: &mut Transactions) -> Result<()> {
-        let trx = match trxs.get_mut(&id) {
+    /// Disputing a transaction puts its amount on hold. For a deposit this means that the amount
+    /// moves from `available` to `held`, leaving `total` untouched. For a withdrawal the withdrawn
+    /// amount is brought back into the account, but held, so `held` and `total` both increase by
+    /// the amount. Other kinds are disputed like a deposit or a withdrawal when
+    /// [Policy::disputable] allows it, depending on whether they credited or debited the account.
+    /// When the deposited amount is no longer available, the policy decides what happens, see
+    /// [UnfundedDispute]. A dispute that holds more than [Policy::held_limit] is an
+    /// error, or marks the account for review.
+    fn process_dispute(
+        &mut self,
+        id: u32,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        let trx = match trxs.get_mut(self.client, id) {
+            Some(trx) if trx.client != self.client => return Err(self.foreign_trx(id, trx)),
+            Some(trx) if trx.currency != self.currency => {
+                return Err(self.foreign_currency(id, trx))
+            }
+            // A resolved transaction may be disputed again, but a refunded one may not.
             Some(trx) if trx.status == TransactionStatus::Ok => trx,
-            Some(trx) if trx.kind != TransactionType::Deposit => {
-                return Err(eyre!("Cannot dispute {id}, only deposits can be disputed"));
+            Some(trx) if trx.status == TransactionStatus::Resolved => trx,
+            // Trx doesnt exist or is Disputed or Refunded, assume this is an error on our partners
+            // side.
+            Some(trx) if trx.status == TransactionStatus::Disputed => {
+                return Ok(Outcome::Ignored(IgnoreReason::AlreadyDisputed))
             }
-            // Trx doesnt exist or is not Ok, assume this is an error on our partners side.
-            _ => return Ok(()),
+            Some(_) => return Ok(Outcome::Ignored(IgnoreReason::ChargedBack)),
+            None => return Ok(Outcome::Ignored(IgnoreReason::UnknownTransaction)),
+        };
+        if !policy.disputable.allows(trx.kind) {
+            let kind = trx.kind;
+            return Err(TransactionError::NotDisputable { tx: id, kind });
+        }
+        let (client, (movement, amount)) = (self.client, Movement::of(trx));
+        let short = || TransactionError::InsufficientFunds {
+            tx: id,
+            client,
+            amount,
+        };
+        let overflow = || TransactionError::Overflow {
+            tx: id,
+            client,
+            amount,
         };
-        let err = || {
-            let amount = trx.amount as f64 / 10_000.0;
-            eyre!("Error on trx {id}: Can't dispute {amount}")
+        let open_disputes = self.open_disputes.checked_add(1).ok_or_else(overflow)?;
+        let (available, held, total) = match movement {
+            Movement::Credit => {
+                let available = match self.available.checked_sub(amount) {
+                    Some(available) => available,
+                    None => match policy.unfunded_disputes {
+                        UnfundedDispute::Fail => return Err(short()),
+                        UnfundedDispute::Hold => (self.available)
+                            .checked_overdraw(amount, Amount(i64::MAX))
+                            .ok_or_else(overflow)?,
+                        UnfundedDispute::Ignore => {
+                            return Ok(Outcome::Ignored(IgnoreReason::InsufficientFunds))
+                        }
+                    },
+                };
+                let held = self.held.checked_add(amount).ok_or_else(overflow)?;
+                (available, held, self.total)
+            }
+            Movement::Debit => {
+                let held = self.held.checked_add(amount).ok_or_else(overflow)?;
+                let total = self.total.checked_add(amount).ok_or_else(overflow)?;
+                (self.available, held, total)
+            }
         };
-        self.available = self.available.checked_sub(trx.amount).ok_or_else(err)?;
-        self.held += trx.amount;
+        // A dispute storm shows as a held amount beyond the limit.
+        match policy.held_limit {
+            Some(limit) if held > limit && policy.review_excess_held => {
+                tracing::warn!(client, %held, %limit, "Marking account for review");
+                self.under_review = true;
+            }
+            Some(limit) if held > limit => {
+                return Err(TransactionError::HeldLimit {
+                    tx: id,
+                    client,
+                    held,
+                    limit,
+                })
+            }
+            _ => {}
+        }
+        (self.available, self.held, self.total) = (available, held, total);
         trx.status = TransactionStatus::Disputed;
-        Ok(())
+        self.open_disputes = open_disputes;
+        self.ever_disputed = true;
+        tracing::debug!(id, kind = ?trx.kind, status = ?trx.status, held = %self.held, "Disputed");
+        Ok(Outcome::Applied)
     }
 
-    fn process_resolve(&mut self, id: u32, trxs: &mut Transactions) -> Result<()> {
-        let trx = match trxs.get_mut(&id) {
+    /// Resolving a transaction releases the held amount. For a deposit the amount moves from
+    /// `held` back to `available`. For a withdrawal the held amount is debited again, so `held`
+    /// and `total` both decrease by the amount, which restores the balances from before the
+    /// dispute. Other kinds are resolved like a deposit or a withdrawal, as they were disputed.
+    fn process_resolve(
+        &mut self,
+        id: u32,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        let kind = TransactionType::Resolve;
+        let trx = match trxs.get_mut(self.client, id) {
+            Some(trx) if trx.client != self.client => return Err(self.foreign_trx(id, trx)),
+            Some(trx) if trx.currency != self.currency => {
+                return Err(self.foreign_currency(id, trx))
+            }
             Some(trx) if trx.status == TransactionStatus::Disputed => trx,
             // Trx doesnt exist or is not Disputed, assume this is an error on our partners side.
-            _ => return Ok(()),
+            Some(trx) if trx.status == TransactionStatus::Refunded => {
+                return undisputed(id, kind, IgnoreReason::ChargedBack, policy)
+            }
+            Some(_) => return undisputed(id, kind, IgnoreReason::NotDisputed, policy),
+            None => return undisputed(id, kind, IgnoreReason::UnknownTransaction, policy),
         };
-        let err = || {
-            let amount = trx.amount as f64 / 10_000.0;
-            eyre!("Error on trx {id}: Can't resolve {amount}")
+        let (client, (movement, amount)) = (self.client, Movement::of(trx));
+        let short = || TransactionError::InsufficientFunds {
+            tx: id,
+            client,
+            amount,
+        };
+        let overflow = || TransactionError::Overflow {
+            tx: id,
+            client,
+            amount,
         };
-        self.available += trx.amount;
-        self.held = self.held.checked_sub(trx.amount).ok_or_else(err)?;
+        match movement {
+            Movement::Credit => {
+                let available = self.available.checked_add(amount).ok_or_else(overflow)?;
+                let held = self.held.checked_sub(amount).ok_or_else(short)?;
+                (self.available, self.held) = (available, held);
+            }
+            Movement::Debit => {
+                let held = self.held.checked_sub(amount).ok_or_else(short)?;
+                let total = self.total.checked_sub(amount).ok_or_else(short)?;
+                (self.held, self.total) = (held, total);
+            }
+        }
         trx.status = TransactionStatus::Resolved;
-        Ok(())
+        // Only disputed transactions are resolved, so the count of open disputes is at least one.
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+        tracing::debug!(id, kind = ?trx.kind, status = ?trx.status, held = %self.held, "Resolved");
+        Ok(Outcome::Applied)
     }
 
-    fn process_chargeback(&mut self, id: u32, trxs: &mut Transactions) -> Result<()> {
-        let trx = match trxs.get_mut(&id) {
+    /// Charging back a transaction reverses it and locks the account. The amount of a disputed
+    /// transaction is on hold, so for a deposit the held amount is taken out of the account, which
+    /// decreases `held` and `total`, and for a withdrawal the held amount is permanently returned to
+    /// the client, which moves it from `held` to `available`. The amount of a resolved transaction
+    /// is no longer on hold, so for a deposit `available` and `total` both decrease by the amount,
+    /// and for a withdrawal `available` and `total` both increase by the amount. Other kinds are
+    /// charged back like a deposit or a withdrawal, as they were disputed.
+    ///
+    /// When the policy allows partial chargebacks, a chargeback with an amount only charges back
+    /// that part of the transaction. For a disputed transaction the remainder is resolved, so it is
+    /// no longer held, and the transaction can not be disputed again either way.
+    ///
+    /// A client may have spent the funds before they are charged back, for example after the
+    /// dispute was resolved. Such a chargeback is an
+    /// [UncoveredChargeback](TransactionError::UncoveredChargeback) error, unless the policy allows
+    /// it to overdraw the account, see [Policy::uncovered_chargebacks].
+    fn process_chargeback(
+        &mut self,
+        chargeback: Mutation,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        let (id, kind) = (chargeback.id, chargeback.kind);
+        if chargeback.amount.is_some() && !policy.partial_chargebacks {
+            return Err(TransactionError::UnexpectedAmount { tx: id, kind });
+        }
+        let trx = match trxs.get_mut(self.client, id) {
+            Some(trx) if trx.client != self.client => return Err(self.foreign_trx(id, trx)),
+            Some(trx) if trx.currency != self.currency => {
+                return Err(self.foreign_currency(id, trx))
+            }
+            Some(trx) if trx.status == TransactionStatus::Disputed => trx,
             Some(trx) if trx.status == TransactionStatus::Resolved => trx,
-            // Trx doesnt exist or is not Resolved, assume this is an error on our partners side.
-            _ => return Ok(()),
+            // Trx doesnt exist or is not Disputed or Resolved, assume this is an error on our
+            // partners side.
+            Some(trx) if trx.status == TransactionStatus::Refunded => {
+                return undisputed(id, kind, IgnoreReason::ChargedBack, policy)
+            }
+            Some(_) => return undisputed(id, kind, IgnoreReason::NotDisputed, policy),
+            None => return undisputed(id, kind, IgnoreReason::UnknownTransaction, policy),
+        };
+        let (client, (movement, amount)) = (self.client, Movement::of(trx));
+        let short = || TransactionError::InsufficientFunds {
+            tx: id,
+            client,
+            amount,
         };
-        let err = || {
-            let amount = trx.amount as f64 / 10_000.0;
-            eyre!("Error on trx {id}: Can't chargeback {amount}")
+        let overflow = || TransactionError::Overflow {
+            tx: id,
+            client,
+            amount,
         };
-        let available = self.available.checked_sub(trx.amount).ok_or_else(err)?;
-        let total = self.total.checked_sub(trx.amount).ok_or_else(err)?;
-        (self.available, self.total) = (available, total);
+        // The part of the transaction that is charged back, and the part that is resolved.
+        let charged = chargeback.amount.unwrap_or(amount);
+        let remainder = amount.checked_sub(charged).ok_or_else(|| {
+            let (amount, disputed) = (charged, amount);
+            TransactionError::ExcessiveChargeback {
+                tx: id,
+                amount,
+                disputed,
+            }
+        })?;
+        let uncovered = || TransactionError::UncoveredChargeback {
+            tx: id,
+            client,
+            amount: charged,
+        };
+        let mut covered = true;
+        // Takes the charged amount out of a balance, as far as the policy allows.
+        let mut charge = |balance: Amount| match balance.checked_sub(charged) {
+            Some(balance) => Ok(balance),
+            None if policy.uncovered_chargebacks == UncoveredChargeback::Fail => Err(uncovered()),
+            None => {
+                covered = false;
+                balance
+                    .checked_overdraw(charged, Amount(i64::MAX))
+                    .ok_or_else(overflow)
+            }
+        };
+        let disputed = trx.status == TransactionStatus::Disputed;
+        let (mut available, mut held, mut total) = (self.available, self.held, self.total);
+        match (movement, disputed) {
+            (Movement::Credit, true) => {
+                held = held.checked_sub(amount).ok_or_else(short)?;
+                total = charge(total)?;
+                available = available.checked_add(remainder).ok_or_else(overflow)?;
+            }
+            (Movement::Debit, true) => {
+                held = held.checked_sub(amount).ok_or_else(short)?;
+                available = available.checked_add(charged).ok_or_else(overflow)?;
+                total = total.checked_sub(remainder).ok_or_else(short)?;
+            }
+            (Movement::Credit, false) => {
+                available = charge(available)?;
+                total = charge(total)?;
+            }
+            (Movement::Debit, false) => {
+                available = available.checked_add(charged).ok_or_else(overflow)?;
+                total = total.checked_add(charged).ok_or_else(overflow)?;
+            }
+        }
+        if !covered {
+            tracing::warn!(client, %charged, "Charged back more than the client has left");
+            self.under_review |= policy.uncovered_chargebacks == UncoveredChargeback::Review;
+        }
+        (self.available, self.held, self.total) = (available, held, total);
+        if disputed {
+            self.open_disputes = self.open_disputes.saturating_sub(1);
+        }
         self.locked = true;
         trx.status = TransactionStatus::Refunded;
-        Ok(())
+        tracing::debug!(id, kind = ?trx.kind, status = ?trx.status, "Charged back, account locked");
+        Ok(Outcome::Applied)
+    }
+
+    /// Disputes, resolves and chargebacks may only reference transactions of the same client,
+    /// otherwise a partner could move the funds of another client.
+    fn foreign_trx(&self, id: u32, trx: &Transaction) -> TransactionError {
+        let (client, owner) = (self.client, trx.client);
+        TransactionError::ForeignTransaction {
+            tx: id,
+            client,
+            owner,
+        }
+    }
+
+    /// Disputes, resolves and chargebacks may only reference transactions in the currency of the
+    /// account, otherwise they would put an amount in one currency on hold in another currency.
+    fn foreign_currency(&self, id: u32, trx: &Transaction) -> TransactionError {
+        TransactionError::ForeignCurrency {
+            tx: id,
+            currency: self.currency,
+            expected: trx.currency,
+        }
+    }
+
+    /// Interest is credited to `available` and `total` like a deposit, but it does not count as a
+    /// deposit in the statistics. It is recorded with its own kind, so that it is only disputed
+    /// when the [Policy::disputable] allows it.
+    fn process_interest(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+    ) -> Result<Outcome, TransactionError> {
+        let trx: Transaction = trx.try_into()?;
+        if trxs.check_replay(&trx)? {
+            return Ok(Outcome::Ignored(IgnoreReason::Replayed));
+        }
+        let (id, client, amount) = (trx.id, self.client, trx.amount);
+        let overflow = || TransactionError::Overflow {
+            tx: id,
+            client,
+            amount,
+        };
+        let available = self.available.checked_add(amount).ok_or_else(overflow)?;
+        let total = self.total.checked_add(amount).ok_or_else(overflow)?;
+        (self.available, self.total) = (available, total);
+        tracing::debug!(%amount, available = %self.available, "Credited interest");
+        trxs.record(trx);
+        Ok(Outcome::Applied)
+    }
+
+    /// A fee is taken out of `available` and `total` like a withdrawal, but it may never draw the
+    /// balance below zero, regardless of the overdraft limit. A fee that the account can not cover
+    /// is an error rather than ignored, since it is initiated by us rather than by the client. It
+    /// is recorded with its own kind, so that fees can be told apart from withdrawals.
+    fn process_fee(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+    ) -> Result<Outcome, TransactionError> {
+        let trx: Transaction = trx.try_into()?;
+        if trxs.check_replay(&trx)? {
+            return Ok(Outcome::Ignored(IgnoreReason::Replayed));
+        }
+        let (id, client, amount) = (trx.id, self.client, trx.amount);
+        let short = || TransactionError::InsufficientFunds {
+            tx: id,
+            client,
+            amount,
+        };
+        let available = self.available.checked_sub(amount).ok_or_else(short)?;
+        let total = self.total.checked_sub(amount).ok_or_else(short)?;
+        (self.available, self.total) = (available, total);
+        tracing::debug!(%amount, available = %self.available, "Charged fee");
+        trxs.record(trx);
+        Ok(Outcome::Applied)
+    }
+
+    /// An adjustment adds its signed amount to `available` and `total`. A negative adjustment may
+    /// only overdraw the account as far as the overdraft limit allows, and is an error otherwise,
+    /// since like a fee it is initiated by us. It is recorded with its own kind, which is only
+    /// disputed when the [Policy::disputable] allows it.
+    fn process_adjustment(
+        &mut self,
+        trx: Mutation,
+        trxs: &mut Transactions,
+        policy: &Policy,
+    ) -> Result<Outcome, TransactionError> {
+        let trx: Transaction = trx.try_into()?;
+        if trxs.check_replay(&trx)? {
+            return Ok(Outcome::Ignored(IgnoreReason::Replayed));
+        }
+        let (id, client, amount) = (trx.id, self.client, trx.amount);
+        let (available, total) = if amount.0 >= 0 {
+            let overflow = || TransactionError::Overflow {
+                tx: id,
+                client,
+                amount,
+            };
+            let available = self.available.checked_add(amount).ok_or_else(overflow)?;
+            let total = self.total.checked_add(amount).ok_or_else(overflow)?;
+            (available, total)
+        } else {
+            let (debit, limit) = (Amount(-amount.0), policy.overdraft_limit);
+            let short = || TransactionError::InsufficientFunds {
+                tx: id,
+                client,
+                amount: debit,
+            };
+            let available = self.available.checked_overdraw(debit, limit);
+            let total = self.total.checked_overdraw(debit, limit);
+            (available.ok_or_else(short)?, total.ok_or_else(short)?)
+        };
+        (self.available, self.total) = (available, total);
+        tracing::debug!(%amount, available = %self.available, "Adjusted");
+        trxs.record(trx);
+        Ok(Outcome::Applied)
+    }
+
+    /// Reactivates the account, after which it accepts mutations again. An unlock of an account
+    /// that is not locked is ignored, since it is likely a mistake on our partners side.
+    fn process_unlock(&mut self) -> Result<Outcome, TransactionError> {
+        if !self.locked {
+            return Ok(Outcome::Ignored(IgnoreReason::NotLocked));
+        }
+        self.locked = false;
+        tracing::debug!("Unlocked");
+        Ok(Outcome::Applied)
+    }
+}
+
+/// Handles a resolve or chargeback that references a transaction which is not under dispute, which
+/// is ignored for `reason`, unless the policy has [Policy::strict_disputes].
+fn undisputed(
+    tx: u32,
+    kind: TransactionType,
+    reason: IgnoreReason,
+    policy: &Policy,
+) -> Result<Outcome, TransactionError> {
+    match policy.strict_disputes {
+        true => Err(TransactionError::Undisputed { tx, kind, reason }),
+        false => Ok(Outcome::Ignored(reason)),
     }
 }
 
@@ -147,11 +1006,14 @@ mod tests {
     use TransactionType::*;
 
     fn mutation(id: u32, kind: TransactionType) -> Mutation {
+        let has_amount = matches!(kind, Deposit | Withdrawal | Interest | Fee | Adjustment);
         Mutation {
             id,
             kind,
             client: 1,
-            amount: Some(5),
+            amount: has_amount.then(|| Amount(5)),
+            currency: None,
+            to_client: None,
         }
     }
 
@@ -163,30 +1025,62 @@ mod tests {
         account
             .process_deposit(mutation(1, Deposit), &mut trxs)
             .unwrap();
-        account.available = 5;
-        account.held = 0;
-        account.total = 5;
+        account.available = Amount(5);
+        account.held = Amount(0);
+        account.total = Amount(5);
+    }
+
+    #[test]
+    fn test_deposit_overflow() {
+        let mut account = Account {
+            available: Amount(i64::MAX - 4),
+            total: Amount(i64::MAX - 4),
+            ..Account::new(1)
+        };
+        let mut trxs = Transactions::default();
+
+        let deposit = account.process_deposit(mutation(1, Deposit), &mut trxs);
+        assert!(matches!(deposit, Err(TransactionError::Overflow { .. })));
+        assert_eq!(account.available, Amount(i64::MAX - 4));
+        assert_eq!(account.total, Amount(i64::MAX - 4));
     }
 
     #[test]
     fn test_withdrawal() {
         let mut account = Account {
-            client: 1,
-            available: 7,
-            held: 0,
-            total: 7,
-            locked: false,
+            available: Amount(7),
+            total: Amount(7),
+            ..Account::new(1)
         };
         let mut trxs = Transactions::default();
 
         account
-            .process_withdrawal(mutation(1, Withdrawal), &mut trxs)
+            .process_withdrawal(mutation(1, Withdrawal), &mut trxs, &Policy::default())
             .unwrap();
-        account.available = 2;
-        account.held = 0;
-        account.total = 2;
-        let withdraw2 = account.process_withdrawal(mutation(1, Withdrawal), &mut trxs);
-        assert!(withdraw2.is_err());
+        account.available = Amount(2);
+        account.held = Amount(0);
+        account.total = Amount(2);
+        let withdraw2 =
+            account.process_withdrawal(mutation(2, Withdrawal), &mut trxs, &Policy::default());
+        assert_eq!(
+            withdraw2.unwrap(),
+            Outcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+        assert_eq!(account.available, Amount(2));
+        assert_eq!(account.total, Amount(2));
+        assert!(trxs.get(1, 2).is_none());
+
+        let policy = Policy {
+            strict_withdrawals: true,
+            ..Default::default()
+        };
+        let withdraw2 = account.process_withdrawal(mutation(2, Withdrawal), &mut trxs, &policy);
+        assert!(matches!(
+            withdraw2,
+            Err(TransactionError::InsufficientFunds { .. })
+        ));
+        assert_eq!(account.available, Amount(2));
+        assert!(trxs.get(1, 2).is_none());
     }
 
     #[test]
@@ -195,15 +1089,19 @@ mod tests {
         let mut trxs = Transactions::default();
         account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
 
-        account.process_dispute(1, &mut trxs).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 5);
-        assert_eq!(account.total, 5);
+        account
+            .process_dispute(1, &mut trxs, &Policy::default())
+            .unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(5));
         // Disputing again must not error, we ignore this case.
-        account.process_dispute(1, &mut trxs).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 5);
-        assert_eq!(account.total, 5);
+        account
+            .process_dispute(1, &mut trxs, &Policy::default())
+            .unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(5));
     }
 
     #[test]
@@ -213,15 +1111,19 @@ mod tests {
         account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
         account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
 
-        account.process_resolve(1, &mut trxs).unwrap();
-        assert_eq!(account.available, 5);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.total, 5);
+        account
+            .process_resolve(1, &mut trxs, &Policy::default())
+            .unwrap();
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(5));
         // Disputing again must not error, we ignore this case.
-        account.process_resolve(1, &mut trxs).unwrap();
-        assert_eq!(account.available, 5);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.total, 5);
+        account
+            .process_resolve(1, &mut trxs, &Policy::default())
+            .unwrap();
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(5));
     }
 
     #[test]
@@ -232,14 +1134,1134 @@ mod tests {
         account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
         account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
 
-        account.process_chargeback(1, &mut trxs).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.total, 0);
+        let policy = Policy::default();
+        account
+            .process_chargeback(mutation(1, Chargeback), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(0));
         // Disputing again must not error, we ignore this case.
-        account.process_chargeback(1, &mut trxs).unwrap();
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.total, 0);
+        account
+            .process_chargeback(mutation(1, Chargeback), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(0));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_cycle() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(2, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(3, Withdrawal), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(5));
+
+        // The withdrawn amount comes back into the account, but is held.
+        account.mutate(mutation(3, Dispute), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(10));
+
+        // The held amount is debited again.
+        account.mutate(mutation(3, Resolve), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(5));
+
+        // The withdrawn amount is permanently returned to the client.
+        account.mutate(mutation(3, Chargeback), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(10));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(10));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_table() {
+        use TransactionStatus::{Disputed, Refunded, Resolved};
+        // Every step is a mutation of withdrawal 3, followed by the available, held and total
+        // balances and the status of the withdrawal after it. Treating the withdrawal like a
+        // deposit would move the amount from available to held on a dispute instead.
+        let scenarios = [
+            vec![
+                (Dispute, 5, 5, 10, Disputed),
+                (Resolve, 5, 0, 5, Resolved),
+                (Dispute, 5, 5, 10, Disputed),
+                (Resolve, 5, 0, 5, Resolved),
+            ],
+            vec![
+                (Dispute, 5, 5, 10, Disputed),
+                (Chargeback, 10, 0, 10, Refunded),
+            ],
+            vec![
+                (Dispute, 5, 5, 10, Disputed),
+                (Resolve, 5, 0, 5, Resolved),
+                (Chargeback, 10, 0, 10, Refunded),
+            ],
+        ];
+        for (scenario, steps) in scenarios.iter().enumerate() {
+            let mut account = Account::new(1);
+            let mut trxs = Transactions::default();
+            for trx in [
+                mutation(1, Deposit),
+                mutation(2, Deposit),
+                mutation(3, Withdrawal),
+            ] {
+                account.mutate(trx, &mut trxs).unwrap();
+            }
+            for &(kind, available, held, total, status) in steps {
+                let outcome = account.mutate(mutation(3, kind), &mut trxs).unwrap();
+                assert_eq!(outcome, Outcome::Applied, "{kind:?} in scenario {scenario}");
+                let balances = (account.available, account.held, account.total);
+                let expected = (Amount(available), Amount(held), Amount(total));
+                assert_eq!(balances, expected, "{kind:?} in scenario {scenario}");
+                assert_eq!(
+                    trxs.get(1, 3).unwrap().status,
+                    status,
+                    "{kind:?} in scenario {scenario}"
+                );
+            }
+            assert_eq!(account.locked, steps.last().unwrap().0 == Chargeback);
+            assert_eq!(account.open_disputes, 0);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_id() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+
+        let conflicting = Mutation {
+            amount: Some(Amount(6)),
+            ..mutation(1, Deposit)
+        };
+        let err = account.mutate(conflicting, &mut trxs).unwrap_err();
+        assert_eq!(err, TransactionError::DuplicateId { tx: 1 });
+        assert!(account.mutate(mutation(1, Withdrawal), &mut trxs).is_err());
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.total, Amount(5));
+        assert_eq!(trxs.get(1, 1).unwrap().amount, Amount(5));
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(2, Withdrawal), &mut trxs).unwrap();
+        account.mutate(mutation(3, Deposit), &mut trxs).unwrap();
+
+        for trx in [mutation(1, Deposit), mutation(2, Withdrawal)] {
+            let outcome = account.mutate(trx, &mut trxs).unwrap();
+            assert_eq!(outcome, Outcome::Ignored(IgnoreReason::Replayed));
+        }
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.total, Amount(5));
+        assert_eq!(account.deposits, 2);
+        assert_eq!(account.withdrawals, 1);
+    }
+
+    #[test]
+    fn test_duplicate_id_after_dispute() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+
+        let conflicting = Mutation {
+            amount: Some(Amount(6)),
+            ..mutation(1, Deposit)
+        };
+        assert!(account.mutate(conflicting, &mut trxs).is_err());
+        let replay = account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        assert_eq!(replay, Outcome::Ignored(IgnoreReason::Replayed));
+        // The original transaction and its dispute status must be left intact.
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Disputed);
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(5));
+    }
+
+    #[test]
+    fn test_unlock() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+        account.mutate(mutation(1, Chargeback), &mut trxs).unwrap();
+        assert!(account.locked);
+        assert!(account.mutate(mutation(2, Deposit), &mut trxs).is_err());
+
+        let outcome = account.mutate(mutation(3, Unlock), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Applied);
+        assert!(!account.locked);
+        account.mutate(mutation(2, Deposit), &mut trxs).unwrap();
+
+        // The account is active again, so another unlock has nothing to do.
+        let outcome = account.mutate(mutation(4, Unlock), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::NotLocked));
+        assert!(!account.locked);
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.total, Amount(5));
+    }
+
+    #[test]
+    fn test_dispute_other_client() {
+        let mut account1 = Account::new(1);
+        let mut account2 = Account::new(2);
+        let mut trxs = Transactions::default();
+        account1.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+
+        assert!(account2.mutate(mutation(1, Dispute), &mut trxs).is_err());
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Ok);
+        assert_eq!(account1.available, Amount(5));
+        assert_eq!(account1.held, Amount(0));
+        assert_eq!(account2.available, Amount(0));
+        assert_eq!(account2.held, Amount(0));
+    }
+
+    #[test]
+    fn test_ignored() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+
+        let outcome = account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::UnknownTransaction));
+        let outcome = account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Applied);
+        let outcome = account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::NotDisputed));
+        let outcome = account.mutate(mutation(1, Chargeback), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::NotDisputed));
+        let outcome = account.mutate(mutation(2, Resolve), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::UnknownTransaction));
+    }
+
+    #[test]
+    fn test_strict_disputes() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        let policy = Policy {
+            strict_disputes: true,
+            ..Default::default()
+        };
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+
+        // A resolve without a dispute.
+        let resolve = account.mutate_with(mutation(1, Resolve), &mut trxs, &policy);
+        assert_eq!(
+            resolve,
+            Err(TransactionError::Undisputed {
+                tx: 1,
+                kind: Resolve,
+                reason: IgnoreReason::NotDisputed
+            })
+        );
+        assert_eq!(
+            resolve.unwrap_err().to_string(),
+            "Error on trx 1: resolves must reference a disputed transaction (transaction is not \
+            disputed)"
+        );
+        // A chargeback without a dispute, and without a resolve after it.
+        let chargeback = account.mutate_with(mutation(1, Chargeback), &mut trxs, &policy);
+        assert!(matches!(
+            chargeback,
+            Err(TransactionError::Undisputed {
+                reason: IgnoreReason::NotDisputed,
+                ..
+            })
+        ));
+        let chargeback = account.mutate_with(mutation(2, Chargeback), &mut trxs, &policy);
+        assert!(matches!(
+            chargeback,
+            Err(TransactionError::Undisputed {
+                reason: IgnoreReason::UnknownTransaction,
+                ..
+            })
+        ));
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+        assert!(!account.locked);
+
+        // With a dispute both are applied as usual.
+        account
+            .mutate_with(mutation(1, Dispute), &mut trxs, &policy)
+            .unwrap();
+        account
+            .mutate_with(mutation(1, Resolve), &mut trxs, &policy)
+            .unwrap();
+        account
+            .mutate_with(mutation(1, Chargeback), &mut trxs, &policy)
+            .unwrap();
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_already_disputed() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+
+        // The second dispute does not hold the amount twice.
+        let outcome = account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::AlreadyDisputed));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.open_disputes, 1);
+        let outcome = account.mutate(mutation(2, Dispute), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::UnknownTransaction));
+    }
+
+    #[test]
+    fn test_invariant() {
+        let mut account = Account::new(1);
+        account.available = Amount(5);
+        account.total = Amount(3);
+        let mut trxs = Transactions::default();
+        let err = account.mutate(mutation(1, Deposit), &mut trxs).unwrap_err();
+        assert!(matches!(err, TransactionError::InconsistentAccount { .. }));
+    }
+
+    #[test]
+    fn test_overdraft() {
+        let policy = Policy {
+            overdraft_limit: Amount(3),
+            strict_withdrawals: true,
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        let deposit = Mutation {
+            amount: Some(Amount(2)),
+            ..mutation(1, Deposit)
+        };
+        account.mutate_with(deposit, &mut trxs, &policy).unwrap();
+
+        // Withdrawing exactly to the limit succeeds, and leaves a negative balance.
+        account
+            .mutate_with(mutation(2, Withdrawal), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(-3));
+        assert_eq!(account.total, Amount(-3));
+
+        // Withdrawing a single unit beyond the limit fails, and leaves the balance untouched.
+        let withdrawal = Mutation {
+            amount: Some(Amount(1)),
+            ..mutation(3, Withdrawal)
+        };
+        let err = account.mutate_with(withdrawal, &mut trxs, &policy);
+        assert!(matches!(
+            err,
+            Err(TransactionError::InsufficientFunds { .. })
+        ));
+        assert_eq!(account.available, Amount(-3));
+        assert_eq!(account.total, Amount(-3));
+
+        // Without an overdraft limit, the same withdrawal is ignored.
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        let outcome = account.mutate(mutation(1, Withdrawal), &mut trxs);
+        assert_eq!(
+            outcome.unwrap(),
+            Outcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_unfunded_dispute() {
+        let deposit = Mutation {
+            amount: Some(Amount(5)),
+            ..mutation(1, Deposit)
+        };
+        let withdrawal = Mutation {
+            amount: Some(Amount(3)),
+            ..mutation(2, Withdrawal)
+        };
+        let policy = |unfunded_disputes| Policy {
+            unfunded_disputes,
+            ..Default::default()
+        };
+        let run = |policy: &Policy| {
+            let mut account = Account::new(1);
+            let mut trxs = Transactions::default();
+            account.mutate_with(deposit, &mut trxs, policy).unwrap();
+            account.mutate_with(withdrawal, &mut trxs, policy).unwrap();
+            let outcome = account.mutate_with(mutation(1, Dispute), &mut trxs, policy);
+            (account, trxs, outcome)
+        };
+
+        // By default the dispute fails, since only 2 of the 5 deposited are available.
+        let (account, trxs, outcome) = run(&policy(UnfundedDispute::Fail));
+        assert_eq!(
+            outcome,
+            Err(TransactionError::InsufficientFunds {
+                tx: 1,
+                client: 1,
+                amount: Amount(5)
+            })
+        );
+        assert_eq!(account.available, Amount(2));
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Ok);
+
+        // Holding the deposit regardless draws the available balance below zero.
+        let (account, trxs, outcome) = run(&policy(UnfundedDispute::Hold));
+        assert_eq!(outcome, Ok(Outcome::Applied));
+        assert_eq!(account.available, Amount(-3));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(2));
+        assert_eq!(account.open_disputes, 1);
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Disputed);
+
+        // Ignoring the dispute leaves the account and the deposit untouched.
+        let (account, trxs, outcome) = run(&policy(UnfundedDispute::Ignore));
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Ignored(IgnoreReason::InsufficientFunds))
+        );
+        assert_eq!(account.available, Amount(2));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.open_disputes, 0);
+        assert!(!account.ever_disputed);
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Ok);
+    }
+
+    #[test]
+    fn test_uncovered_chargeback() {
+        let deposit = Mutation {
+            amount: Some(Amount(5)),
+            ..mutation(1, Deposit)
+        };
+        let withdrawal = Mutation {
+            amount: Some(Amount(3)),
+            ..mutation(2, Withdrawal)
+        };
+        let policy = |uncovered_chargebacks| Policy {
+            uncovered_chargebacks,
+            ..Default::default()
+        };
+        // The deposit is no longer held once the dispute is resolved, so the client can withdraw
+        // it before the chargeback comes in.
+        let run = |policy: &Policy| {
+            let mut account = Account::new(1);
+            let mut trxs = Transactions::default();
+            account.mutate_with(deposit, &mut trxs, policy).unwrap();
+            account
+                .mutate_with(mutation(1, Dispute), &mut trxs, policy)
+                .unwrap();
+            account
+                .mutate_with(mutation(1, Resolve), &mut trxs, policy)
+                .unwrap();
+            account.mutate_with(withdrawal, &mut trxs, policy).unwrap();
+            let outcome = account.mutate_with(mutation(1, Chargeback), &mut trxs, policy);
+            (account, trxs, outcome)
+        };
+
+        // By default the chargeback fails, since only 2 of the 5 deposited are left.
+        let (account, trxs, outcome) = run(&policy(UncoveredChargeback::Fail));
+        assert_eq!(
+            outcome,
+            Err(TransactionError::UncoveredChargeback {
+                tx: 1,
+                client: 1,
+                amount: Amount(5)
+            })
+        );
+        assert_eq!(account.available, Amount(2));
+        assert!(!account.locked);
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Resolved);
+
+        // Charging back regardless draws the balances below zero.
+        let (account, trxs, outcome) = run(&policy(UncoveredChargeback::Overdraw));
+        assert_eq!(outcome, Ok(Outcome::Applied));
+        assert_eq!(account.available, Amount(-3));
+        assert_eq!(account.total, Amount(-3));
+        assert!(account.locked);
+        assert!(!account.under_review);
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Refunded);
+
+        let (account, _, outcome) = run(&policy(UncoveredChargeback::Review));
+        assert_eq!(outcome, Ok(Outcome::Applied));
+        assert_eq!(account.total, Amount(-3));
+        assert!(account.under_review);
+
+        // A chargeback that the client can cover does not mark the account.
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        let policy = policy(UncoveredChargeback::Review);
+        account.mutate_with(deposit, &mut trxs, &policy).unwrap();
+        account
+            .mutate_with(mutation(1, Dispute), &mut trxs, &policy)
+            .unwrap();
+        account
+            .mutate_with(mutation(1, Chargeback), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.total, Amount(0));
+        assert!(!account.under_review);
+    }
+
+    #[test]
+    fn test_held_limit() {
+        // Every deposit holds 5 when it is disputed, so the third dispute exceeds a limit of 12.
+        let policy = Policy {
+            held_limit: Some(Amount(12)),
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        for id in 1..=3 {
+            account
+                .mutate_with(mutation(id, Deposit), &mut trxs, &policy)
+                .unwrap();
+        }
+        for id in 1..=2 {
+            account
+                .mutate_with(mutation(id, Dispute), &mut trxs, &policy)
+                .unwrap();
+        }
+        assert_eq!(account.held, Amount(10));
+        let err = account.mutate_with(mutation(3, Dispute), &mut trxs, &policy);
+        assert_eq!(
+            err,
+            Err(TransactionError::HeldLimit {
+                tx: 3,
+                client: 1,
+                held: Amount(15),
+                limit: Amount(12)
+            })
+        );
+        assert_eq!(account.held, Amount(10));
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.open_disputes, 2);
+        assert_eq!(trxs.get(1, 3).unwrap().status, TransactionStatus::Ok);
+        assert!(!account.under_review);
+
+        // When reviewing instead, the dispute is applied and the account is marked.
+        let policy = Policy {
+            review_excess_held: true,
+            ..policy
+        };
+        account
+            .mutate_with(mutation(3, Dispute), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.held, Amount(15));
+        assert_eq!(account.open_disputes, 3);
+        assert!(account.under_review);
+        // The mark sticks once the held amount is released again.
+        account
+            .mutate_with(mutation(3, Resolve), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.held, Amount(10));
+        assert!(account.under_review);
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(2, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        account.mutate(mutation(2, Dispute), &mut trxs).unwrap();
+        assert_eq!(account.open_disputes, 2);
+        account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+        assert_eq!(account.open_disputes, 1);
+        account.mutate(mutation(2, Resolve), &mut trxs).unwrap();
+        account.mutate(mutation(2, Chargeback), &mut trxs).unwrap();
+        assert_eq!(account.open_disputes, 0);
+    }
+
+    #[test]
+    fn test_held_overflow() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(2, Withdrawal), &mut trxs).unwrap();
+        // Stack up so many disputes that holding the withdrawn amount as well overflows `held`.
+        (account.held, account.total) = (Amount(i64::MAX - 2), Amount(i64::MAX - 2));
+
+        let dispute = account.mutate(mutation(2, Dispute), &mut trxs);
+        assert!(matches!(dispute, Err(TransactionError::Overflow { .. })));
+        assert_eq!(account.held, Amount(i64::MAX - 2));
+        assert_eq!(account.total, Amount(i64::MAX - 2));
+        assert_eq!(account.open_disputes, 0);
+        assert_eq!(trxs.get(1, 2).unwrap().status, TransactionStatus::Ok);
+    }
+
+    #[test]
+    fn test_resolve_overflow() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        // Bring available so close to the maximum that releasing the held amount overflows it.
+        account.available = Amount(i64::MAX - 2);
+
+        let resolve = account.process_resolve(1, &mut trxs, &Policy::default());
+        assert!(matches!(resolve, Err(TransactionError::Overflow { .. })));
+        assert_eq!(account.available, Amount(i64::MAX - 2));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Disputed);
+        let err = resolve.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error on trx 1: 0.0005 overflows the balance of client 1"
+        );
+        assert_eq!(
+            err.describe(2),
+            "Error on trx 1: 0.05 overflows the balance of client 1"
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(2, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(3, Withdrawal), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        // A withdrawal that is ignored for insufficient funds is not counted.
+        assert_eq!(
+            account.mutate(mutation(4, Withdrawal), &mut trxs),
+            Ok(Outcome::Ignored(IgnoreReason::InsufficientFunds))
+        );
+        account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+        account.mutate(mutation(5, Deposit), &mut trxs).unwrap();
+        assert_eq!(account.deposits, 3);
+        assert_eq!(account.withdrawals, 1);
+        assert_eq!(account.deposited, Amount(15));
+    }
+
+    #[test]
+    fn test_redispute_chargeback() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(5));
+        assert_eq!(account.open_disputes, 1);
+
+        account.mutate(mutation(1, Chargeback), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(0));
+        assert_eq!(account.open_disputes, 0);
+        assert!(account.locked);
+
+        // A refunded transaction can not be disputed again.
+        account.mutate(mutation(2, Unlock), &mut trxs).unwrap();
+        let outcome = account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        assert_eq!(outcome, Outcome::Ignored(IgnoreReason::ChargedBack));
+    }
+
+    #[test]
+    fn test_redispute_resolve() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        for _ in 0..2 {
+            account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+            assert_eq!(account.available, Amount(0));
+            assert_eq!(account.held, Amount(5));
+            account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+            assert_eq!(account.available, Amount(5));
+            assert_eq!(account.held, Amount(0));
+        }
+        assert_eq!(account.total, Amount(5));
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Resolved);
+    }
+
+    #[test]
+    fn test_get() {
+        let mut accounts = Accounts::default();
+        let mut trxs = Transactions::default();
+        accounts
+            .mutate(mutation(1, Deposit), 1, &mut trxs, &Policy::default())
+            .unwrap();
+        assert_eq!(
+            accounts.get(1, None).map(|account| account.available),
+            Some(Amount(5))
+        );
+        assert_eq!(accounts.get(2, None), None);
+        // Looking up an account does not create it.
+        assert_eq!(accounts.into_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut accounts = Accounts::default();
+        let mut trxs = Transactions::default();
+        for (id, client) in [(1, 1), (2, 2), (3, 1)] {
+            let deposit = Mutation {
+                client,
+                ..mutation(id, Deposit)
+            };
+            accounts
+                .mutate(deposit, u64::from(id), &mut trxs, &Policy::default())
+                .unwrap();
+        }
+        let total = |accounts: &Accounts| {
+            (accounts.iter())
+                .map(|account| account.total)
+                .fold(Amount::ZERO, |sum, total| sum.checked_add(total).unwrap())
+        };
+        assert_eq!(total(&accounts), Amount(15));
+        assert_eq!(total(&accounts), Amount(15));
+        let mut clients = Vec::new();
+        for account in &accounts {
+            clients.push(account.client);
+        }
+        clients.sort_unstable();
+        assert_eq!(clients, [1, 2]);
+    }
+
+    #[test]
+    fn test_apply_all() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        let mutations = [
+            mutation(1, Deposit),
+            mutation(2, Deposit),
+            mutation(1, Dispute),
+            mutation(1, Chargeback),
+            mutation(2, Dispute),
+        ];
+        let err = account.apply_all(mutations, &mut trxs).unwrap_err();
+        assert!(matches!(err, TransactionError::AccountLocked { tx: 2, .. }));
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+    }
+
+    /// A xorshift generator, which is plenty random to generate mutations from, while keeping the
+    /// test reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    #[test]
+    fn test_invariant_holds_for_random_mutations() {
+        for seed in 1..=500 {
+            let mut rng = Rng(seed);
+            let mut ids = 0;
+            let mutations: Vec<_> = (0..64)
+                .map(|_| {
+                    let kind = [Deposit, Withdrawal, Dispute, Resolve, Chargeback, Unlock]
+                        [rng.next(6) as usize];
+                    let (id, amount) = match kind {
+                        Deposit | Withdrawal => {
+                            ids += 1;
+                            (ids, Some(Amount(rng.next(1_000) as i64 + 1)))
+                        }
+                        // Reference an earlier transaction most of the time, and an unknown one
+                        // some of the time.
+                        _ => (rng.next(ids + 2), None),
+                    };
+                    Mutation {
+                        id: id as u32,
+                        kind,
+                        client: 1,
+                        amount,
+                        currency: None,
+                        to_client: None,
+                    }
+                })
+                .collect();
+
+            let mut account = Account::new(1);
+            let mut trxs = Transactions::default();
+            let result = account.apply_all(mutations, &mut trxs);
+            assert!(
+                !matches!(result, Err(TransactionError::InconsistentAccount { .. })),
+                "seed {seed}: {result:?}"
+            );
+            assert_eq!(
+                account.available.checked_add(account.held),
+                Some(account.total),
+                "seed {seed}"
+            );
+            assert!(account.held >= Amount::ZERO, "seed {seed}");
+            assert!(account.available >= Amount::ZERO, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_interest() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(2, Interest), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(10));
+        assert_eq!(account.total, Amount(10));
+        assert_eq!(account.deposits, 1);
+        assert_eq!(trxs.get(1, 2).unwrap().kind, Interest);
+
+        let err = account.mutate(mutation(2, Dispute), &mut trxs).unwrap_err();
+        assert_eq!(
+            err,
+            TransactionError::NotDisputable {
+                tx: 2,
+                kind: Interest
+            }
+        );
+        assert_eq!(account.available, Amount(10));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.open_disputes, 0);
+    }
+
+    #[test]
+    fn test_fee() {
+        let policy = Policy {
+            overdraft_limit: Amount(100),
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+
+        // A fee may take the balance to exactly zero.
+        account
+            .mutate_with(mutation(2, Fee), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.total, Amount(0));
+        assert_eq!(account.withdrawals, 0);
+        assert_eq!(trxs.get(1, 2).unwrap().kind, Fee);
+
+        // But not below zero, even though the policy allows withdrawals to overdraw.
+        let err = account.mutate_with(mutation(3, Fee), &mut trxs, &policy);
+        assert!(matches!(
+            err,
+            Err(TransactionError::InsufficientFunds { tx: 3, .. })
+        ));
+        assert_eq!(account.available, Amount(0));
+        assert!(trxs.get(1, 3).is_none());
+    }
+
+    #[test]
+    fn test_ever_disputed() {
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        assert!(!account.ever_disputed);
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        assert!(account.ever_disputed);
+        // The flag sticks after the dispute is resolved.
+        account.mutate(mutation(1, Resolve), &mut trxs).unwrap();
+        assert_eq!(account.open_disputes, 0);
+        assert!(account.ever_disputed);
+    }
+
+    #[test]
+    fn test_disputable() {
+        let kinds = [Deposit, Withdrawal, Interest, Fee, Adjustment];
+        let cases = [
+            (Disputable::Deposits, vec![Deposit]),
+            (
+                Disputable::DepositsAndWithdrawals,
+                vec![Deposit, Withdrawal],
+            ),
+            (Disputable::All, kinds.to_vec()),
+        ];
+        for (disputable, allowed) in cases {
+            let policy = Policy {
+                disputable,
+                ..Default::default()
+            };
+            let mut account = Account::new(1);
+            let mut trxs = Transactions::default();
+            // Enough funds to dispute every credit.
+            let funding = Mutation {
+                amount: Some(Amount(100)),
+                ..mutation(100, Deposit)
+            };
+            account.mutate_with(funding, &mut trxs, &policy).unwrap();
+            for (id, kind) in (1..).zip(kinds) {
+                account
+                    .mutate_with(mutation(id, kind), &mut trxs, &policy)
+                    .unwrap();
+            }
+            for (id, kind) in (1..).zip(kinds) {
+                let outcome = account.mutate_with(mutation(id, Dispute), &mut trxs, &policy);
+                if allowed.contains(&kind) {
+                    assert_eq!(outcome, Ok(Outcome::Applied), "{kind} with {disputable:?}");
+                } else {
+                    let err = TransactionError::NotDisputable { tx: id, kind };
+                    assert_eq!(outcome, Err(err), "{kind} with {disputable:?}");
+                }
+            }
+            assert_eq!(account.open_disputes as usize, allowed.len());
+        }
+    }
+
+    #[test]
+    fn test_dispute_negative_adjustment() {
+        let policy = Policy {
+            disputable: Disputable::All,
+            ..Default::default()
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account
+            .mutate_with(mutation(1, Deposit), &mut trxs, &policy)
+            .unwrap();
+        let adjustment = Mutation {
+            amount: Some(Amount(-3)),
+            ..mutation(2, Adjustment)
+        };
+        account.mutate_with(adjustment, &mut trxs, &policy).unwrap();
+        assert_eq!(account.total, Amount(2));
+
+        // A negative adjustment is disputed like a withdrawal, bringing the amount back but held.
+        account
+            .mutate_with(mutation(2, Dispute), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(2));
+        assert_eq!(account.held, Amount(3));
+        assert_eq!(account.total, Amount(5));
+
+        account
+            .mutate_with(mutation(2, Chargeback), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(5));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(5));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_last_row() {
+        let mut accounts = Accounts::default();
+        let mut trxs = Transactions::default();
+        let policy = Policy::default();
+        accounts
+            .mutate(mutation(1, Deposit), 1, &mut trxs, &policy)
+            .unwrap();
+        accounts
+            .mutate(mutation(2, Deposit), 2, &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(accounts.get(1, None).unwrap().last_row, 2);
+        // A row that is not applied does not change the account, so it is not recorded.
+        let overdraw = Mutation {
+            amount: Some(Amount(50)),
+            ..mutation(3, Withdrawal)
+        };
+        assert_eq!(
+            accounts.mutate(overdraw, 3, &mut trxs, &policy),
+            Ok(Outcome::Ignored(IgnoreReason::InsufficientFunds))
+        );
+        assert_eq!(accounts.get(1, None).unwrap().last_row, 2);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut accounts = Accounts::default();
+        let mut trxs = Transactions::default();
+        let policy = Policy::default();
+        accounts
+            .mutate(mutation(1, Deposit), 1, &mut trxs, &policy)
+            .unwrap();
+        let transfer = |id, amount| Mutation {
+            amount: Some(Amount(amount)),
+            to_client: Some(2),
+            ..mutation(id, Transfer)
+        };
+        accounts
+            .mutate(transfer(2, 3), 2, &mut trxs, &policy)
+            .unwrap();
+        let (source, recipient) = (
+            accounts.get(1, None).unwrap(),
+            accounts.get(2, None).unwrap(),
+        );
+        assert_eq!((source.available, source.total), (Amount(2), Amount(2)));
+        assert_eq!(
+            (recipient.available, recipient.total),
+            (Amount(3), Amount(3))
+        );
+        assert_eq!((source.last_row, recipient.last_row), (2, 2));
+        let outcome = accounts.mutate(transfer(2, 3), 3, &mut trxs, &policy);
+        assert_eq!(outcome.unwrap(), Outcome::Ignored(IgnoreReason::Replayed));
+
+        // A transfer that can not be paid changes neither account, and does not create the
+        // account of the recipient.
+        let mut accounts = Accounts::default();
+        let mut trxs = Transactions::default();
+        accounts
+            .mutate(mutation(1, Deposit), 1, &mut trxs, &policy)
+            .unwrap();
+        let err = accounts.mutate(transfer(2, 6), 2, &mut trxs, &policy);
+        assert!(matches!(
+            err,
+            Err(TransactionError::InsufficientFunds {
+                tx: 2,
+                client: 1,
+                ..
+            })
+        ));
+        assert_eq!(accounts.get(1, None).unwrap().available, Amount(5));
+        assert_eq!(accounts.get(2, None), None);
+        assert_eq!(trxs.get(1, 2), None);
+
+        let to_self = Mutation {
+            to_client: Some(1),
+            ..transfer(3, 1)
+        };
+        let err = accounts.mutate(to_self, 3, &mut trxs, &policy);
+        assert!(matches!(
+            err,
+            Err(TransactionError::SelfTransfer { tx: 3, client: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_adjustment() {
+        let adjustment = |id, amount| Mutation {
+            amount: Some(Amount(amount)),
+            ..mutation(id, Adjustment)
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+
+        account.mutate(adjustment(2, 3), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(8));
+        assert_eq!(account.total, Amount(8));
+        assert_eq!(account.deposits, 1);
+        assert_eq!(trxs.get(1, 2).unwrap().kind, Adjustment);
+
+        account.mutate(adjustment(3, -6), &mut trxs).unwrap();
+        assert_eq!(account.available, Amount(2));
+        assert_eq!(account.total, Amount(2));
+        assert_eq!(account.withdrawals, 0);
+
+        let err = account.mutate(mutation(2, Dispute), &mut trxs).unwrap_err();
+        assert_eq!(
+            err,
+            TransactionError::NotDisputable {
+                tx: 2,
+                kind: Adjustment
+            }
+        );
+        assert_eq!(account.held, Amount(0));
+    }
+
+    #[test]
+    fn test_negative_adjustment_overdraw() {
+        let adjustment = |id, amount| Mutation {
+            amount: Some(Amount(amount)),
+            ..mutation(id, Adjustment)
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+
+        // Without an overdraft, an adjustment may not take the balance below zero.
+        let err = account.mutate(adjustment(2, -6), &mut trxs);
+        assert_eq!(
+            err,
+            Err(TransactionError::InsufficientFunds {
+                tx: 2,
+                client: 1,
+                amount: Amount(6)
+            })
+        );
+        assert_eq!(account.available, Amount(5));
+        assert!(trxs.get(1, 2).is_none());
+
+        // With an overdraft it may, up to the limit.
+        let policy = Policy {
+            overdraft_limit: Amount(1),
+            ..Default::default()
+        };
+        account
+            .mutate_with(adjustment(2, -6), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(-1));
+        assert_eq!(account.total, Amount(-1));
+        let err = account.mutate_with(adjustment(3, -1), &mut trxs, &policy);
+        assert!(matches!(
+            err,
+            Err(TransactionError::InsufficientFunds { tx: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_partial_chargeback() {
+        let policy = Policy {
+            partial_chargebacks: true,
+            ..Default::default()
+        };
+        let chargeback = |id, amount| Mutation {
+            amount: Some(Amount(amount)),
+            ..mutation(id, Chargeback)
+        };
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        let mutations = [
+            mutation(1, Deposit),
+            mutation(2, Deposit),
+            mutation(1, Dispute),
+            mutation(2, Dispute),
+        ];
+        for trx in mutations {
+            account.mutate_with(trx, &mut trxs, &policy).unwrap();
+        }
+
+        // Charging back more than the transaction is an error.
+        let err = account.mutate_with(chargeback(1, 6), &mut trxs, &policy);
+        assert!(matches!(
+            err,
+            Err(TransactionError::ExcessiveChargeback { tx: 1, .. })
+        ));
+
+        // The remainder of a partial chargeback is released.
+        account
+            .mutate_with(chargeback(1, 2), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(3));
+        assert_eq!(account.held, Amount(5));
+        assert_eq!(account.total, Amount(8));
+        assert_eq!(trxs.get(1, 1).unwrap().status, TransactionStatus::Refunded);
+        assert!(account.locked);
+
+        // A full chargeback takes out the entire amount.
+        account
+            .mutate_with(mutation(3, Unlock), &mut trxs, &policy)
+            .unwrap();
+        account
+            .mutate_with(chargeback(2, 5), &mut trxs, &policy)
+            .unwrap();
+        assert_eq!(account.available, Amount(3));
+        assert_eq!(account.held, Amount(0));
+        assert_eq!(account.total, Amount(3));
+
+        // Without the policy, a chargeback may not have an amount.
+        let mut account = Account::new(1);
+        let mut trxs = Transactions::default();
+        account.mutate(mutation(1, Deposit), &mut trxs).unwrap();
+        account.mutate(mutation(1, Dispute), &mut trxs).unwrap();
+        let err = account.mutate(chargeback(1, 2), &mut trxs);
+        assert!(matches!(
+            err,
+            Err(TransactionError::UnexpectedAmount { tx: 1, .. })
+        ));
     }
 }
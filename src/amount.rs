@@ -0,0 +1,437 @@
+use crate::error::AmountError;
+use core::fmt;
+
+/// The number of decimals we maintain unless configured otherwise, which makes the smallest
+/// possible increment 0.0001 currency.
+pub const DEFAULT_PRECISION: u32 = 4;
+
+/// The number of smallest possible increments that make up a single unit of currency with the
+/// [DEFAULT_PRECISION]. This is only a shorthand for code outside of the engine, together with
+/// [to_minor_units] and [to_decimal_string]. The engine itself does not use it, but scales by the
+/// precision that it is configured with.
+pub const SCALE: u64 = 10_u64.pow(DEFAULT_PRECISION);
+
+/// The number of smallest possible increments that make up a single unit of currency, when we
+/// maintain `precision` decimals.
+fn scale(precision: u32) -> Result<i64, AmountError> {
+    10_i64
+        .checked_pow(precision)
+        .ok_or(AmountError::UnsupportedPrecision(precision))
+}
+
+/// Formats a number of smallest increments as a decimal string with `precision` decimal places.
+/// This takes an `i128`, so that it can also format sums of amounts that do not fit an [Amount].
+pub fn format_decimal(value: i128, precision: u32) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    // `unsigned_abs` does not overflow for `i128::MIN`, unlike `abs`.
+    let (value, decimals) = (value.unsigned_abs(), precision as usize);
+    match 10_u128.checked_pow(precision) {
+        Some(1) => format!("{sign}{value}"),
+        Some(scale) => format!("{sign}{}.{:0decimals$}", value / scale, value % scale),
+        // When the scale does not even fit in a `u128`, every digit is a decimal.
+        None => format!("{sign}0.{value:0decimals$}"),
+    }
+}
+
+/// Parses a decimal string such as `"1.5"` into the number of smallest possible increments with the
+/// [DEFAULT_PRECISION]. This is a thin wrapper around [Amount::from_decimal_str] for code outside
+/// of the engine. The engine parses its input with [Amount::from_decimal_str_rounded] instead,
+/// with the precision and rounding that it is configured with, so this only agrees with the engine
+/// under the default configuration.
+pub fn to_minor_units(decimal: &str) -> Result<u64, AmountError> {
+    let amount = Amount::from_decimal_str(decimal, DEFAULT_PRECISION)?;
+    // Negative amounts are rejected while parsing.
+    Ok(amount.0.unsigned_abs())
+}
+
+/// Formats a number of smallest possible increments as a decimal string with the
+/// [DEFAULT_PRECISION]. This is a thin wrapper around [format_decimal] for code outside of the
+/// engine, which accepts every `u64`, also the ones that are too large to be an [Amount]. The
+/// engine writes its balances with [Amount::to_decimal_string] and the precision that it is
+/// configured with instead.
+pub fn to_decimal_string(minor: u64) -> String {
+    format_decimal(minor.into(), DEFAULT_PRECISION)
+}
+
+/// An amount of money. Since we are working with money, we do not store amounts as floats, but
+/// rather we store the amount of smallest possible increments as an integer. The amount of a
+/// transaction is never negative, but the balance of an account can be when it is overdrawn, so the
+/// integer is signed. How large such an increment is depends on the precision the engine is
+/// configured with, so all conversions from and to the decimal representation go through this type
+/// and take that precision, which keeps the precision logic in a single place.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct Amount(pub i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// Parses a decimal string such as `"1.5"` into an amount with `precision` decimals. We do not
+    /// go through `f64` here, because that would introduce rounding errors, instead we parse the
+    /// integer and fractional digits separately. Amounts with more than `precision` decimals are
+    /// rejected, since we cannot represent them without losing precision.
+    pub fn from_decimal_str(s: &str, precision: u32) -> Result<Self, AmountError> {
+        Self::from_decimal_str_rounded(s, precision, Rounding::Reject)
+    }
+
+    /// Like [Amount::from_decimal_str], but amounts with more than `precision` decimals are
+    /// rounded to `precision` decimals following `rounding`.
+    pub fn from_decimal_str_rounded(
+        s: &str,
+        precision: u32,
+        rounding: Rounding,
+    ) -> Result<Self, AmountError> {
+        let invalid = || AmountError::Invalid(s.to_string());
+        // Values like `inf` and `NaN` are accepted by `f64`, so they show up in exports of systems
+        // that use floats, but they are never valid amounts.
+        if s.parse::<f64>().map_or(false, |value| !value.is_finite()) {
+            return Err(AmountError::NotFinite(s.to_string()));
+        }
+        if s.starts_with('-') {
+            return Err(AmountError::Negative(s.to_string()));
+        }
+        let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (int.is_empty() && frac.is_empty()) || !is_digits(int) || !is_digits(frac) {
+            return Err(invalid());
+        }
+        let (scale, decimals) = (scale(precision)?, precision as usize);
+        // The decimals beyond the precision are dropped, and only decide how to round.
+        let (frac, dropped) = frac.split_at(frac.len().min(decimals));
+        if !dropped.is_empty() && rounding == Rounding::Reject {
+            let amount = s.to_string();
+            return Err(AmountError::TooPrecise { amount, precision });
+        }
+        // The parts only consist of digits, so parsing them can only fail because they are too
+        // large, just like scaling them. Saturating to the largest amount would silently change a
+        // huge deposit, so that is an error instead.
+        let too_large = || AmountError::TooLarge {
+            amount: s.to_string(),
+            max: Amount(i64::MAX).to_decimal_string(precision),
+        };
+        let int: i64 = match int {
+            "" => 0,
+            int => int.parse().map_err(|_| too_large())?,
+        };
+        // Pad the fractional part with zeroes, so that `"5"` is interpreted as `"5000"` when we
+        // maintain four decimals.
+        let frac: i64 = match frac {
+            "" => 0,
+            frac => format!("{frac:0<decimals$}")
+                .parse()
+                .map_err(|_| invalid())?,
+        };
+        let value = int.checked_mul(scale).and_then(|int| int.checked_add(frac));
+        let value = value.and_then(|value| value.checked_add(rounding.increment(value, dropped)));
+        value.map(Amount).ok_or_else(too_large)
+    }
+
+    /// Formats the amount as a decimal string with `precision` decimal places, such as `"1.5000"`
+    /// for a precision of four. Like parsing, this does not go through `f64`, because that can not
+    /// represent every integer balance exactly, instead we format the integer and fractional
+    /// digits separately.
+    pub fn to_decimal_string(self, precision: u32) -> String {
+        format_decimal(self.0.into(), precision)
+    }
+
+    /// Converts the amount to a floating point number of currency. Note that this may lose
+    /// precision for very large amounts, so it is only meant for presentation.
+    pub fn to_f64(self, precision: u32) -> f64 {
+        self.0 as f64 / 10_f64.powi(precision as i32)
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    /// Subtracts `rhs`, returning `None` when the result would be negative. This is how we make
+    /// sure that balances can not be drawn below zero by accident.
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.checked_overdraw(rhs, Amount::ZERO)
+    }
+
+    /// Subtracts `rhs`, allowing the result to be negative as long as it does not go below
+    /// `-limit`.
+    pub fn checked_overdraw(self, rhs: Amount, limit: Amount) -> Option<Amount> {
+        let value = self.0.checked_sub(rhs.0)?;
+        (value >= -limit.0).then(|| Amount(value))
+    }
+}
+
+/// How to convert an amount with more decimals than the precision that we maintain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// The amount is rejected, since it can not be represented without losing precision.
+    Reject,
+    /// The excess decimals are dropped, which rounds towards zero.
+    Truncate,
+    /// The amount is rounded to the nearest increment, and away from zero when it is exactly in
+    /// between two increments.
+    HalfUp,
+    /// The amount is rounded to the nearest increment, and to the even increment when it is
+    /// exactly in between two increments, which is also known as banker's rounding.
+    HalfEven,
+}
+
+impl Rounding {
+    /// Every rounding mode along with its name.
+    const NAMES: [(Rounding, &'static str); 4] = [
+        (Rounding::Reject, "reject"),
+        (Rounding::Truncate, "truncate"),
+        (Rounding::HalfUp, "half-up"),
+        (Rounding::HalfEven, "half-even"),
+    ];
+
+    /// The number of increments to add to the truncated `value`, given the `dropped` decimals.
+    fn increment(self, value: i64, dropped: &str) -> i64 {
+        let (first, rest) = match dropped.as_bytes().split_first() {
+            Some((first, rest)) => (*first, rest),
+            None => return 0,
+        };
+        let tie = first == b'5' && rest.iter().all(|digit| *digit == b'0');
+        let up = match self {
+            Rounding::Reject | Rounding::Truncate => false,
+            Rounding::HalfUp => first >= b'5',
+            Rounding::HalfEven if tie => value % 2 == 1,
+            Rounding::HalfEven => first >= b'5',
+        };
+        i64::from(up)
+    }
+}
+
+impl Default for Rounding {
+    fn default() -> Self {
+        Rounding::Reject
+    }
+}
+
+impl core::str::FromStr for Rounding {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, AmountError> {
+        (Rounding::NAMES.iter())
+            .find(|(_, name)| *name == s)
+            .map(|&(rounding, _)| rounding)
+            .ok_or_else(|| AmountError::UnknownRounding(s.to_string()))
+    }
+}
+
+/// The ISO 4217 code of a currency, such as `EUR`. The code is stored in uppercase, so `eur` and
+/// `EUR` are the same currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // The code only consists of ascii letters, so it is always valid utf-8.
+        core::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl core::str::FromStr for Currency {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, AmountError> {
+        match *s.as_bytes() {
+            [a, b, c] if s.bytes().all(|b| b.is_ascii_alphabetic()) => {
+                Ok(Currency([a, b, c].map(|b| b.to_ascii_uppercase())))
+            }
+            _ => Err(AmountError::InvalidCurrency(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl serde::Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Snapshots are read from a reader, which can not lend out a `&str`.
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Since an amount does not know its own precision, we can only display the number of smallest
+/// possible increments. Use [Amount::to_decimal_string] to present an amount as currency.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} units", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_decimal_str() {
+        let parse = |s| Amount::from_decimal_str(s, DEFAULT_PRECISION).unwrap();
+        assert_eq!(parse("1"), Amount(10_000));
+        assert_eq!(parse("1.5"), Amount(15_000));
+        assert_eq!(parse("1.0001"), Amount(10_001));
+        assert_eq!(parse("0.3"), Amount(3_000));
+        assert_eq!(parse(".25"), Amount(2_500));
+        assert_eq!(parse("2."), Amount(20_000));
+    }
+
+    #[test]
+    fn test_minor_units() {
+        assert_eq!(SCALE, 10_000);
+        assert_eq!(to_minor_units("1").unwrap(), SCALE);
+        for (decimal, minor) in [
+            ("0.0000", 0),
+            ("0.0001", 1),
+            ("1.5000", 15_000),
+            ("922337203685477.5807", i64::MAX as u64),
+        ] {
+            assert_eq!(to_minor_units(decimal).unwrap(), minor);
+            assert_eq!(to_decimal_string(minor), decimal);
+        }
+        assert_eq!(to_decimal_string(u64::MAX), "1844674407370955.1615");
+        assert!(to_minor_units("922337203685477.5808").is_err());
+        assert!(to_minor_units("0.00001").is_err());
+        assert!(to_minor_units("-1").is_err());
+    }
+
+    #[test]
+    fn test_from_decimal_str_invalid() {
+        let parse = |s| Amount::from_decimal_str(s, DEFAULT_PRECISION);
+        assert!(parse("").is_err());
+        assert!(parse(".").is_err());
+        assert!(parse("1.23456").is_err());
+        assert!(parse("1,5").is_err());
+        assert!(parse("1.2.3").is_err());
+        assert!(parse("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_from_decimal_str_too_large() {
+        // 1e12 fits with four decimals, but not with eight.
+        assert_eq!(
+            Amount::from_decimal_str("1000000000000", 4).unwrap(),
+            Amount(10_000_000_000_000_000)
+        );
+        assert_eq!(
+            Amount::from_decimal_str("1000000000000", 8).unwrap_err(),
+            AmountError::TooLarge {
+                amount: "1000000000000".to_string(),
+                max: "92233720368.54775807".to_string()
+            }
+        );
+        assert_eq!(
+            Amount::from_decimal_str("99999999999999999999", 4)
+                .unwrap_err()
+                .to_string(),
+            "99999999999999999999 is too large, the largest amount is 922337203685477.5807"
+        );
+        // Rounding up the largest amount overflows as well.
+        let max = "922337203685477.58075";
+        assert!(Amount::from_decimal_str_rounded(max, 4, Rounding::Truncate).is_ok());
+        let rounded = Amount::from_decimal_str_rounded(max, 4, Rounding::HalfUp);
+        assert!(matches!(rounded, Err(AmountError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn test_precision() {
+        let amount = Amount::from_decimal_str("1.23456789", 8).unwrap();
+        assert_eq!(amount, Amount(123_456_789));
+        assert_eq!(amount.to_decimal_string(8), "1.23456789");
+        assert_eq!(
+            Amount::from_decimal_str("1.5", 0).unwrap_err().to_string(),
+            "1.5 has more than 0 decimals"
+        );
+        assert_eq!(
+            Amount::from_decimal_str("1", 19).unwrap_err(),
+            AmountError::UnsupportedPrecision(19)
+        );
+        assert_eq!(Amount::from_decimal_str("7", 0).unwrap(), Amount(7));
+        assert_eq!(Amount(7).to_decimal_string(0), "7");
+    }
+
+    #[test]
+    fn test_checked_overdraw() {
+        assert_eq!(Amount(5).checked_sub(Amount(6)), None);
+        assert_eq!(
+            Amount(5).checked_overdraw(Amount(6), Amount(1)),
+            Some(Amount(-1))
+        );
+        assert_eq!(Amount(5).checked_overdraw(Amount(7), Amount(1)), None);
+        assert_eq!(Amount(-1).to_decimal_string(4), "-0.0001");
+    }
+
+    #[test]
+    fn test_to_decimal_string() {
+        assert_eq!(Amount(0).to_decimal_string(4), "0.0000");
+        assert_eq!(Amount(15_000).to_decimal_string(4), "1.5000");
+        assert_eq!(Amount(-15_001).to_decimal_string(4), "-1.5001");
+        // These balances are well beyond the range in which `f64` represents every integer.
+        assert_eq!(
+            Amount(i64::MAX).to_decimal_string(4),
+            "922337203685477.5807"
+        );
+        assert_eq!(
+            Amount(i64::MIN).to_decimal_string(4),
+            "-922337203685477.5808"
+        );
+        assert_eq!(
+            Amount(i64::MAX - 1).to_decimal_string(0),
+            "9223372036854775806"
+        );
+        assert_eq!(Amount(12).to_decimal_string(20), "0.00000000000000000012");
+    }
+
+    #[test]
+    fn test_currency() {
+        let eur: Currency = "eur".parse().unwrap();
+        assert_eq!(eur, "EUR".parse().unwrap());
+        assert_eq!(eur.to_string(), "EUR");
+        assert_eq!(serde_json::to_string(&eur).unwrap(), "\"EUR\"");
+        assert_eq!(serde_json::from_str::<Currency>("\"Eur\"").unwrap(), eur);
+        for code in ["", "EU", "EURO", "E1R", "€"] {
+            assert!(code.parse::<Currency>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_rounding() {
+        let parse = |s, rounding| Amount::from_decimal_str_rounded(s, 4, rounding).unwrap();
+        let cases = [
+            ("1.23455", [12_345, 12_346, 12_346]),
+            ("1.23445", [12_344, 12_345, 12_344]),
+            ("1.234451", [12_344, 12_345, 12_345]),
+            ("1.23441", [12_344, 12_344, 12_344]),
+            ("1.2345", [12_345, 12_345, 12_345]),
+            ("0.99995", [9_999, 10_000, 10_000]),
+        ];
+        for (s, expected) in cases {
+            let modes = [Rounding::Truncate, Rounding::HalfUp, Rounding::HalfEven];
+            let amounts = modes.map(|rounding| parse(s, rounding).0);
+            assert_eq!(amounts, expected, "rounding {s}");
+        }
+        assert!(Amount::from_decimal_str_rounded("1.23455", 4, Rounding::Reject).is_err());
+        assert_eq!("half-even".parse::<Rounding>().unwrap(), Rounding::HalfEven);
+        assert!("nearest".parse::<Rounding>().is_err());
+    }
+}
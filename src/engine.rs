@@ -0,0 +1,2930 @@
+use crate::{
+    account::{
+        self, Disputable, IgnoreReason, Movement, Outcome, Policy, UncoveredChargeback,
+        UnfundedDispute,
+    },
+    amount::{Amount, Currency, Rounding, DEFAULT_PRECISION},
+    error::TransactionError,
+    idempotency::IdempotencyLog,
+    parallel, present,
+    source::{CsvSource, TransactionSource},
+    state::EngineState,
+    transaction::{self, Mutation, TransactionStatus, TransactionType},
+};
+use eyre::{eyre, Report, Result};
+use std::{
+    fmt::{self, Display},
+    io::{BufWriter, Read, Write},
+    str::FromStr,
+};
+
+/// The number of bytes that the csv reader buffers by default, which is the default of the `csv`
+/// crate.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Describes a row that was not applied to its account.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Incident {
+    pub kind: IncidentKind,
+    /// The id of the transaction, if the row could be read far enough to know it.
+    pub tx: Option<u32>,
+    /// The id of the client, if the row could be read far enough to know it.
+    pub client: Option<u32>,
+    /// A human readable description of why the row was not applied.
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IncidentKind {
+    /// The row could not be processed. In strict mode this aborts the run.
+    Failed,
+    /// The row was ignored, because we assume it is an error on our partners side.
+    Ignored,
+    /// The row was rejected, because the account of the client is locked. This does not abort
+    /// the run, since a single locked account should not hold up the other clients.
+    Rejected,
+}
+
+impl Incident {
+    /// Describes a row that could not be processed, which a [TransactionSource] returns for a row
+    /// that it can not turn into a mutation.
+    pub fn failed(tx: Option<u32>, client: Option<u32>, error: impl Display) -> Self {
+        Self {
+            kind: IncidentKind::Failed,
+            tx,
+            client,
+            reason: error.to_string(),
+        }
+    }
+
+    fn rejected(tx: u32, client: u32, error: impl Display) -> Self {
+        Self {
+            kind: IncidentKind::Rejected,
+            tx: Some(tx),
+            client: Some(client),
+            reason: error.to_string(),
+        }
+    }
+
+    fn ignored(tx: u32, client: u32, reason: IgnoreReason) -> Self {
+        Self {
+            kind: IncidentKind::Ignored,
+            tx: Some(tx),
+            client: Some(client),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// The effect of a row with the transaction id of [EngineConfig::explain] on the account that it
+/// applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// The number of the row among the rows that are processed.
+    pub row: u64,
+    pub tx: u32,
+    pub kind: TransactionType,
+    pub client: u32,
+    pub currency: Option<Currency>,
+    /// The account before the row, or `None` when it did not exist yet.
+    pub before: Option<account::Account>,
+    /// The account after the row, or `None` when it still does not exist.
+    pub after: Option<account::Account>,
+    /// The status of the transaction after the row, or `None` when it is not recorded.
+    pub status: Option<TransactionStatus>,
+    /// Why the row was not applied, or `None` when it was.
+    pub incident: Option<Incident>,
+}
+
+impl Transition {
+    fn before(
+        row: u64,
+        trx: &Mutation,
+        accounts: &account::Accounts,
+        trxs: &transaction::Transactions,
+    ) -> Self {
+        let currency = trx.account_currency(trxs);
+        Self {
+            row,
+            tx: trx.id,
+            kind: trx.kind,
+            client: trx.client,
+            currency,
+            before: accounts.get(trx.client, currency).copied(),
+            after: None,
+            status: None,
+            incident: None,
+        }
+    }
+
+    fn after(
+        self,
+        accounts: &account::Accounts,
+        trxs: &transaction::Transactions,
+        incident: Option<Incident>,
+    ) -> Self {
+        Self {
+            after: accounts.get(self.client, self.currency).copied(),
+            status: trxs.get(self.client, self.tx).map(|trx| trx.status),
+            incident,
+            ..self
+        }
+    }
+
+    /// Describes the transition on a single line, presenting the balances with `precision`
+    /// decimals.
+    pub fn describe(&self, precision: u32) -> String {
+        let outcome = match &self.incident {
+            Some(incident) => format!("was not applied ({})", incident.reason),
+            None => "was applied".to_string(),
+        };
+        let status = match self.status {
+            Some(status) => format!("{status:?}").to_lowercase(),
+            None => "not recorded".to_string(),
+        };
+        let balance = |account: Option<account::Account>| match account {
+            Some(account) => format!(
+                "available {}, held {}, total {}{}",
+                account.available.to_decimal_string(precision),
+                account.held.to_decimal_string(precision),
+                account.total.to_decimal_string(precision),
+                if account.locked { ", locked" } else { "" }
+            ),
+            None => "no account".to_string(),
+        };
+        format!(
+            "Row {}: {:?} of trx {} for client {} {outcome}, the transaction is {status}: {} -> {}",
+            self.row,
+            self.kind,
+            self.tx,
+            self.client,
+            balance(self.before),
+            balance(self.after)
+        )
+    }
+}
+
+/// The formats in which the engine can present the resulting account states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A csv with a header row, where the balances are formatted as strings with a fixed
+    /// number of decimals.
+    Csv,
+    /// Newline-delimited JSON, with one object per account and numeric balances.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::JsonLines),
+            _ => Err(eyre!("Unknown output format {s}, expected csv or json")),
+        }
+    }
+}
+
+/// The knobs that control how the engine processes its input and presents its output. Use
+/// [EngineConfig::builder] to construct a validated configuration.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// When set, rows that can not be processed are skipped instead of aborting the run.
+    pub lenient: bool,
+    /// When set, the output rows are sorted by client id, which makes the output deterministic.
+    pub sort: bool,
+    /// The number of bytes of the input that the csv reader buffers. A larger buffer means fewer
+    /// reads, which can improve the throughput for large inputs on fast storage.
+    pub buffer_size: usize,
+    /// The character that separates the columns of the input, which is a comma by default.
+    pub delimiter: u8,
+    /// The format in which the resulting account states are written.
+    pub format: OutputFormat,
+    /// When set, the output includes the number of deposits and withdrawals of each client, and
+    /// the total amount that they deposited.
+    pub stats: bool,
+    /// A custom order and naming of the csv columns. When this is `None`, the columns are written
+    /// as `client,available,held,total,locked,open_disputes`. The statistic columns can be part of
+    /// a custom layout, so it can not be combined with [EngineConfig::stats].
+    pub layout: Option<present::CsvLayout>,
+    /// When set, the output ends every row with the risk flags of the client, which are whether it
+    /// has ever had a transaction disputed, see
+    /// [Account::ever_disputed](account::Account::ever_disputed), and whether it is marked for
+    /// review, see [Account::under_review](account::Account::under_review). Like the statistics,
+    /// the flags can be part of a custom layout, so they can not be combined with
+    /// [EngineConfig::layout].
+    pub risk_flags: bool,
+    /// When set, the output ends every row with the number of the row that last changed the
+    /// account, see [Account::last_row](account::Account::last_row), so that a balance can be
+    /// traced back to the input. The column can be part of a custom layout, so it can not be
+    /// combined with [EngineConfig::layout].
+    pub last_row: bool,
+    /// How the locked state of an account is written in csv output, which is `true` or `false` by
+    /// default. JSON output always has a boolean.
+    pub locked: present::LockedFormat,
+    /// When set, the csv output ends with a row that sums the balances of all accounts, with
+    /// `total` as its client and the number of locked accounts in the locked column, see
+    /// [present::Totals]. Accounts in different currencies get a row per currency.
+    pub summary_row: bool,
+    /// When set, the balances in the csv output are written as the integer number of smallest
+    /// increments that the engine maintains, such as `15000` for 1.5 with a precision of four,
+    /// for consumers that apply the scale themselves.
+    pub minor_units: bool,
+    /// The number of decimals that amounts are maintained and presented with.
+    pub precision: u32,
+    /// How amounts in the input with more decimals than the precision are converted. By default
+    /// they are rejected.
+    pub rounding: Rounding,
+    /// The power of ten that the amounts in the input are divided by to get an amount of currency.
+    /// This is zero by default, meaning that the input is denominated in currency, but a scale of
+    /// two allows reading input that is denominated in cents, for example. The scale may not be
+    /// larger than the precision, since the input could not be represented otherwise.
+    pub input_scale: u32,
+    /// The number of threads that process transactions. With more than one thread, the clients are
+    /// divided over the threads. Note that the threads do not share their transactions, so reused
+    /// transaction ids and disputes across clients are only detected when using a single thread.
+    /// An input with transfers is processed on a single thread regardless, see
+    /// [TransactionSource::has_transfers].
+    pub threads: usize,
+    /// The maximum number of deposits and withdrawals that are retained for disputes, or `None` to
+    /// retain all of them. A dispute that references a transaction that is no longer retained is
+    /// ignored. With multiple threads, every thread retains this many transactions.
+    pub history_limit: Option<usize>,
+    /// When set, the rows are sorted by their optional `timestamp` column before they are applied,
+    /// for inputs that are not delivered in chronological order. This reads the entire input into
+    /// memory first. See [crate::parse::sort_chronologically] for how missing and equal timestamps
+    /// are ordered.
+    pub chronological: bool,
+    /// When set, the client of every dispute, resolve and chargeback is checked against the client
+    /// of the transaction that it references before the row is applied, and a mismatch fails with
+    /// the number of the row. The row number is the position of the row among the rows that are
+    /// processed, which is its position in the input unless the rows are sorted chronologically.
+    /// This check needs to see the transactions of all clients, so it processes the rows on a
+    /// single thread, regardless of [EngineConfig::threads].
+    pub check_clients: bool,
+    /// When set, transaction ids are only unique per client, so the same id may be used by
+    /// different clients, and a dispute, resolve or chargeback references the transaction with
+    /// that id of its own client. See
+    /// [Transactions::set_per_client](transaction::Transactions::set_per_client).
+    pub per_client_ids: bool,
+    /// When set, processing stops right after the first chargeback that locks an account, so the
+    /// account can be reviewed before more rows are applied. The rows up to and including the
+    /// chargeback are applied, and [RunSummary::stopped_at_row] tells where processing stopped.
+    /// Stopping right away needs to process the rows on a single thread, regardless of
+    /// [EngineConfig::threads].
+    pub stop_on_lock: bool,
+    /// The maximum number of rows that a single input may have, or `None` for no maximum. An input
+    /// with more rows aborts the run before the first row beyond the maximum is applied, which
+    /// protects against inputs that would retain more transactions than fit in memory.
+    pub max_rows: Option<u64>,
+    /// In lenient mode, the number of rows that may fail before the run is aborted, or `None` for
+    /// no maximum. An input that is mostly invalid would otherwise produce a meaningless output.
+    /// With multiple threads, the rows of the workers are all processed before their errors are
+    /// counted.
+    pub max_errors: Option<u64>,
+    /// When set, every row with this transaction id is recorded as a [Transition], along with the
+    /// account that it applies to before and after the row, see [ingest_with_transitions].
+    /// Recording the transitions in input order needs to process the rows on a single thread,
+    /// regardless of [EngineConfig::threads].
+    pub explain: Option<u32>,
+    /// When set, only the accounts of these clients are written. All rows are still processed,
+    /// since a dispute of one of these clients could reference any transaction.
+    pub clients: Option<Vec<u32>>,
+    /// When set, only the rows of these clients are processed, and the rows of every other client
+    /// are skipped. Unlike [EngineConfig::clients], this filters the rows before they are applied,
+    /// so the accounts of the other clients are not touched at all.
+    pub allow_clients: Option<Vec<u32>>,
+    /// When set, the rows of these clients are skipped, for example because a client is under
+    /// legal hold. All rows of a client are skipped, including the disputes of its earlier
+    /// transactions, so that the state of the client stays consistent. This can not be combined
+    /// with [EngineConfig::allow_clients].
+    pub deny_clients: Option<Vec<u32>>,
+    /// The policy that is followed when applying transactions to accounts.
+    pub policy: Policy,
+}
+
+impl EngineConfig {
+    /// Starts building a configuration from the defaults.
+    pub fn builder() -> EngineConfigBuilder {
+        EngineConfigBuilder::default()
+    }
+
+    /// Checks that the knobs are not set to contradicting values.
+    pub fn validate(&self) -> Result<()> {
+        if self.stats && self.layout.is_some() {
+            return Err(eyre!(
+                "Statistics can not be combined with a custom layout, include the statistic \
+                columns in the layout instead"
+            ));
+        }
+        if self.risk_flags && self.layout.is_some() {
+            return Err(eyre!(
+                "Risk flags can not be combined with a custom layout, include the ever_disputed \
+                and under_review columns in the layout instead"
+            ));
+        }
+        if self.last_row && self.layout.is_some() {
+            return Err(eyre!(
+                "The last row can not be combined with a custom layout, include the last_row \
+                column in the layout instead"
+            ));
+        }
+        if self.summary_row && self.format != OutputFormat::Csv {
+            return Err(eyre!("A summary row can only be added to csv output"));
+        }
+        if self.minor_units && self.format != OutputFormat::Csv {
+            return Err(eyre!("Minor units can only be written in csv output"));
+        }
+        if self.allow_clients.is_some() && self.deny_clients.is_some() {
+            return Err(eyre!("Clients can not be both allowed and denied"));
+        }
+        if self.buffer_size == 0 {
+            return Err(eyre!("The buffer size must be at least one byte"));
+        }
+        if self.input_scale > self.precision {
+            return Err(eyre!(
+                "An input scale of {} is larger than the precision of {}",
+                self.input_scale,
+                self.precision
+            ));
+        }
+        Ok(())
+    }
+
+    /// The number of decimals that the amounts in the input may have. An amount of `150` with an
+    /// input scale of two is 1.50 currency, so with a precision of four it is read as `150.00`.
+    pub(crate) fn input_precision(&self) -> u32 {
+        self.precision.saturating_sub(self.input_scale)
+    }
+
+    /// The number of decimals that the amounts in the output are written with. In minor units an
+    /// amount has no decimals, so the integer that the engine maintains is written as it is.
+    fn output_precision(&self) -> u32 {
+        match self.minor_units {
+            true => 0,
+            false => self.precision,
+        }
+    }
+
+    /// Whether `trx` is skipped because of [EngineConfig::allow_clients] or
+    /// [EngineConfig::deny_clients]. A transfer is skipped when either of its clients is.
+    pub(crate) fn filters_out(&self, trx: &Mutation) -> bool {
+        let allowed = |client| match (&self.allow_clients, &self.deny_clients) {
+            (Some(allowed), _) => allowed.contains(&client),
+            (_, Some(denied)) => !denied.contains(&client),
+            (None, None) => true,
+        };
+        !allowed(trx.client) || trx.to_client.map_or(false, |to| !allowed(to))
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            lenient: false,
+            sort: true,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            delimiter: b',',
+            format: OutputFormat::Csv,
+            stats: false,
+            layout: None,
+            risk_flags: false,
+            last_row: false,
+            locked: present::LockedFormat::default(),
+            summary_row: false,
+            minor_units: false,
+            precision: DEFAULT_PRECISION,
+            rounding: Rounding::Reject,
+            input_scale: 0,
+            threads: 1,
+            history_limit: None,
+            chronological: false,
+            check_clients: false,
+            per_client_ids: false,
+            stop_on_lock: false,
+            max_rows: None,
+            max_errors: None,
+            explain: None,
+            clients: None,
+            allow_clients: None,
+            deny_clients: None,
+            policy: Policy::default(),
+        }
+    }
+}
+
+/// Builds an [EngineConfig], starting from the defaults. Every setter consumes and returns the
+/// builder, so the calls can be chained.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfigBuilder {
+    config: EngineConfig,
+}
+
+impl EngineConfigBuilder {
+    /// See [EngineConfig::lenient].
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.config.lenient = lenient;
+        self
+    }
+
+    /// See [EngineConfig::sort].
+    pub fn sort(mut self, sort: bool) -> Self {
+        self.config.sort = sort;
+        self
+    }
+
+    /// See [EngineConfig::buffer_size].
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.config.buffer_size = size;
+        self
+    }
+
+    /// See [EngineConfig::delimiter].
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.config.delimiter = delimiter;
+        self
+    }
+
+    /// See [EngineConfig::format].
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// See [EngineConfig::stats].
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.config.stats = stats;
+        self
+    }
+
+    /// See [EngineConfig::layout].
+    pub fn layout(mut self, layout: present::CsvLayout) -> Self {
+        self.config.layout = Some(layout);
+        self
+    }
+
+    /// See [EngineConfig::risk_flags].
+    pub fn risk_flags(mut self, risk_flags: bool) -> Self {
+        self.config.risk_flags = risk_flags;
+        self
+    }
+
+    /// See [EngineConfig::last_row].
+    pub fn last_row(mut self, last_row: bool) -> Self {
+        self.config.last_row = last_row;
+        self
+    }
+
+    /// See [EngineConfig::locked].
+    pub fn locked(mut self, locked: present::LockedFormat) -> Self {
+        self.config.locked = locked;
+        self
+    }
+
+    /// See [EngineConfig::summary_row].
+    pub fn summary_row(mut self, summary_row: bool) -> Self {
+        self.config.summary_row = summary_row;
+        self
+    }
+
+    /// See [EngineConfig::minor_units].
+    pub fn minor_units(mut self, minor_units: bool) -> Self {
+        self.config.minor_units = minor_units;
+        self
+    }
+
+    /// See [EngineConfig::precision].
+    pub fn precision(mut self, precision: u32) -> Self {
+        self.config.precision = precision;
+        self
+    }
+
+    /// See [EngineConfig::rounding].
+    pub fn rounding(mut self, rounding: Rounding) -> Self {
+        self.config.rounding = rounding;
+        self
+    }
+
+    /// See [EngineConfig::input_scale].
+    pub fn input_scale(mut self, input_scale: u32) -> Self {
+        self.config.input_scale = input_scale;
+        self
+    }
+
+    /// See [EngineConfig::threads].
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    /// See [EngineConfig::history_limit].
+    pub fn history_limit(mut self, limit: Option<usize>) -> Self {
+        self.config.history_limit = limit;
+        self
+    }
+
+    /// See [EngineConfig::chronological].
+    pub fn chronological(mut self, chronological: bool) -> Self {
+        self.config.chronological = chronological;
+        self
+    }
+
+    /// See [EngineConfig::check_clients].
+    pub fn check_clients(mut self, check: bool) -> Self {
+        self.config.check_clients = check;
+        self
+    }
+
+    /// See [EngineConfig::per_client_ids].
+    pub fn per_client_ids(mut self, per_client: bool) -> Self {
+        self.config.per_client_ids = per_client;
+        self
+    }
+
+    /// See [EngineConfig::stop_on_lock].
+    pub fn stop_on_lock(mut self, stop: bool) -> Self {
+        self.config.stop_on_lock = stop;
+        self
+    }
+
+    /// See [EngineConfig::max_rows].
+    pub fn max_rows(mut self, max: Option<u64>) -> Self {
+        self.config.max_rows = max;
+        self
+    }
+
+    /// See [EngineConfig::max_errors].
+    pub fn max_errors(mut self, max: Option<u64>) -> Self {
+        self.config.max_errors = max;
+        self
+    }
+
+    /// See [EngineConfig::explain].
+    pub fn explain(mut self, tx: Option<u32>) -> Self {
+        self.config.explain = tx;
+        self
+    }
+
+    /// See [EngineConfig::clients].
+    pub fn clients(mut self, clients: Vec<u32>) -> Self {
+        self.config.clients = Some(clients);
+        self
+    }
+
+    /// See [EngineConfig::allow_clients].
+    pub fn allow_clients(mut self, clients: Vec<u32>) -> Self {
+        self.config.allow_clients = Some(clients);
+        self
+    }
+
+    /// See [EngineConfig::deny_clients].
+    pub fn deny_clients(mut self, clients: Vec<u32>) -> Self {
+        self.config.deny_clients = Some(clients);
+        self
+    }
+
+    /// See [EngineConfig::policy].
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.config.policy = policy;
+        self
+    }
+
+    /// See [Policy::overdraft_limit].
+    pub fn overdraft_limit(mut self, limit: Amount) -> Self {
+        self.config.policy.overdraft_limit = limit;
+        self
+    }
+
+    /// See [Policy::partial_chargebacks].
+    pub fn partial_chargebacks(mut self, partial: bool) -> Self {
+        self.config.policy.partial_chargebacks = partial;
+        self
+    }
+
+    /// See [Policy::strict_withdrawals].
+    pub fn strict_withdrawals(mut self, strict: bool) -> Self {
+        self.config.policy.strict_withdrawals = strict;
+        self
+    }
+
+    /// See [Policy::strict_disputes].
+    pub fn strict_disputes(mut self, strict: bool) -> Self {
+        self.config.policy.strict_disputes = strict;
+        self
+    }
+
+    /// See [Policy::disputable].
+    pub fn disputable(mut self, disputable: Disputable) -> Self {
+        self.config.policy.disputable = disputable;
+        self
+    }
+
+    /// See [Policy::unfunded_disputes].
+    pub fn unfunded_disputes(mut self, unfunded: UnfundedDispute) -> Self {
+        self.config.policy.unfunded_disputes = unfunded;
+        self
+    }
+
+    /// See [Policy::uncovered_chargebacks].
+    pub fn uncovered_chargebacks(mut self, uncovered: UncoveredChargeback) -> Self {
+        self.config.policy.uncovered_chargebacks = uncovered;
+        self
+    }
+
+    /// See [Policy::held_limit].
+    pub fn held_limit(mut self, limit: Option<Amount>) -> Self {
+        self.config.policy.held_limit = limit;
+        self
+    }
+
+    /// See [Policy::review_excess_held].
+    pub fn review_excess_held(mut self, review: bool) -> Self {
+        self.config.policy.review_excess_held = review;
+        self
+    }
+
+    /// Validates the configuration and returns it.
+    pub fn build(self) -> Result<EngineConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// The meat of our application. Reads csv data from `input` and runs it trough the engine to
+/// construct a list of accounts and transactions, then writes the resulting account states as csv
+/// to `output`. See [ingest] for how rows that can not be applied are handled.
+pub fn process<R: Read, W: Write>(
+    input: R,
+    output: W,
+    config: &EngineConfig,
+) -> Result<Vec<Incident>> {
+    let mut state = EngineState::default();
+    let incidents = ingest(&mut state, input, config)?;
+    write_accounts(state, output, config)?;
+    Ok(incidents)
+}
+
+/// Reads csv data from `input` and applies it to the accounts and transactions in `state`. This
+/// allows the state to be built up from multiple inputs, or to resume from a snapshot. By default
+/// the first row that can not be processed aborts the run, in which case the state may be left
+/// partially updated. In lenient mode these rows are skipped. Rows for a locked account are always
+/// rejected without aborting the run. The skipped, ignored and rejected rows are all returned as
+/// incidents, so the caller can decide how to report them.
+///
+/// The rows of a single client are always applied in input order, also when processing with
+/// multiple threads, since a dispute only makes sense after the transaction that it references.
+/// Rows of different clients may be applied in any order relative to each other.
+pub fn ingest<R: Read>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+) -> Result<Vec<Incident>> {
+    let (incidents, _, _) = run(state, input, config, None)?;
+    Ok(incidents)
+}
+
+/// Like [ingest], but also returns a [RunSummary] with the number of rows that were read, applied,
+/// ignored, rejected and failed.
+pub fn ingest_with_summary<R: Read>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+) -> Result<(Vec<Incident>, RunSummary)> {
+    let (incidents, summary, _) = run(state, input, config, None)?;
+    Ok((incidents, summary))
+}
+
+/// Like [ingest_with_summary], but also returns the [Transition] of every row with the
+/// transaction id of [EngineConfig::explain], in input order.
+pub fn ingest_with_transitions<R: Read>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+) -> Result<(Vec<Incident>, RunSummary, Vec<Transition>)> {
+    run(state, input, config, None)
+}
+
+/// Like [ingest], but calls `on_update` with the new state of the account after every transaction
+/// that is applied, which allows observing the balances while the input is being consumed. The
+/// callback borrows the account from the engine, so nothing is cloned unless the callback decides
+/// to keep a copy. Since an [Account](account::Account) is only a few integers, copying it is
+/// cheap, and batching the updates is left to the callback. The updates are delivered in input
+/// order, so the input is always processed on the calling thread, regardless of
+/// [EngineConfig::threads].
+pub fn ingest_with_updates<R, F>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+    mut on_update: F,
+) -> Result<Vec<Incident>>
+where
+    R: Read,
+    F: FnMut(&account::Account),
+{
+    ingest_with_hook(state, input, config, |_, account| on_update(account))
+}
+
+/// Like [ingest_with_updates], but the `hook` also gets the mutation that was applied, which allows
+/// building an audit trail of every change to the accounts. The hook is called after the mutation
+/// was applied successfully, so ignored, rejected and failed rows do not reach it. Without a hook,
+/// as in [ingest], the engine does not look up the account or keep the mutation around at all.
+pub fn ingest_with_hook<R, F>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+    mut hook: F,
+) -> Result<Vec<Incident>>
+where
+    R: Read,
+    F: FnMut(&Mutation, &account::Account),
+{
+    let (incidents, _, _) = run(state, input, config, Some(&mut hook))?;
+    Ok(incidents)
+}
+
+/// Like [ingest_with_transitions], but skips the rows that the [EngineState::applied] log says were
+/// applied before, and adds every row that is applied to it, so that an input can be retried from
+/// its start. The log is created when the state does not have one yet. The skipped rows are
+/// returned as ignored incidents. The log is kept in input order, so the input is always processed
+/// on the calling thread, regardless of [EngineConfig::threads].
+///
+/// The log only says which rows were applied, so a retry has to resume from a snapshot of the
+/// state, which holds the log together with the accounts that its rows were applied to.
+pub fn ingest_with_log<R: Read>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+) -> Result<(Vec<Incident>, RunSummary, Vec<Transition>)> {
+    // The log is taken out of the state while the state is being processed.
+    let mut log = state.applied.take().unwrap_or_default();
+    let result = run(state, input, config, Some(&mut log));
+    state.applied = Some(log);
+    result
+}
+
+/// Follows the rows of a run that is processed on the calling thread, see [ingest_with_hook] and
+/// [ingest_with_log].
+trait Hook {
+    /// Whether `trx` should be skipped rather than applied, because it was applied before.
+    fn skip(&mut self, _trx: &Mutation) -> bool {
+        false
+    }
+
+    /// Called with the account after `trx` was applied to it, once for each of the two accounts of
+    /// a transfer. An error aborts the run.
+    fn applied(&mut self, trx: &Mutation, account: &account::Account) -> Result<()>;
+}
+
+impl<F: FnMut(&Mutation, &account::Account)> Hook for F {
+    fn applied(&mut self, trx: &Mutation, account: &account::Account) -> Result<()> {
+        self(trx, account);
+        Ok(())
+    }
+}
+
+impl Hook for IdempotencyLog {
+    fn skip(&mut self, trx: &Mutation) -> bool {
+        self.was_applied(trx)
+    }
+
+    fn applied(&mut self, trx: &Mutation, account: &account::Account) -> Result<()> {
+        // A transfer is a single row, even though it is applied to the account of the recipient
+        // as well.
+        if account.client == trx.client {
+            self.append(trx);
+        }
+        Ok(())
+    }
+}
+
+/// Like [ingest_with_summary], but reads the mutations from any [TransactionSource] rather than
+/// from csv data.
+pub fn ingest_source<S: TransactionSource>(
+    state: &mut EngineState,
+    mut source: S,
+    config: &EngineConfig,
+) -> Result<(Vec<Incident>, RunSummary)> {
+    config.validate()?;
+    let (incidents, summary, _) = run_source(state, &mut source, config, None)?;
+    Ok((incidents, summary))
+}
+
+fn run<R: Read>(
+    state: &mut EngineState,
+    input: R,
+    config: &EngineConfig,
+    hook: Option<&mut dyn Hook>,
+) -> Result<(Vec<Incident>, RunSummary, Vec<Transition>)> {
+    config.validate()?;
+    let mut source = CsvSource::new(input, config)?;
+    run_source(state, &mut source, config, hook)
+}
+
+fn run_source(
+    state: &mut EngineState,
+    source: &mut dyn TransactionSource,
+    config: &EngineConfig,
+    hook: Option<&mut dyn Hook>,
+) -> Result<(Vec<Incident>, RunSummary, Vec<Transition>)> {
+    let (mut incidents, mut summary) = (Vec::new(), RunSummary::default());
+    let mut transitions = Vec::new();
+    let mut errors = 0;
+    let mut on_incident = |incident: Incident| {
+        if incident.kind == IncidentKind::Failed && !config.lenient {
+            return Err(eyre!(incident.reason));
+        }
+        if incident.kind == IncidentKind::Failed {
+            errors += 1;
+            if config.max_errors.map_or(false, |max| errors >= max) {
+                return Err(eyre!(
+                    "Stopping after {errors} rows that could not be processed, which is the \
+                    maximum that is allowed, the last one being: {}",
+                    incident.reason
+                ));
+            }
+        }
+        incidents.push(incident);
+        Ok(())
+    };
+    state.trxs.set_limit(config.history_limit);
+    state.trxs.set_per_client(config.per_client_ids)?;
+
+    let sequential = config.check_clients
+        || config.stop_on_lock
+        || config.explain.is_some()
+        || source.has_transfers();
+    // The hook follows the rows in input order, so it needs them to be processed on this thread.
+    if config.threads > 1 && !sequential && hook.is_none() {
+        parallel::process(source, state, config, &mut on_incident, &mut summary)?
+    } else {
+        process_sequential(
+            source,
+            state,
+            config,
+            &mut on_incident,
+            &mut summary,
+            hook,
+            &mut transitions,
+        )?
+    }
+    summary.log();
+    Ok((incidents, summary, transitions))
+}
+
+/// Writes the resulting account states in `state` to `output`. The accounts are written while they
+/// are taken out of the state, and the writers pass their output on to `output` whenever their
+/// buffer of a few kilobytes is full, so without sorting the memory that writing takes does not
+/// depend on the number of accounts.
+///
+/// Sorting is the exception, since the accounts are kept in a map, which has no order, so all
+/// accounts are moved into a list and sorted before the first one is written. That briefly holds
+/// every account twice, but an account is only a few integers, which is small compared to the
+/// transactions that the state holds as well. Turn off [EngineConfig::sort] to write them as they
+/// come.
+pub fn write_accounts<W: Write>(
+    state: EngineState,
+    output: W,
+    config: &EngineConfig,
+) -> Result<()> {
+    write_shards(state, vec![output], config)
+}
+
+/// Like [write_accounts], but spreads the accounts over the `outputs`, which are written in a
+/// single pass. Each account goes to the output at its client id modulo the number of outputs, so
+/// every client always lands in the same shard. Every output is a complete file in the configured
+/// format, and with [EngineConfig::summary_row] it ends with the sums of its own accounts.
+pub fn write_shards<W: Write>(
+    state: EngineState,
+    outputs: Vec<W>,
+    config: &EngineConfig,
+) -> Result<()> {
+    let shards = outputs.len();
+    if shards == 0 {
+        return Err(eyre!("The accounts can not be written to zero shards"));
+    }
+    let shard = |account: &account::Account| account.client as usize % shards;
+    let selected = |account: &account::Account| match &config.clients {
+        Some(clients) => clients.contains(&account.client),
+        None => true,
+    };
+    // The currency column is only written when the input has currencies, so that the output of an
+    // input without them stays the same. All shards get the same columns.
+    let currencies =
+        (state.accounts.iter()).any(|account| selected(account) && account.currency.is_some());
+    let mut writers = (outputs.into_iter())
+        .map(|output| AccountWriter::new(output, currencies, config))
+        .collect::<Result<Vec<_>>>()?;
+    let mut totals = vec![Vec::new(); shards];
+    if config.summary_row {
+        for (index, totals) in totals.iter_mut().enumerate() {
+            let accounts = state.accounts.iter();
+            let accounts = accounts.filter(|a| selected(a) && shard(a) == index);
+            *totals = present::Totals::per_currency(accounts);
+        }
+    }
+    let accounts = state
+        .accounts
+        .into_iter()
+        .filter(|account| selected(account));
+    if config.sort {
+        let mut accounts: Vec<_> = accounts.collect();
+        accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+        for account in accounts {
+            writers[shard(&account)].write(account, config)?;
+        }
+    } else {
+        for account in accounts {
+            writers[shard(&account)].write(account, config)?;
+        }
+    }
+    for (writer, totals) in writers.into_iter().zip(totals) {
+        writer.finish(totals, config)?;
+    }
+    Ok(())
+}
+
+/// Checks whether writing failed because the reader on the other end went away, such as when the
+/// output is piped into `head`. The caller can treat this as a normal way for a run to end, rather
+/// than as a failure.
+pub fn is_broken_pipe(report: &Report) -> bool {
+    report.chain().any(|error| {
+        let io_error = match error.downcast_ref::<csv::Error>() {
+            Some(error) => match error.kind() {
+                csv::ErrorKind::Io(error) => Some(error),
+                _ => None,
+            },
+            None => error.downcast_ref::<std::io::Error>(),
+        };
+        io_error.map_or(false, |error| {
+            error.kind() == std::io::ErrorKind::BrokenPipe
+        })
+    })
+}
+
+/// Writes the incidents to `output` as newline-delimited JSON, with one object per incident.
+pub fn write_incidents<W: Write>(incidents: &[Incident], output: W) -> Result<()> {
+    let mut writer = BufWriter::new(output);
+    for incident in incidents {
+        serde_json::to_writer(&mut writer, incident)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Processes all mutations of `source` on the current thread, calling the `hook`, when there is
+/// one, for each row that is applied and `on_incident` for each row that is not, and counting the
+/// rows in `summary`. If
+/// `on_incident` returns an error itself, the run is aborted. The rows that are explained are added
+/// to `transitions`.
+fn process_sequential<F>(
+    source: &mut dyn TransactionSource,
+    state: &mut EngineState,
+    config: &EngineConfig,
+    on_incident: &mut F,
+    summary: &mut RunSummary,
+    mut hook: Option<&mut dyn Hook>,
+    transitions: &mut Vec<Transition>,
+) -> Result<()>
+where
+    F: FnMut(Incident) -> Result<()>,
+{
+    // Our state is maintained in two structs, one contains all the accounts, whereas the other
+    // contains all of our transaction state, that is, deposits and withdrawals. We would have
+    // preferred to not need to keep track of the latter, but since disputes, resolves and
+    // chargebacks don't contain their own amount, we need to be able to look back at the entire
+    // history of deposits and withdrawals.
+    let EngineState { accounts, trxs, .. } = state;
+
+    // We iterate over each record in the csv file.
+    let mutations = std::iter::from_fn(|| source.next_mutation());
+    for (index, result) in mutations.enumerate() {
+        check_row_limit(summary, config)?;
+        summary.rows += 1;
+        let chargebacks = summary.chargebacks;
+        let incident = match result {
+            Ok(trx) if config.filters_out(&trx) => {
+                summary.filtered += 1;
+                None
+            }
+            Ok(trx) => {
+                let explained = (config.explain == Some(trx.id))
+                    .then(|| Transition::before(summary.rows, &trx, accounts, trxs));
+                let mismatch = match config.check_clients {
+                    true => check_client(&trx, trxs, index + 1),
+                    false => None,
+                };
+                let incident = match mismatch {
+                    Some(incident) => {
+                        summary.failed += 1;
+                        Some(incident)
+                    }
+                    None => match &mut hook {
+                        Some(hook) => apply_and_hook(trx, accounts, trxs, config, summary, *hook)?,
+                        None => {
+                            let (row, policy) = (summary.rows, &config.policy);
+                            apply(trx, row, accounts, trxs, policy, config.precision, summary)
+                        }
+                    },
+                };
+                if let Some(transition) = explained {
+                    transitions.push(transition.after(accounts, trxs, incident.clone()));
+                }
+                incident
+            }
+            Err(incident) => {
+                summary.failed += 1;
+                Some(incident)
+            }
+        };
+        if let Some(incident) = incident {
+            on_incident(incident)?;
+        }
+        // Every chargeback that is applied locks the account.
+        if config.stop_on_lock && summary.chargebacks > chargebacks {
+            tracing::info!(
+                row = summary.rows,
+                "Stopping, because an account was locked"
+            );
+            summary.stopped_at_row = Some(summary.rows);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails when the rows that were counted in `summary` already reach [EngineConfig::max_rows], so
+/// the next row would exceed it.
+pub(crate) fn check_row_limit(summary: &RunSummary, config: &EngineConfig) -> Result<()> {
+    match config.max_rows {
+        Some(max) if summary.rows >= max => Err(eyre!(
+            "The input has more than {max} rows, which is the maximum that is allowed"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Applies the mutation unless the `hook` skips it, and calls the `hook` with the mutation and the
+/// account when it was applied, followed by the account of the recipient for a transfer.
+fn apply_and_hook(
+    trx: Mutation,
+    accounts: &mut account::Accounts,
+    trxs: &mut transaction::Transactions,
+    config: &EngineConfig,
+    summary: &mut RunSummary,
+    hook: &mut dyn Hook,
+) -> Result<Option<Incident>> {
+    if hook.skip(&trx) {
+        let reason = IgnoreReason::AlreadyApplied;
+        summary.ignore(trx.kind, reason);
+        return Ok(Some(Incident::ignored(trx.id, trx.client, reason)));
+    }
+    let currency = trx.account_currency(trxs);
+    let (row, policy) = (summary.rows, &config.policy);
+    let incident = apply(trx, row, accounts, trxs, policy, config.precision, summary);
+    if incident.is_none() {
+        hook.applied(&trx, accounts.account_for_id(trx.client, currency))?;
+        if let Some(to) = trx.to_client {
+            hook.applied(&trx, accounts.account_for_id(to, currency))?;
+        }
+    }
+    Ok(incident)
+}
+
+/// Checks that a dispute, resolve or chargeback is for the same client as the transaction that it
+/// references, and returns an incident with the number of the row when it is not.
+fn check_client(trx: &Mutation, trxs: &transaction::Transactions, row: usize) -> Option<Incident> {
+    use TransactionType::*;
+    if !matches!(trx.kind, Dispute | Resolve | Chargeback) {
+        return None;
+    }
+    let owner = trxs.get(trx.client, trx.id)?.client;
+    if owner == trx.client {
+        return None;
+    }
+    let (tx, client) = (trx.id, trx.client);
+    let error = TransactionError::ForeignTransaction { tx, client, owner };
+    let reason = format!("Row {row}: {error}");
+    Some(Incident::failed(Some(tx), Some(client), reason))
+}
+
+/// Writes accounts one at a time in the configured format, so that the accounts can be spread
+/// over multiple outputs.
+enum AccountWriter<W: Write> {
+    /// A csv with the columns of the layout, which is written with a header up front. This is used
+    /// for a custom layout, and for accounts that have a currency.
+    Layout(csv::Writer<W>, present::CsvLayout),
+    /// A csv of [present::CsvRow]s, or [present::StatsCsvRow]s with statistics. The header is
+    /// written up front as well, rather than along with the first row, so that the output of an
+    /// input without rows still has a header.
+    Csv(csv::Writer<W>),
+    JsonLines(BufWriter<W>),
+}
+
+impl<W: Write> AccountWriter<W> {
+    /// Starts writing to `output`. The currency column is added when `currencies` is set.
+    fn new(output: W, currencies: bool, config: &EngineConfig) -> Result<Self> {
+        let writer = match config.format {
+            OutputFormat::Csv if config.layout.is_some() || currencies => {
+                let layout = match &config.layout {
+                    Some(layout) => layout.clone(),
+                    None => standard_layout(config, true),
+                };
+                let layout = layout.with_locked(config.locked);
+                let mut writer = csv::Writer::from_writer(output);
+                writer.write_record(layout.headers())?;
+                AccountWriter::Layout(writer, layout)
+            }
+            OutputFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(output);
+                writer.write_record(standard_layout(config, false).headers())?;
+                AccountWriter::Csv(writer)
+            }
+            OutputFormat::JsonLines => AccountWriter::JsonLines(BufWriter::new(output)),
+        };
+        Ok(writer)
+    }
+
+    fn write(&mut self, account: account::Account, config: &EngineConfig) -> Result<()> {
+        let precision = config.output_precision();
+        let (flags, last_row) = (config.risk_flags, config.last_row);
+        match self {
+            AccountWriter::Layout(writer, layout) => {
+                writer.write_record(layout.row(&account, precision))?
+            }
+            // We transform each account from our internal sturct to a struct that matches the csv
+            // rows we need to produce.
+            AccountWriter::Csv(writer) if config.stats => {
+                let row = present::StatsCsvRow::from_account(account, precision)
+                    .with_locked(&account, config.locked);
+                let row = if flags { row.with_flags(&account) } else { row };
+                let row = if last_row {
+                    row.with_last_row(&account)
+                } else {
+                    row
+                };
+                writer.serialize(row)?;
+            }
+            AccountWriter::Csv(writer) => {
+                let row = present::CsvRow::from_account(account, precision)
+                    .with_locked(&account, config.locked);
+                let row = if flags { row.with_flags(&account) } else { row };
+                let row = if last_row {
+                    row.with_last_row(&account)
+                } else {
+                    row
+                };
+                writer.serialize(row)?;
+            }
+            AccountWriter::JsonLines(writer) => {
+                if config.stats {
+                    let row = present::StatsJsonRow::from_account(account, precision);
+                    let row = if flags { row.with_flags(&account) } else { row };
+                    let row = if last_row {
+                        row.with_last_row(&account)
+                    } else {
+                        row
+                    };
+                    serde_json::to_writer(&mut *writer, &row)?;
+                } else {
+                    let row = present::JsonRow::from_account(account, precision);
+                    let row = if flags { row.with_flags(&account) } else { row };
+                    let row = if last_row {
+                        row.with_last_row(&account)
+                    } else {
+                        row
+                    };
+                    serde_json::to_writer(&mut *writer, &row)?;
+                }
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the `totals` for the summary row, and flushes the output.
+    fn finish(self, totals: Vec<present::Totals>, config: &EngineConfig) -> Result<()> {
+        let precision = config.output_precision();
+        match self {
+            AccountWriter::Layout(mut writer, layout) => {
+                for totals in totals {
+                    writer.write_record(totals.row(&layout, precision))?;
+                }
+                writer.flush()?;
+            }
+            AccountWriter::Csv(mut writer) => {
+                let layout = standard_layout(config, false);
+                for totals in totals {
+                    writer.write_record(totals.row(&layout, precision))?;
+                }
+                writer.flush()?;
+            }
+            AccountWriter::JsonLines(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// The columns of the [present::CsvRow]s or [present::StatsCsvRow]s that are written for `config`,
+/// with a currency column when `currencies` is set.
+fn standard_layout(config: &EngineConfig, currencies: bool) -> present::CsvLayout {
+    let mut layout = match currencies {
+        true => present::CsvLayout::with_currency(config.stats),
+        false => present::CsvLayout::standard(config.stats),
+    };
+    if config.risk_flags {
+        layout = layout.with_flags();
+    }
+    if config.last_row {
+        layout = layout.with_last_row();
+    }
+    layout
+}
+
+/// Applies the mutation to the account of its client. When the mutation is not applied, this
+/// returns an incident that describes why, presenting amounts with `precision` decimals.
+pub(crate) fn apply(
+    trx: Mutation,
+    row: u64,
+    accounts: &mut account::Accounts,
+    trxs: &mut transaction::Transactions,
+    policy: &Policy,
+    precision: u32,
+    summary: &mut RunSummary,
+) -> Option<Incident> {
+    let (tx, client, kind) = (trx.id, trx.client, trx.kind);
+    let _span = tracing::debug_span!("row", tx, client, ?kind).entered();
+    let flow = net_flow(&trx, trxs);
+    // Mutate the account of the client according to this transaction.
+    match accounts.mutate(trx, row, trxs, policy) {
+        Ok(Outcome::Applied) => {
+            summary.applied(kind);
+            summary.net_flow += flow;
+            None
+        }
+        Ok(Outcome::Ignored(reason)) => {
+            tracing::debug!(%reason, "Ignored transaction");
+            summary.ignore(kind, reason);
+            Some(Incident::ignored(tx, client, reason))
+        }
+        Err(e @ TransactionError::AccountLocked { .. }) => {
+            tracing::debug!(error = %e, "Rejected transaction");
+            summary.rejected += 1;
+            Some(Incident::rejected(tx, client, e.describe(precision)))
+        }
+        Err(e) => {
+            tracing::debug!(error = %e, "Failed transaction");
+            summary.failed += 1;
+            let reason = e.describe(precision);
+            Some(Incident::failed(Some(tx), Some(client), reason))
+        }
+    }
+}
+
+/// The amount by which `trx` changes the total of its account when it is applied. This is worked
+/// out from the transaction types rather than from the account, so that a [Reconciliation] catches
+/// mistakes in the bookkeeping of the accounts.
+fn net_flow(trx: &Mutation, trxs: &transaction::Transactions) -> i128 {
+    use TransactionType::*;
+    let amount = i128::from(trx.amount.map_or(0, |amount| amount.0));
+    let original = match trx.kind {
+        // The amount of an adjustment is signed already.
+        Deposit | Interest | Adjustment => return amount,
+        Withdrawal | Fee => return -amount,
+        // A transfer moves funds between accounts, not into them.
+        Unlock | Transfer => return 0,
+        Dispute | Resolve | Chargeback => match trxs.get(trx.client, trx.id) {
+            Some(original) => original,
+            None => return 0,
+        },
+    };
+    // Every kind that can be disputed is disputed like a deposit or like a withdrawal.
+    let (movement, full) = Movement::of(original);
+    let full = i128::from(full.0);
+    // A chargeback without an amount charges back the entire transaction.
+    let charged = trx.amount.map_or(full, |_| amount);
+    let disputed = original.status == TransactionStatus::Disputed;
+    match (trx.kind, movement) {
+        // Only a disputed withdrawal brings funds back into the account, on hold.
+        (Dispute, Movement::Debit) => full,
+        (Resolve, Movement::Debit) => -full,
+        (Chargeback, Movement::Credit) => -charged,
+        // The held amount of a disputed withdrawal is already part of the total, and the part that
+        // is not charged back is debited again.
+        (Chargeback, Movement::Debit) if disputed => charged - full,
+        (Chargeback, Movement::Debit) => charged,
+        _ => 0,
+    }
+}
+
+/// Compares the totals of the accounts with the net flow of the mutations that were applied to
+/// them. The totals should have grown by exactly the net flow, so a mismatch means that there is a
+/// bug in the engine. All amounts are in smallest increments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// The sum of the totals of the accounts before processing.
+    pub opening: i128,
+    /// See [RunSummary::net_flow].
+    pub net_flow: i128,
+    /// The sum of the totals of the accounts after processing.
+    pub closing: i128,
+}
+
+impl Reconciliation {
+    pub fn is_balanced(&self) -> bool {
+        self.opening + self.net_flow == self.closing
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (opening, net_flow, closing) = (self.opening, self.net_flow, self.closing);
+        let relation = if self.is_balanced() {
+            "equal to"
+        } else {
+            "NOT equal to"
+        };
+        write!(
+            f,
+            "The totals add up to {closing} units, which is {relation} the opening balance of \
+            {opening} units plus the net flow of {net_flow} units"
+        )
+    }
+}
+
+/// Counts the rows that were read, and what became of them. The mutations that were applied are
+/// counted per transaction type.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    /// The rows that were read, including the ones that could not be parsed.
+    pub rows: u64,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub unlocks: u64,
+    pub interest: u64,
+    pub fees: u64,
+    pub adjustments: u64,
+    pub transfers: u64,
+    /// The mutations that were ignored, for any [IgnoreReason].
+    pub ignored: u64,
+    /// The ignored disputes that reference an unknown transaction.
+    pub unknown_disputes: u64,
+    /// The ignored disputes that reference a transaction that is already disputed.
+    pub repeated_disputes: u64,
+    /// The ignored resolves that reference a transaction that is not under dispute.
+    pub undisputed_resolves: u64,
+    /// The ignored chargebacks that reference a transaction that is not under dispute, nor
+    /// resolved.
+    pub undisputed_chargebacks: u64,
+    /// The mutations that were rejected because the account is locked.
+    pub rejected: u64,
+    /// The rows that could not be parsed or applied.
+    pub failed: u64,
+    /// The rows that were skipped, because their client is not allowed or denied.
+    pub filtered: u64,
+    /// The number of the row after which processing stopped, because it locked an account. This is
+    /// only set when [EngineConfig::stop_on_lock] is.
+    pub stopped_at_row: Option<u64>,
+    /// The net amount of smallest increments that the applied mutations moved into the accounts,
+    /// which the totals of the accounts should have grown by, see [Reconciliation].
+    pub net_flow: i128,
+}
+
+impl RunSummary {
+    fn applied(&mut self, kind: TransactionType) {
+        let counter = match kind {
+            TransactionType::Deposit => &mut self.deposits,
+            TransactionType::Withdrawal => &mut self.withdrawals,
+            TransactionType::Dispute => &mut self.disputes,
+            TransactionType::Resolve => &mut self.resolves,
+            TransactionType::Chargeback => &mut self.chargebacks,
+            TransactionType::Unlock => &mut self.unlocks,
+            TransactionType::Interest => &mut self.interest,
+            TransactionType::Fee => &mut self.fees,
+            TransactionType::Adjustment => &mut self.adjustments,
+            TransactionType::Transfer => &mut self.transfers,
+        };
+        *counter += 1;
+    }
+
+    fn ignore(&mut self, kind: TransactionType, reason: IgnoreReason) {
+        self.ignored += 1;
+        match (kind, reason) {
+            (TransactionType::Dispute, IgnoreReason::UnknownTransaction) => {
+                self.unknown_disputes += 1
+            }
+            (_, IgnoreReason::AlreadyDisputed) => self.repeated_disputes += 1,
+            (_, IgnoreReason::AlreadyApplied) => {}
+            (TransactionType::Resolve, _) => self.undisputed_resolves += 1,
+            (TransactionType::Chargeback, _) => self.undisputed_chargebacks += 1,
+            _ => {}
+        }
+    }
+
+    /// Adds the counts of `other` to this summary, for example to combine the summaries of
+    /// multiple inputs.
+    pub fn merge(&mut self, other: &RunSummary) {
+        self.rows += other.rows;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+        self.unlocks += other.unlocks;
+        self.interest += other.interest;
+        self.fees += other.fees;
+        self.adjustments += other.adjustments;
+        self.transfers += other.transfers;
+        self.ignored += other.ignored;
+        self.unknown_disputes += other.unknown_disputes;
+        self.repeated_disputes += other.repeated_disputes;
+        self.undisputed_resolves += other.undisputed_resolves;
+        self.undisputed_chargebacks += other.undisputed_chargebacks;
+        self.rejected += other.rejected;
+        self.failed += other.failed;
+        self.filtered += other.filtered;
+        self.stopped_at_row = other.stopped_at_row.or(self.stopped_at_row);
+        self.net_flow += other.net_flow;
+    }
+
+    pub(crate) fn log(&self) {
+        tracing::info!(
+            rows = self.rows,
+            deposits = self.deposits,
+            withdrawals = self.withdrawals,
+            disputes = self.disputes,
+            resolves = self.resolves,
+            chargebacks = self.chargebacks,
+            unlocks = self.unlocks,
+            interest = self.interest,
+            fees = self.fees,
+            adjustments = self.adjustments,
+            transfers = self.transfers,
+            ignored = self.ignored,
+            unknown_disputes = self.unknown_disputes,
+            repeated_disputes = self.repeated_disputes,
+            undisputed_resolves = self.undisputed_resolves,
+            undisputed_chargebacks = self.undisputed_chargebacks,
+            rejected = self.rejected,
+            failed = self.failed,
+            filtered = self.filtered,
+            "Processed transactions"
+        );
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Rows read: {}", self.rows)?;
+        writeln!(
+            f,
+            "Applied: {} deposits, {} withdrawals, {} disputes, {} resolves, {} chargebacks, \
+            {} unlocks, {} interest payments, {} fees, {} adjustments, {} transfers",
+            self.deposits,
+            self.withdrawals,
+            self.disputes,
+            self.resolves,
+            self.chargebacks,
+            self.unlocks,
+            self.interest,
+            self.fees,
+            self.adjustments,
+            self.transfers
+        )?;
+        writeln!(
+            f,
+            "Ignored: {} ({} disputes of unknown transactions, {} repeated disputes, {} resolves \
+            and {} chargebacks without a dispute)",
+            self.ignored,
+            self.unknown_disputes,
+            self.repeated_disputes,
+            self.undisputed_resolves,
+            self.undisputed_chargebacks
+        )?;
+        writeln!(f, "Rejected: {}", self.rejected)?;
+        writeln!(f, "Failed: {}", self.failed)?;
+        write!(f, "Filtered: {}", self.filtered)?;
+        match self.stopped_at_row {
+            Some(row) => write!(f, "\nStopped after row {row}, which locked an account"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "type,client,tx,amount\n\
+        deposit,1,1,2.0\n\
+        deposit,1,2,\n\
+        withdrawal,1,3,1.5\n";
+
+    #[test]
+    fn test_process_strict() {
+        let mut output = Vec::new();
+        let config = EngineConfig::default();
+        assert!(process(INPUT.as_bytes(), &mut output, &config).is_err());
+    }
+
+    #[test]
+    fn test_process_lenient() {
+        let mut output = Vec::new();
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let incidents = process(INPUT.as_bytes(), &mut output, &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].kind, IncidentKind::Failed);
+        assert_eq!(incidents[0].tx, Some(2));
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n1,0.5000,0.0000,0.5000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_process_sorted() {
+        let input = "type,client,tx,amount\n\
+            deposit,3,1,1.0\n\
+            deposit,1,2,1.0\n\
+            deposit,2,3,1.0\n";
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &EngineConfig::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let clients: Vec<_> = output.lines().skip(1).map(|l| &l[..1]).collect();
+        assert_eq!(clients, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_process_json() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.5\n\
+            deposit,2,2,1.0\n\
+            withdrawal,2,3,1.0\n";
+        let mut output = Vec::new();
+        let config = EngineConfig {
+            format: OutputFormat::JsonLines,
+            ..Default::default()
+        };
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "{\"client\":1,\"available\":2.5,\"held\":0.0,\"total\":2.5,\"locked\":false,\"open_disputes\":0}\n\
+            {\"client\":2,\"available\":0.0,\"held\":0.0,\"total\":0.0,\"locked\":false,\"open_disputes\":0}\n"
+        );
+    }
+
+    #[test]
+    fn test_process_parallel() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.0\n\
+            deposit,3,3,4.0\n\
+            withdrawal,1,4,1.5\n\
+            dispute,2,2,\n\
+            deposit,4,5,1.0\n\
+            dispute,3,3,\n\
+            resolve,3,3,\n";
+        let mut sequential = Vec::new();
+        process(input.as_bytes(), &mut sequential, &EngineConfig::default()).unwrap();
+        let mut parallel = Vec::new();
+        let config = EngineConfig {
+            threads: 3,
+            ..Default::default()
+        };
+        process(input.as_bytes(), &mut parallel, &config).unwrap();
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn test_process_precision() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.12345678\n\
+            withdrawal,1,2,0.00000001\n";
+        let mut output = Vec::new();
+        let config = EngineConfig {
+            precision: 8,
+            ..Default::default()
+        };
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n1,2.12345677,0.00000000,2.12345677,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_incident_report() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            dispute,1,5,\n\
+            resolve,1,1,\n";
+        let incidents =
+            process(input.as_bytes(), std::io::sink(), &EngineConfig::default()).unwrap();
+        let mut report = Vec::new();
+        write_incidents(&incidents, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert_eq!(
+            report,
+            "{\"kind\":\"ignored\",\"tx\":5,\"client\":1,\
+            \"reason\":\"unknown transaction\"}\n\
+            {\"kind\":\"ignored\",\"tx\":1,\"client\":1,\
+            \"reason\":\"transaction is not disputed\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let first = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.0\n\
+            dispute,1,1,\n";
+        let second = "type,client,tx,amount\n\
+            resolve,1,1,\n\
+            dispute,2,2,\n\
+            withdrawal,1,3,0.5\n";
+        let config = EngineConfig::default();
+
+        let mut state = EngineState::default();
+        ingest(&mut state, first.as_bytes(), &config).unwrap();
+        let mut snapshot = Vec::new();
+        state.write_snapshot(&mut snapshot).unwrap();
+
+        let mut state = EngineState::read_snapshot(snapshot.as_slice()).unwrap();
+        ingest(&mut state, second.as_bytes(), &config).unwrap();
+        let mut output = Vec::new();
+        write_accounts(state, &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,1.5000,0.0000,1.5000,false,0\n\
+            2,0.0000,3.0000,3.0000,false,1\n"
+        );
+    }
+
+    #[test]
+    fn test_multiple_inputs() {
+        let first = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.0\n";
+        let second = "type,client,tx,amount\n\
+            dispute,1,1,\n\
+            withdrawal,2,3,1.0\n";
+        let config = EngineConfig::default();
+
+        let mut state = EngineState::default();
+        ingest(&mut state, first.as_bytes(), &config).unwrap();
+        ingest(&mut state, second.as_bytes(), &config).unwrap();
+        let mut output = Vec::new();
+        write_accounts(state, &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,0.0000,2.0000,2.0000,false,1\n\
+            2,2.0000,0.0000,2.0000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let first = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.0\n";
+        // Reuses the id of a deposit from the first input and disputes the other one.
+        let second = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            dispute,2,2,\n";
+        let config = EngineConfig::default();
+
+        let mut state = EngineState::default();
+        ingest(&mut state, first.as_bytes(), &config).unwrap();
+        state.clear();
+        assert!(state.accounts.iter().next().is_none());
+        assert!(state.trxs.is_empty());
+        let incidents = ingest(&mut state, second.as_bytes(), &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].reason, "unknown transaction");
+        let mut output = Vec::new();
+        write_accounts(state, &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,5.0000,0.0000,5.0000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_streaming_output() {
+        /// Records the size of every write, to see how much output is held back.
+        #[derive(Default)]
+        struct Writes(Vec<usize>);
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for format in [OutputFormat::Csv, OutputFormat::JsonLines] {
+            let mut state = EngineState::default();
+            for client in 0..10_000 {
+                let account = state.accounts.account_for_id(client, None);
+                (account.available, account.total) = (Amount(1), Amount(1));
+            }
+            let config = EngineConfig {
+                sort: false,
+                format,
+                ..Default::default()
+            };
+            let mut writes = Writes::default();
+            write_accounts(state, &mut writes, &config).unwrap();
+            // The output of hundreds of kilobytes is passed on in chunks of the buffer size.
+            assert!(writes.0.len() > 10);
+            assert!(writes.0.iter().all(|&len| len <= 8 * 1024));
+        }
+    }
+
+    #[test]
+    fn test_shards() {
+        let state = || {
+            let mut state = EngineState::default();
+            for client in 0..10 {
+                let account = state.accounts.account_for_id(client, None);
+                (account.available, account.total) = (Amount(1), Amount(1));
+            }
+            state
+        };
+        let config = EngineConfig::default();
+        let mut shards = vec![Vec::new(); 4];
+        write_shards(state(), shards.iter_mut().collect(), &config).unwrap();
+        let mut rows = Vec::new();
+        for (index, shard) in shards.into_iter().enumerate() {
+            let shard = String::from_utf8(shard).unwrap();
+            let mut lines = shard.lines();
+            assert_eq!(
+                lines.next(),
+                Some("client,available,held,total,locked,open_disputes")
+            );
+            for row in lines {
+                let client: usize = row.split(',').next().unwrap().parse().unwrap();
+                assert_eq!(client % 4, index);
+                rows.push(row.to_string());
+            }
+        }
+        // Every account lands in exactly one shard, so together the shards hold the full output.
+        rows.sort_by_key(|row| row.split(',').next().unwrap().parse::<u32>().unwrap());
+        let mut output = Vec::new();
+        write_accounts(state(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(rows, output.lines().skip(1).collect::<Vec<_>>());
+
+        let shards: Vec<Vec<u8>> = Vec::new();
+        assert!(write_shards(state(), shards, &config).is_err());
+    }
+
+    #[test]
+    fn test_summary_row() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.5\n\
+            deposit,3,3,1.25\n\
+            withdrawal,3,4,0.25\n\
+            dispute,1,1,\n\
+            dispute,2,2,\n\
+            chargeback,2,2,\n";
+        let config = EngineConfig::builder().summary_row(true).build().unwrap();
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,0.0000,2.0000,2.0000,false,1\n\
+            2,0.0000,0.0000,0.0000,true,0\n\
+            3,1.0000,0.0000,1.0000,false,0\n\
+            total,1.0000,2.0000,3.0000,1,1\n"
+        );
+
+        let config = EngineConfig {
+            summary_row: true,
+            format: OutputFormat::JsonLines,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_updates() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.0\n\
+            withdrawal,1,3,5.0\n\
+            dispute,2,2,\n";
+        let config = EngineConfig {
+            lenient: true,
+            ..EngineConfig::default()
+        };
+        let mut updates = Vec::new();
+        let mut state = EngineState::default();
+        ingest_with_updates(&mut state, input.as_bytes(), &config, |account| {
+            updates.push((account.client, account.available, account.held))
+        })
+        .unwrap();
+        // The ignored withdrawal does not produce an update.
+        assert_eq!(
+            updates,
+            [
+                (1, Amount(20_000), Amount(0)),
+                (2, Amount(30_000), Amount(0)),
+                (2, Amount(0), Amount(30_000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hook() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            withdrawal,1,2,5.0\n\
+            deposit,2,3,3.0\n\
+            dispute,1,1,\n";
+        // The hook runs on this thread in input order, also when more threads are configured.
+        let config = EngineConfig {
+            lenient: true,
+            threads: 2,
+            ..EngineConfig::default()
+        };
+        let mut audit = Vec::new();
+        let mut state = EngineState::default();
+        ingest_with_hook(&mut state, input.as_bytes(), &config, |trx, account| {
+            audit.push((*trx, account.held))
+        })
+        .unwrap();
+        let mutation = |id, kind, client, amount| Mutation {
+            id,
+            kind,
+            client,
+            amount,
+            currency: None,
+            to_client: None,
+        };
+        // The ignored withdrawal does not reach the hook.
+        assert_eq!(
+            audit,
+            [
+                (
+                    mutation(1, TransactionType::Deposit, 1, Some(Amount(20_000))),
+                    Amount(0)
+                ),
+                (
+                    mutation(3, TransactionType::Deposit, 2, Some(Amount(30_000))),
+                    Amount(0)
+                ),
+                (
+                    mutation(1, TransactionType::Dispute, 1, None),
+                    Amount(20_000)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_input_scale() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,150\n\
+            withdrawal,1,2,25.5\n";
+        let config = EngineConfig {
+            input_scale: 2,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,1.2450,0.0000,1.2450,false,0\n"
+        );
+
+        let config = EngineConfig {
+            input_scale: 5,
+            ..Default::default()
+        };
+        assert!(process(input.as_bytes(), Vec::new(), &config).is_err());
+        assert!(EngineConfig::builder().input_scale(5).build().is_err());
+    }
+
+    #[test]
+    fn test_no_empty_accounts() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            dispute,2,5,\n\
+            withdrawal,3,6,1.0\n";
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &config).unwrap();
+        assert_eq!(incidents.len(), 2);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,2.0000,0.0000,2.0000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,1,2,1.5\n\
+            withdrawal,1,3,1.0\n\
+            deposit,2,4,3.0\n\
+            withdrawal,2,5,5.0\n";
+        let config = EngineConfig {
+            lenient: true,
+            stats: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes,deposits,withdrawals,deposited\n\
+            1,2.5000,0.0000,2.5000,false,0,2,1,3.5000\n\
+            2,3.0000,0.0000,3.0000,false,0,1,0,3.0000\n"
+        );
+    }
+
+    #[test]
+    fn test_risk_flags() {
+        // Client 1 disputes and resolves a deposit, which still flags it.
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            dispute,1,1,\n\
+            resolve,1,1,\n\
+            deposit,2,2,3.0\n";
+        let config = EngineConfig {
+            risk_flags: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes,ever_disputed,under_review\n\
+            1,2.0000,0.0000,2.0000,false,0,true,false\n\
+            2,3.0000,0.0000,3.0000,false,0,false,false\n"
+        );
+
+        let config = EngineConfig {
+            risk_flags: true,
+            layout: Some(present::CsvLayout::ledger()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_minor_units() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.5\n\
+            withdrawal,1,2,0.0001\n\
+            deposit,2,3,2.25\n\
+            dispute,2,3,\n";
+        let config = EngineConfig {
+            minor_units: true,
+            summary_row: true,
+            ..Default::default()
+        };
+        let mut state = EngineState::default();
+        ingest(&mut state, input.as_bytes(), &config).unwrap();
+        let accounts = [1, 2].map(|client| *state.accounts.get(client, None).unwrap());
+        let mut output = Vec::new();
+        write_accounts(state, &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,14999,0,14999,false,0\n\
+            2,0,22500,22500,false,1\n\
+            total,14999,22500,37499,0,1\n"
+        );
+        // Every balance is written as the integer that the engine maintains.
+        for (line, acc) in output.lines().skip(1).zip(accounts) {
+            let balances = [acc.available.0, acc.held.0, acc.total.0].map(|b| b.to_string());
+            assert_eq!(
+                line.split(',').skip(1).take(3).collect::<Vec<_>>(),
+                balances
+            );
+        }
+
+        let config = EngineConfig {
+            minor_units: true,
+            format: OutputFormat::JsonLines,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_last_row() {
+        // The dispute of client 1 is ignored, so row 3 is the last one that changed it.
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,3.0\n\
+            withdrawal,1,3,1.0\n\
+            dispute,1,9,\n\
+            deposit,2,4,1.0\n";
+        for threads in [1, 2] {
+            let config = EngineConfig {
+                lenient: true,
+                last_row: true,
+                threads,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            process(input.as_bytes(), &mut output, &config).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert_eq!(
+                output,
+                "client,available,held,total,locked,open_disputes,last_row\n\
+                1,1.0000,0.0000,1.0000,false,0,3\n\
+                2,4.0000,0.0000,4.0000,false,0,5\n"
+            );
+        }
+
+        let config = EngineConfig {
+            last_row: true,
+            layout: Some(present::CsvLayout::ledger()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_locked_format() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,2,2,3.0\n";
+        let config = EngineConfig {
+            stats: true,
+            locked: present::LockedFormat::Word,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes,deposits,withdrawals,deposited\n\
+            1,0.0000,0.0000,0.0000,frozen,0,1,0,2.0000\n\
+            2,3.0000,0.0000,3.0000,active,0,1,0,3.0000\n"
+        );
+
+        let config = EngineConfig {
+            layout: Some(present::CsvLayout::ledger()),
+            locked: present::LockedFormat::Digit,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "Client,Total,Available,Held,Locked\n\
+            1,0.0000,0.0000,0.0000,1\n\
+            2,3.0000,3.0000,0.0000,0\n"
+        );
+    }
+
+    #[test]
+    fn test_ordering_per_client() {
+        // The dispute of client 1 comes before the deposit of client 2, and the resolve of client 2
+        // comes before the resolve of client 1, but within each client the order is sound.
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            dispute,1,1,\n\
+            deposit,2,2,3.0\n\
+            dispute,2,2,\n\
+            resolve,2,2,\n\
+            resolve,1,1,\n\
+            withdrawal,1,3,0.5\n";
+        for threads in [1, 2] {
+            let config = EngineConfig {
+                threads,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let incidents = process(input.as_bytes(), &mut output, &config).unwrap();
+            assert!(incidents.is_empty());
+            let output = String::from_utf8(output).unwrap();
+            assert_eq!(
+                output,
+                "client,available,held,total,locked,open_disputes\n\
+                1,1.5000,0.0000,1.5000,false,0\n\
+                2,3.0000,0.0000,3.0000,false,0\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = EngineConfig::builder()
+            .lenient(true)
+            .sort(false)
+            .precision(2)
+            .overdraft_limit(Amount(100))
+            .build()
+            .unwrap();
+        assert!(config.lenient);
+        assert!(!config.sort);
+        assert_eq!(config.precision, 2);
+        assert_eq!(config.policy.overdraft_limit, Amount(100));
+        assert_eq!(config.threads, 1);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let input = "type,tx,ammount\n";
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let err = process(input.as_bytes(), Vec::new(), &config).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("missing the columns [\"client\", \"amount\"]"));
+        assert!(err.to_string().contains("unexpected columns [\"ammount\"]"));
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.0\n\
+            deposit,1,2,1.0\n\
+            dispute,1,2,\n\
+            deposit,1,3,1.0\n\
+            deposit,1,4,1.0\n\
+            dispute,1,1,\n\
+            dispute,1,4,\n";
+        let config = EngineConfig {
+            history_limit: Some(2),
+            ..Default::default()
+        };
+        let mut state = EngineState::default();
+        let incidents = ingest(&mut state, input.as_bytes(), &config).unwrap();
+        // The first deposit was evicted, so its dispute is ignored, but the second deposit was
+        // disputed, so it is retained and the third deposit is evicted in its stead.
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].tx, Some(1));
+        assert_eq!(state.trxs.len(), 2);
+        assert!(state.trxs.get(1, 3).is_none());
+        let account = state.accounts.get(1, None).unwrap();
+        assert_eq!(account.held, Amount(20_000));
+        assert_eq!(account.available, Amount(20_000));
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            withdrawal,1,2,3.0\n\
+            withdrawal,1,3,1.5\n";
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &EngineConfig::default()).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].kind, IncidentKind::Ignored);
+        assert_eq!(incidents[0].tx, Some(2));
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n1,0.5000,0.0000,0.5000,false,0\n"
+        );
+
+        let config = EngineConfig::builder()
+            .strict_withdrawals(true)
+            .build()
+            .unwrap();
+        assert!(process(input.as_bytes(), Vec::new(), &config).is_err());
+    }
+
+    #[test]
+    fn test_locked_account() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,1,2,1.0\n\
+            deposit,2,3,1.0\n";
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &EngineConfig::default()).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].kind, IncidentKind::Rejected);
+        assert_eq!(incidents[0].tx, Some(2));
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,0.0000,0.0000,0.0000,true,0\n\
+            2,1.0000,0.0000,1.0000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_chronological() {
+        let input = "type,client,tx,amount,timestamp\n\
+            dispute,1,1,,20\n\
+            deposit,1,1,2.0,10\n\
+            deposit,2,2,1.0,\n";
+        let config = EngineConfig {
+            chronological: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &config).unwrap();
+        assert!(incidents.is_empty());
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,0.0000,2.0000,2.0000,false,1\n\
+            2,1.0000,0.0000,1.0000,false,0\n"
+        );
+
+        // Without sorting, the dispute references a transaction that does not exist yet.
+        let incidents = process(input.as_bytes(), Vec::new(), &EngineConfig::default()).unwrap();
+        assert_eq!(incidents.len(), 1);
+    }
+
+    /// A writer whose reader went away after accepting `remaining` bytes.
+    struct ClosedPipe {
+        remaining: usize,
+    }
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let written = buf.len().min(self.remaining);
+            self.remaining -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_broken_pipe() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,1.0\n";
+        for format in [OutputFormat::Csv, OutputFormat::JsonLines] {
+            let config = EngineConfig {
+                format,
+                ..Default::default()
+            };
+            let mut state = EngineState::default();
+            ingest(&mut state, input.as_bytes(), &config).unwrap();
+            let output = ClosedPipe { remaining: 10 };
+            let err = write_accounts(state, output, &config).unwrap_err();
+            assert!(is_broken_pipe(&err));
+        }
+        assert!(!is_broken_pipe(&eyre!("Some other failure")));
+    }
+
+    #[test]
+    fn test_layout() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,1,2,1.0\n\
+            dispute,1,2,\n";
+        let config = EngineConfig::builder()
+            .layout(present::CsvLayout::ledger())
+            .build()
+            .unwrap();
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "Client,Total,Available,Held,Locked\n1,3.0000,2.0000,1.0000,false\n"
+        );
+
+        let config = EngineConfig {
+            stats: true,
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_check_clients() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,1.0\n\
+            dispute,2,1,\n\
+            dispute,1,1,\n";
+        let config = EngineConfig {
+            lenient: true,
+            check_clients: true,
+            threads: 4,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(
+            incidents[0].reason,
+            "Row 3: Error on trx 1: Client 2 can't reference a transaction of client 1"
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,0.0000,2.0000,2.0000,false,1\n\
+            2,1.0000,0.0000,1.0000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_delimiter() {
+        let input = "type; client; tx; amount\n\
+            deposit; 1; 1; 2.5\n\
+            withdrawal; 1; 2; 1.0\n";
+        let config = EngineConfig::builder().delimiter(b';').build().unwrap();
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let mut expected = Vec::new();
+        let comma_input = input.replace(';', ",");
+        process(
+            comma_input.as_bytes(),
+            &mut expected,
+            &EngineConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.5\n\
+            deposit,2,2,1.0\n\
+            withdrawal,1,3,1.0\n";
+        // A buffer that is smaller than a single row still reads all rows.
+        let config = EngineConfig::builder().buffer_size(4).build().unwrap();
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let mut expected = Vec::new();
+        process(input.as_bytes(), &mut expected, &EngineConfig::default()).unwrap();
+        assert_eq!(output, expected);
+
+        assert!(EngineConfig::builder().buffer_size(0).build().is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,1,2,\n\
+            withdrawal,1,3,1.5\n\
+            withdrawal,1,4,1.5\n\
+            dispute,1,3,\n\
+            dispute,1,3,\n\
+            dispute,1,9,\n\
+            resolve,1,3,\n\
+            deposit,2,5,1.0\n\
+            dispute,2,5,\n\
+            chargeback,2,5,\n\
+            deposit,2,6,1.0\n";
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        for threads in [1, 2] {
+            let config = EngineConfig {
+                threads,
+                ..config.clone()
+            };
+            let mut state = EngineState::default();
+            let (incidents, summary) =
+                ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+            assert_eq!(incidents.len(), 5);
+            let expected = RunSummary {
+                rows: 12,
+                deposits: 2,
+                withdrawals: 1,
+                disputes: 2,
+                resolves: 1,
+                chargebacks: 1,
+                ignored: 3,
+                unknown_disputes: 1,
+                repeated_disputes: 1,
+                rejected: 1,
+                failed: 1,
+                net_flow: 5_000,
+                ..Default::default()
+            };
+            assert_eq!(summary, expected);
+        }
+    }
+
+    #[test]
+    fn test_undisputed() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            resolve,1,1,\n\
+            chargeback,1,1,\n\
+            chargeback,1,2,\n";
+        let config = EngineConfig::default();
+        let mut state = EngineState::default();
+        let (incidents, summary) =
+            ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+        assert_eq!(incidents.len(), 3);
+        assert_eq!(summary.ignored, 3);
+        assert_eq!(summary.undisputed_resolves, 1);
+        assert_eq!(summary.undisputed_chargebacks, 2);
+
+        // In strict mode the first resolve without a dispute aborts the run.
+        let config = EngineConfig::builder()
+            .strict_disputes(true)
+            .build()
+            .unwrap();
+        let mut state = EngineState::default();
+        let error = ingest(&mut state, input.as_bytes(), &config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error on trx 1: resolves must reference a disputed transaction (transaction is not \
+            disputed)"
+        );
+    }
+
+    #[test]
+    fn test_stop_on_lock() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,1.0\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,2,3,1.0\n";
+        let config = EngineConfig {
+            stop_on_lock: true,
+            threads: 2,
+            ..Default::default()
+        };
+        let mut state = EngineState::default();
+        let (_, summary) = ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+        assert_eq!(summary.stopped_at_row, Some(4));
+        assert_eq!(summary.rows, 4);
+        let mut output = Vec::new();
+        write_accounts(state, &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,0.0000,0.0000,0.0000,true,0\n\
+            2,1.0000,0.0000,1.0000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_clients() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,1.0\n\
+            deposit,3,3,4.0\n\
+            withdrawal,3,4,1.5\n";
+        let config = EngineConfig::builder()
+            .clients(vec![3, 1, 7])
+            .build()
+            .unwrap();
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &config).unwrap();
+        assert!(incidents.is_empty());
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,2.0000,0.0000,2.0000,false,0\n\
+            3,2.5000,0.0000,2.5000,false,0\n"
+        );
+    }
+
+    #[test]
+    fn test_client_filters() {
+        // Without filtering, the dispute of client 2 would fail, since the deposit is unknown.
+        let input = "type,client,tx,amount,to_client\n\
+            deposit,1,1,2.0,\n\
+            deposit,2,2,1.0,\n\
+            deposit,3,3,4.0,\n\
+            dispute,2,2,,\n\
+            transfer,3,4,1.0,2\n";
+        for threads in [1, 3] {
+            let config = EngineConfig::builder()
+                .threads(threads)
+                .deny_clients(vec![2])
+                .build()
+                .unwrap();
+            let mut state = EngineState::default();
+            let (incidents, summary) =
+                ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+            assert!(incidents.iter().all(|incident| incident.tx != Some(4)));
+            assert_eq!(summary.filtered, 3);
+            assert_eq!(state.accounts.get(2, None), None);
+            assert_eq!(state.accounts.get(3, None).unwrap().total, Amount(40_000));
+
+            let config = EngineConfig::builder()
+                .threads(threads)
+                .allow_clients(vec![1, 3])
+                .build()
+                .unwrap();
+            let mut state = EngineState::default();
+            let (_, summary) = ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+            assert_eq!(summary.filtered, 3);
+            assert_eq!(summary.rows, 5);
+            assert_eq!(state.accounts.get(2, None), None);
+            assert_eq!(state.accounts.get(1, None).unwrap().total, Amount(20_000));
+        }
+
+        let config = EngineConfig::builder()
+            .allow_clients(vec![1])
+            .deny_clients(vec![2])
+            .build();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_max_rows() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,1.0\n\
+            deposit,3,3,4.0\n";
+        for (threads, chronological) in [(1, false), (2, false), (1, true)] {
+            let config = EngineConfig {
+                threads,
+                chronological,
+                max_rows: Some(2),
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let err = process(input.as_bytes(), &mut output, &config).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "The input has more than 2 rows, which is the maximum that is allowed"
+            );
+            let config = EngineConfig {
+                max_rows: Some(3),
+                ..config
+            };
+            assert!(process(input.as_bytes(), &mut output, &config).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,2,2,\n\
+            withdrawal,1,3,x\n\
+            deposit,3,4,4.0\n\
+            dispute,3,4,1.0\n\
+            deposit,4,5,1.0\n";
+        for threads in [1, 2] {
+            let config = EngineConfig {
+                threads,
+                lenient: true,
+                max_errors: Some(2),
+                ..Default::default()
+            };
+            let mut state = EngineState::default();
+            let err = ingest(&mut state, input.as_bytes(), &config).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("Stopping after 2 rows that could not be processed"));
+            let config = EngineConfig {
+                max_errors: Some(4),
+                ..config
+            };
+            let mut state = EngineState::default();
+            let incidents = ingest(&mut state, input.as_bytes(), &config).unwrap();
+            assert_eq!(incidents.len(), 3);
+        }
+        // On a single thread, the rows after the last error that is allowed are not processed.
+        let config = EngineConfig {
+            lenient: true,
+            max_errors: Some(2),
+            ..Default::default()
+        };
+        let mut state = EngineState::default();
+        assert!(ingest(&mut state, input.as_bytes(), &config).is_err());
+        assert!(state.accounts.get(1, None).is_some());
+        assert_eq!(state.accounts.get(3, None), None);
+    }
+
+    #[test]
+    fn test_currencies() {
+        let input = "type,client,tx,amount,currency\n\
+            deposit,1,1,2.0,EUR\n\
+            deposit,1,2,3.0,usd\n\
+            deposit,2,3,1.0,\n\
+            dispute,1,1,,\n\
+            dispute,1,2,,EUR\n\
+            withdrawal,1,4,2.5,USD\n";
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let incidents = process(input.as_bytes(), &mut output, &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(
+            incidents[0].reason,
+            "Error on trx 2: The transaction is in USD, not in EUR"
+        );
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,currency,available,held,total,locked,open_disputes\n\
+            1,EUR,0.0000,2.0000,2.0000,false,1\n\
+            1,USD,0.5000,0.0000,0.5000,false,0\n\
+            2,,1.0000,0.0000,1.0000,false,0\n"
+        );
+
+        let mut state = EngineState::default();
+        ingest(&mut state, input.as_bytes(), &config).unwrap();
+        let mut snapshot = Vec::new();
+        state.write_snapshot(&mut snapshot).unwrap();
+        let state = EngineState::read_snapshot(snapshot.as_slice()).unwrap();
+        let usd = "USD".parse().ok();
+        assert_eq!(state.accounts.get(1, usd).unwrap().total, Amount(5_000));
+    }
+
+    #[test]
+    fn test_per_client_ids() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,7,2.0\n\
+            deposit,2,7,3.0\n\
+            dispute,2,7,\n";
+        // By default the second deposit reuses the id of the first one.
+        let mut state = EngineState::default();
+        assert!(ingest(&mut state, input.as_bytes(), &EngineConfig::default()).is_err());
+
+        for threads in [1, 2] {
+            let config = EngineConfig::builder()
+                .per_client_ids(true)
+                .threads(threads)
+                .build()
+                .unwrap();
+            let mut state = EngineState::default();
+            let incidents = ingest(&mut state, input.as_bytes(), &config).unwrap();
+            assert!(incidents.is_empty());
+            assert_eq!(state.trxs.get(1, 7).unwrap().amount, Amount(20_000));
+            assert_eq!(
+                state.trxs.get(2, 7).unwrap().status,
+                TransactionStatus::Disputed
+            );
+
+            // The transactions stay apart after resuming from a snapshot.
+            let mut snapshot = Vec::new();
+            state.write_snapshot(&mut snapshot).unwrap();
+            let mut state = EngineState::read_snapshot(snapshot.as_slice()).unwrap();
+            let chargeback = "type,client,tx,amount\n\
+                chargeback,2,7,\n\
+                dispute,1,7,\n";
+            ingest(&mut state, chargeback.as_bytes(), &config).unwrap();
+            let client1 = state.accounts.get(1, None).unwrap();
+            assert_eq!((client1.held, client1.locked), (Amount(20_000), false));
+            let client2 = state.accounts.get(2, None).unwrap();
+            assert_eq!((client2.total, client2.locked), (Amount(0), true));
+
+            // Two clients share an id, so the ids can not become unique again.
+            assert!(state.trxs.set_per_client(false).is_err());
+            assert!(state.trxs.is_per_client());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_by_client() {
+        // Snapshots from before accounts had a currency keep the accounts in a map by client.
+        let snapshot = r#"{"accounts":{"accounts":{"1":{"client":1,"available":5,"held":0,
+            "total":5,"locked":false}}},"trxs":{"trxs":{}}}"#;
+        let state = EngineState::read_snapshot(snapshot.as_bytes()).unwrap();
+        assert_eq!(state.accounts.get(1, None).unwrap().total, Amount(5));
+    }
+
+    #[test]
+    fn test_explain() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            deposit,1,2,1.0\n\
+            dispute,1,2,\n\
+            dispute,1,2,\n\
+            withdrawal,1,3,0.5\n\
+            chargeback,1,2,\n";
+        let config = EngineConfig::builder()
+            .explain(Some(2))
+            .threads(2)
+            .precision(2)
+            .build()
+            .unwrap();
+        let mut state = EngineState::default();
+        let (_, _, transitions) =
+            ingest_with_transitions(&mut state, input.as_bytes(), &config).unwrap();
+        let described: Vec<_> = (transitions.iter())
+            .map(|transition| transition.describe(2))
+            .collect();
+        assert_eq!(
+            described,
+            [
+                "Row 2: Deposit of trx 2 for client 1 was applied, the transaction is ok: \
+                available 2.00, held 0.00, total 2.00 -> available 3.00, held 0.00, total 3.00",
+                "Row 3: Dispute of trx 2 for client 1 was applied, the transaction is disputed: \
+                available 3.00, held 0.00, total 3.00 -> available 2.00, held 1.00, total 3.00",
+                "Row 4: Dispute of trx 2 for client 1 was not applied (transaction is already \
+                disputed), the transaction is disputed: available 2.00, held 1.00, total 3.00 -> \
+                available 2.00, held 1.00, total 3.00",
+                "Row 6: Chargeback of trx 2 for client 1 was applied, the transaction is refunded: \
+                available 1.50, held 1.00, total 2.50 -> available 1.50, held 0.00, total 1.50, \
+                locked",
+            ]
+        );
+        assert_eq!(transitions[0].before.unwrap().total, Amount(200));
+    }
+
+    #[test]
+    fn test_rounding() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.23455\n\
+            deposit,2,2,1.23445\n";
+        let config = EngineConfig::builder()
+            .rounding(Rounding::HalfEven)
+            .build()
+            .unwrap();
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,open_disputes\n\
+            1,1.2346,0.0000,1.2346,false,0\n\
+            2,1.2344,0.0000,1.2344,false,0\n"
+        );
+        let err = process(input.as_bytes(), Vec::new(), &EngineConfig::default()).unwrap_err();
+        assert!(err.to_string().contains("1.23455 has more than 4 decimals"));
+    }
+
+    #[test]
+    fn test_reconciliation() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            withdrawal,1,2,0.5\n\
+            dispute,1,2,\n\
+            resolve,1,2,\n\
+            dispute,1,2,\n\
+            chargeback,1,2,0.2\n\
+            deposit,2,3,3.0\n\
+            dispute,2,3,\n\
+            chargeback,2,3,1.0\n\
+            deposit,3,4,1.0\n\
+            withdrawal,3,5,0.5\n\
+            dispute,3,5,\n\
+            resolve,3,5,\n\
+            chargeback,3,5,\n\
+            withdrawal,4,6,1.0\n";
+        let config = EngineConfig::builder()
+            .partial_chargebacks(true)
+            .lenient(true)
+            .build()
+            .unwrap();
+        for threads in [1, 2] {
+            let config = EngineConfig {
+                threads,
+                ..config.clone()
+            };
+            let mut state = EngineState::default();
+            ingest(
+                &mut state,
+                "type,client,tx,amount\ndeposit,5,7,4.0\n".as_bytes(),
+                &config,
+            )
+            .unwrap();
+            let opening = state.accounts.sum_of_totals();
+            let (_, summary) = ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+            let reconciliation = Reconciliation {
+                opening,
+                net_flow: summary.net_flow,
+                closing: state.accounts.sum_of_totals(),
+            };
+            assert!(reconciliation.is_balanced(), "{reconciliation}");
+            assert_eq!(reconciliation.closing, 40_000 + 17_000 + 20_000 + 10_000);
+
+            // A bug that credits an account out of thin air shows up as a mismatch.
+            state.accounts.account_for_id(2, None).total.0 += 1;
+            let reconciliation = Reconciliation {
+                closing: state.accounts.sum_of_totals(),
+                ..reconciliation
+            };
+            assert!(!reconciliation.is_balanced());
+            assert_eq!(
+                reconciliation.to_string(),
+                "The totals add up to 87001 units, which is NOT equal to the opening balance of \
+                40000 units plus the net flow of 47000 units"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reconciliation_disputable() {
+        // Fees and negative adjustments are disputed like withdrawals, and interest like deposits.
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            fee,1,2,1.0\n\
+            dispute,1,2,\n\
+            chargeback,1,2,\n\
+            deposit,2,3,5.0\n\
+            adjustment,2,4,-1.0\n\
+            dispute,2,4,\n\
+            resolve,2,4,\n\
+            interest,2,5,0.5\n\
+            dispute,2,5,\n\
+            chargeback,2,5,\n";
+        let config = EngineConfig::builder()
+            .disputable(Disputable::All)
+            .build()
+            .unwrap();
+        let mut state = EngineState::default();
+        let (_, summary) = ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+        assert_eq!(summary.net_flow, 50_000 + 40_000);
+        assert_eq!(state.accounts.sum_of_totals(), summary.net_flow);
+    }
+
+    #[test]
+    fn test_transfers() {
+        let input = "type,client,tx,amount,to_client\n\
+            deposit,1,1,5.0,\n\
+            transfer,1,2,2.0,3\n\
+            transfer,1,3,1.0,2\n\
+            transfer,3,4,9.0,1\n";
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let mut state = EngineState::default();
+        let (incidents, summary) =
+            ingest_with_summary(&mut state, input.as_bytes(), &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].tx, Some(4));
+        assert_eq!(summary.transfers, 2);
+        assert_eq!(summary.net_flow, 50_000);
+        let total = |state: &EngineState, client| state.accounts.get(client, None).unwrap().total;
+        assert_eq!(total(&state, 1), Amount(20_000));
+        assert_eq!(total(&state, 2), Amount(10_000));
+        assert_eq!(total(&state, 3), Amount(20_000));
+
+        // Clients 1 and 2 would be on different threads, so an input with transfers is processed on
+        // a single thread, with the same outcome.
+        let config = EngineConfig {
+            threads: 2,
+            ..config
+        };
+        let mut other = EngineState::default();
+        let (incidents, summary) =
+            ingest_with_summary(&mut other, input.as_bytes(), &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(summary.transfers, 2);
+        for client in 1..=3 {
+            assert_eq!(total(&other, client), total(&state, client));
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use crate::{
+    account::IgnoreReason,
+    amount::{Amount, Currency, DEFAULT_PRECISION},
+    parse::COLUMNS,
+    transaction::TransactionType,
+};
+use core::fmt;
+
+/// The reasons why a transaction can not be processed. Library consumers can match on these to
+/// react to each category differently, for example to treat insufficient funds differently from a
+/// malformed row, whereas the binary simply converts them into an `eyre::Report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// A deposit, withdrawal, interest payment, fee, adjustment or transfer does not have an
+    /// amount.
+    MissingAmount { tx: u32, kind: TransactionType },
+    /// A dispute, resolve, chargeback or unlock has an amount.
+    UnexpectedAmount { tx: u32, kind: TransactionType },
+    /// The amount can not be parsed, or is not positive.
+    InvalidAmount { tx: u32, reason: String },
+    /// The currency can not be parsed.
+    InvalidCurrency { tx: u32, reason: String },
+    /// The id of a deposit or withdrawal is already in use by a transaction with other contents.
+    DuplicateId { tx: u32 },
+    /// The account does not hold enough funds to take the amount out.
+    InsufficientFunds {
+        tx: u32,
+        client: u32,
+        amount: Amount,
+    },
+    /// Adding the amount would overflow one of the balances of the account.
+    Overflow {
+        tx: u32,
+        client: u32,
+        amount: Amount,
+    },
+    /// The account is locked, so it only accepts unlocks.
+    AccountLocked { tx: u32, client: u32 },
+    /// A dispute, resolve or chargeback references a transaction of another client.
+    ForeignTransaction { tx: u32, client: u32, owner: u32 },
+    /// A dispute, resolve or chargeback is for another currency than the transaction that it
+    /// references.
+    ForeignCurrency {
+        tx: u32,
+        currency: Option<Currency>,
+        expected: Option<Currency>,
+    },
+    /// A partial chargeback is larger than the amount of the transaction that it charges back.
+    ExcessiveChargeback {
+        tx: u32,
+        amount: Amount,
+        disputed: Amount,
+    },
+    /// A resolve or chargeback references a transaction that is not under dispute, for the reason
+    /// that it would otherwise be ignored with.
+    Undisputed {
+        tx: u32,
+        kind: TransactionType,
+        reason: IgnoreReason,
+    },
+    /// A chargeback takes out more than the account has left, because the client spent the funds.
+    UncoveredChargeback {
+        tx: u32,
+        client: u32,
+        amount: Amount,
+    },
+    /// A dispute would hold more on the account than the limit of the policy.
+    HeldLimit {
+        tx: u32,
+        client: u32,
+        held: Amount,
+        limit: Amount,
+    },
+    /// A dispute, resolve or chargeback references a transaction that can not be disputed.
+    NotDisputable { tx: u32, kind: TransactionType },
+    /// A transfer does not say which client it is for.
+    MissingRecipient { tx: u32 },
+    /// A row that is not a transfer says which client it is for.
+    UnexpectedRecipient { tx: u32, kind: TransactionType },
+    /// A transfer is for the client that it is from.
+    SelfTransfer { tx: u32, client: u32 },
+    /// A transfer is applied to a single account, while it needs the accounts of both clients.
+    TransferWithoutAccounts { tx: u32 },
+    /// The balances of the account no longer add up, which means that there is a bug in the engine.
+    InconsistentAccount {
+        client: u32,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+}
+
+impl TransactionError {
+    /// Describes the error, presenting its amounts with `precision` decimals. The engine reports
+    /// its errors like this, with the precision that it is configured with.
+    pub fn describe(&self, precision: u32) -> String {
+        use TransactionError::*;
+        let decimal = |amount: &Amount| amount.to_decimal_string(precision);
+        match self {
+            MissingAmount { tx, kind } => format!(
+                "Error parsing transaction {tx}, {} must have an amount",
+                plural(*kind)
+            ),
+            UnexpectedAmount { tx, kind } => format!(
+                "Error parsing transaction {tx}, {} may not have an amount",
+                plural(*kind)
+            ),
+            InvalidAmount { tx, reason } => format!("Error parsing transaction {tx}, {reason}"),
+            InvalidCurrency { tx, reason } => {
+                format!("Error parsing transaction {tx}, {reason}")
+            }
+            DuplicateId { tx } => {
+                format!("Error on trx {tx}: A transaction with this id already exists")
+            }
+            InsufficientFunds { tx, client, amount } => format!(
+                "Error on trx {tx}: Client {client} has insufficient funds for {}",
+                decimal(amount)
+            ),
+            Overflow { tx, client, amount } => format!(
+                "Error on trx {tx}: {} overflows the balance of client {client}",
+                decimal(amount)
+            ),
+            AccountLocked { tx, client } => {
+                format!("Error on trx {tx}: Attempt to mutate account {client}, which is locked")
+            }
+            ForeignTransaction { tx, client, owner } => format!(
+                "Error on trx {tx}: Client {client} can't reference a transaction of client {owner}"
+            ),
+            ForeignCurrency {
+                tx,
+                currency,
+                expected,
+            } => {
+                let name = |currency: &Option<Currency>| match currency {
+                    Some(currency) => currency.to_string(),
+                    None => "no currency".to_string(),
+                };
+                format!(
+                    "Error on trx {tx}: The transaction is in {}, not in {}",
+                    name(expected),
+                    name(currency)
+                )
+            }
+            ExcessiveChargeback {
+                tx,
+                amount,
+                disputed,
+            } => format!(
+                "Error on trx {tx}: A chargeback of {} exceeds the disputed amount of {}",
+                decimal(amount),
+                decimal(disputed)
+            ),
+            Undisputed { tx, kind, reason } => format!(
+                "Error on trx {tx}: {} must reference a disputed transaction ({reason})",
+                plural(*kind)
+            ),
+            UncoveredChargeback { tx, client, amount } => format!(
+                "Error on trx {tx}: Client {client} can't cover the chargeback of {}",
+                decimal(amount)
+            ),
+            HeldLimit {
+                tx,
+                client,
+                held,
+                limit,
+            } => format!(
+                "Error on trx {tx}: Client {client} would hold {}, which exceeds the limit of {}",
+                decimal(held),
+                decimal(limit)
+            ),
+            NotDisputable { tx, kind } => {
+                format!("Error on trx {tx}: {} can not be disputed", plural(*kind))
+            }
+            MissingRecipient { tx } => {
+                format!("Error parsing transaction {tx}, transfers must have a to_client")
+            }
+            UnexpectedRecipient { tx, kind } => format!(
+                "Error parsing transaction {tx}, {} may not have a to_client",
+                plural(*kind)
+            ),
+            SelfTransfer { tx, client } => {
+                format!("Error on trx {tx}: Client {client} can't transfer to itself")
+            }
+            TransferWithoutAccounts { tx } => {
+                format!("Error on trx {tx}: A transfer needs the accounts of both clients")
+            }
+            InconsistentAccount {
+                client,
+                available,
+                held,
+                total,
+            } => format!(
+                "Account {client} is inconsistent: available {} plus held {} is not equal to \
+                total {}",
+                decimal(available),
+                decimal(held),
+                decimal(total)
+            ),
+        }
+    }
+}
+
+/// Presents the amounts with the [DEFAULT_PRECISION], see [TransactionError::describe].
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe(DEFAULT_PRECISION))
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// The reasons why a string can not be converted into an [Amount] or a [Currency]. Like
+/// [TransactionError], this does not depend on `eyre`, so the state machine can report it without
+/// the IO layers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    /// The number of decimals is too large for the smallest increment to fit in an `i64`.
+    UnsupportedPrecision(u32),
+    /// The string is not a decimal number.
+    Invalid(String),
+    /// The string is a value like `inf` or `NaN`.
+    NotFinite(String),
+    /// The string is a negative number.
+    Negative(String),
+    /// The string has more decimals than the configured precision.
+    TooPrecise { amount: String, precision: u32 },
+    /// The string is a number that is larger than the largest amount, which is `max` with the
+    /// configured precision.
+    TooLarge { amount: String, max: String },
+    /// The string is not a [Currency] code.
+    InvalidCurrency(String),
+    /// The string is not the name of a [Rounding](crate::amount::Rounding) mode.
+    UnknownRounding(String),
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AmountError::*;
+        match self {
+            UnsupportedPrecision(precision) => {
+                write!(f, "A precision of {precision} decimals is not supported")
+            }
+            Invalid(amount) => write!(f, "{amount} is not a valid amount"),
+            NotFinite(amount) => write!(f, "{amount} is not a finite amount"),
+            Negative(amount) => write!(f, "{amount} is negative, amounts must be positive"),
+            TooPrecise { amount, precision } => {
+                write!(f, "{amount} has more than {precision} decimals")
+            }
+            TooLarge { amount, max } => {
+                write!(f, "{amount} is too large, the largest amount is {max}")
+            }
+            InvalidCurrency(currency) => write!(f, "{currency} is not a valid currency code"),
+            UnknownRounding(rounding) => write!(
+                f,
+                "Unknown rounding mode {rounding}, expected reject, truncate, half-up or half-even"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+/// The header of the input csv lacks some of the [COLUMNS]. The columns that are not expected are
+/// kept as well, since one of them is likely a misspelling of a missing column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderError {
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+    /// Whether the input is entirely empty, so it lacks a header altogether.
+    pub empty: bool,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.empty {
+            return write!(
+                f,
+                "The input is empty, it is missing a header with the columns {COLUMNS:?}"
+            );
+        }
+        write!(
+            f,
+            "The input has an invalid header, it is missing the columns {:?} and has the \
+            unexpected columns {:?}, expected the columns {COLUMNS:?}",
+            self.missing, self.unexpected
+        )
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// The string is not the name of a [TransactionType].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownTransactionType(pub String);
+
+impl fmt::Display for UnknownTransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = TransactionType::NAMES.iter().map(|(_, n)| *n).collect();
+        write!(
+            f,
+            "Unknown transaction type {}, expected one of {}",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownTransactionType {}
+
+fn plural(kind: TransactionType) -> &'static str {
+    match kind {
+        TransactionType::Deposit => "deposits",
+        TransactionType::Withdrawal => "withdrawals",
+        TransactionType::Dispute => "disputes",
+        TransactionType::Resolve => "resolves",
+        TransactionType::Chargeback => "chargebacks",
+        TransactionType::Unlock => "unlocks",
+        TransactionType::Interest => "interest payments",
+        TransactionType::Fee => "fees",
+        TransactionType::Adjustment => "adjustments",
+        TransactionType::Transfer => "transfers",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let err = TransactionError::ForeignCurrency {
+            tx: 2,
+            currency: "eur".parse().ok(),
+            expected: None,
+        };
+        let reason = "Error on trx 2: The transaction is in no currency, not in EUR";
+        assert_eq!(err.to_string(), reason);
+        assert_eq!(err.describe(2), reason);
+
+        let err = TransactionError::HeldLimit {
+            tx: 3,
+            client: 1,
+            held: Amount(15_000),
+            limit: Amount(10_000),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Error on trx 3: Client 1 would hold 1.5000, which exceeds the limit of 1.0000"
+        );
+        assert_eq!(
+            err.describe(2),
+            "Error on trx 3: Client 1 would hold 150.00, which exceeds the limit of 100.00"
+        );
+    }
+}
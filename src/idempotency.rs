@@ -0,0 +1,117 @@
+//! A run that is interrupted, for example because the process crashed halfway through a file, is
+//! retried from the start of the input, and a pipeline may deliver an input again after it was
+//! processed. The [IdempotencyLog] remembers which rows were applied, so that a retry skips them
+//! rather than applying them twice.
+//!
+//! The log is part of the [EngineState](crate::state::EngineState), so it is written to the same
+//! snapshot as the accounts that its rows were applied to, and the two can not get out of step. A
+//! crash before the snapshot is written loses the rows from the accounts and the log alike, so the
+//! retry applies them again, and once the snapshot is written the retry skips them.
+
+use crate::transaction::{Mutation, TransactionType};
+use std::collections::HashMap;
+
+/// A row is identified by its type, client and transaction id, since a dispute, resolve and
+/// chargeback reuse the id of the transaction that they reference.
+type Key = (TransactionType, u32, u32);
+
+/// The rows that were applied to a state, see [ingest_with_log](crate::engine::ingest_with_log).
+#[derive(Debug, Default, Clone)]
+pub struct IdempotencyLog {
+    /// The number of times that each row was applied.
+    applied: HashMap<Key, u32>,
+    /// The number of times that each row was seen since the log was read, either because it was
+    /// skipped or because it was applied. This is not part of the snapshot, since a retry starts
+    /// from the beginning of the input again.
+    seen: HashMap<Key, u32>,
+}
+
+impl IdempotencyLog {
+    /// Whether a row like `trx` was applied before, in which case it should be skipped. Every time
+    /// that a row was applied is used up by the first row that it matches, so a transaction that
+    /// is disputed again after it was resolved is only skipped as often as it was disputed before.
+    pub fn was_applied(&mut self, trx: &Mutation) -> bool {
+        let key = (trx.kind, trx.client, trx.id);
+        let applied = self.applied.get(&key).copied().unwrap_or(0);
+        let seen = self.seen.entry(key).or_insert(0);
+        if *seen < applied {
+            *seen += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Records that `trx` was applied.
+    pub fn append(&mut self, trx: &Mutation) {
+        let key = (trx.kind, trx.client, trx.id);
+        *self.applied.entry(key).or_insert(0) += 1;
+        *self.seen.entry(key).or_insert(0) += 1;
+    }
+}
+
+/// The rows are written as a list of `[type, client, tx, count]`, since a JSON object can not have
+/// a tuple as its key.
+impl serde::Serialize for IdempotencyLog {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rows = self.applied.iter();
+        serializer.collect_seq(rows.map(|(&(kind, client, tx), &count)| (kind, client, tx, count)))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IdempotencyLog {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let rows = Vec::<(TransactionType, u32, u32, u32)>::deserialize(deserializer)?;
+        let applied = (rows.into_iter())
+            .map(|(kind, client, tx, count)| ((kind, client, tx), count))
+            .collect();
+        Ok(Self {
+            applied,
+            seen: HashMap::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TransactionType::*;
+
+    fn mutation(kind: TransactionType, id: u32) -> Mutation {
+        Mutation {
+            id,
+            kind,
+            client: 1,
+            amount: None,
+            currency: None,
+            to_client: None,
+        }
+    }
+
+    #[test]
+    fn test_log() {
+        let mut log = IdempotencyLog::default();
+        assert!(!log.was_applied(&mutation(Deposit, 1)));
+        for (kind, id) in [(Deposit, 1), (Dispute, 1), (Resolve, 1), (Dispute, 1)] {
+            // A row that is applied in this run is not skipped again later in this run.
+            assert!(!log.was_applied(&mutation(kind, id)));
+            log.append(&mutation(kind, id));
+        }
+
+        // The retry reads the log back from the snapshot.
+        let snapshot = serde_json::to_string(&log).unwrap();
+        let mut log: IdempotencyLog = serde_json::from_str(&snapshot).unwrap();
+        assert!(log.was_applied(&mutation(Deposit, 1)));
+        assert!(!log.was_applied(&mutation(Deposit, 1)));
+        assert!(!log.was_applied(&mutation(Chargeback, 1)));
+        // The transaction was disputed twice.
+        assert!(log.was_applied(&mutation(Dispute, 1)));
+        assert!(log.was_applied(&mutation(Dispute, 1)));
+        assert!(!log.was_applied(&mutation(Dispute, 1)));
+        log.append(&mutation(Chargeback, 1));
+        let snapshot = serde_json::to_string(&log).unwrap();
+        let mut log: IdempotencyLog = serde_json::from_str(&snapshot).unwrap();
+        assert!(log.was_applied(&mutation(Chargeback, 1)));
+
+        assert!(serde_json::from_str::<IdempotencyLog>("[[\"deposit\", 1, 1]]").is_err());
+    }
+}
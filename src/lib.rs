@@ -0,0 +1,53 @@
+//! The engine is split into two layers. The core consists of [account], [amount], [error] and
+//! [transaction]: the state machine that applies mutations to accounts. It does not touch files,
+//! CSV or `eyre`, every failure is reported as a [TransactionError](error::TransactionError) or an
+//! [AmountError](error::AmountError), which only need `Display`, so embedding it comes down to
+//! formatting those. Around that sit the IO layers, [engine], [idempotency], [parse], [present],
+//! [source] and [state], which read the input, write the output and convert errors into an
+//! `eyre::Report` for the binary.
+//!
+//! The `eyre` feature is on by default. Without it, only the core and [parse] are compiled, which
+//! report their failures through the typed errors of [error]. That drops `eyre` from the
+//! dependencies for deployments that drive the state machine themselves.
+//!
+//! The core is not `no_std` yet: it stores accounts and transactions in a `HashMap`, which would
+//! have to come from `hashbrown` together with `alloc` for the strings in the errors, and it relies
+//! on `serde` and `tracing`, which both work without `std` when their default features are off.
+
+/// Contains the `Account` and `Accounts` structs that store the created list of accounts and their
+/// balances.
+pub mod account;
+/// Contains the `Amount` type that represents money as an integer number of smallest increments.
+pub mod amount;
+/// Contains the entry point of the transaction engine, which ties parsing, processing and presenting
+/// together.
+#[cfg(feature = "eyre")]
+pub mod engine;
+/// Contains the `TransactionError` and `AmountError` enums that describe why a transaction can not
+/// be processed.
+pub mod error;
+/// Contains the `IdempotencyLog`, the rows that were applied to a state, through which an input
+/// can be retried without applying rows twice.
+#[cfg(feature = "eyre")]
+pub mod idempotency;
+/// Contains the multi threaded processing path of the engine, which divides the clients over a
+/// number of worker threads.
+#[cfg(feature = "eyre")]
+mod parallel;
+/// Contains the functionality needed to read the input CSV and map it to a useful struct.
+pub mod parse;
+/// Contains the functionality needed to display an output CSV, created from our internal data
+/// structures.
+#[cfg(feature = "eyre")]
+pub mod present;
+/// Contains the `TransactionSource` trait, through which the engine reads its mutations, and its
+/// implementation for csv data.
+#[cfg(feature = "eyre")]
+pub mod source;
+/// Contains the `EngineState` struct that holds all state of the engine, and the functionality to
+/// write it to and read it from a snapshot.
+#[cfg(feature = "eyre")]
+pub mod state;
+/// Contains the `Transaction` and `Transactions` structs that represent the flow of money into and
+/// out of our accounts.
+pub mod transaction;
@@ -1,25 +1,46 @@
-/// Contains the `Account` and `Accounts` structs that store the created list of accounts and their
-/// balances.
-mod account;
-/// Contains the functionality needed to read the input CSV and map it to a useful struct.
-mod parse;
-/// Contains the functionality needed to display an output CSV, created from our internal data
-/// structures.
-mod present;
-/// Contains the `Transaction` and `Transactions` structs that represent the flow of money into and
-/// out of our accounts.
-mod transaction;
-
 use eyre::{eyre, Result};
+use flate2::read::GzDecoder;
+use payments::{
+    account::{Disputable, UncoveredChargeback, UnfundedDispute},
+    amount::{Amount, DEFAULT_PRECISION},
+    engine::{
+        self, EngineConfig, Incident, IncidentKind, OutputFormat, Reconciliation, RunSummary,
+    },
+    source,
+    state::EngineState,
+};
 use std::{
     fs,
+    io::{BufRead, BufReader, Read},
+    path::Path,
     process::{ExitCode, Termination},
 };
+use tracing::Level;
+
+const USAGE: &str = "Usage: cargo run -- [--help] [--version] [--check] [--lenient] [--no-sort] \
+    [--gzip] [--stats] [--risk-flags] [--last-row] [--format csv|json] \
+    [--layout ledger | column[:Header],...] [--locked true/false|frozen/active|1/0] \
+    [-v | -vv | -vvv] [--precision N] [--input-scale N] [--minor-units] \
+    [--rounding reject|truncate|half-up|half-even] \
+    [--overdraft AMOUNT] [--strict-withdrawals] [--strict-disputes] \
+    [--disputable deposits|deposits-withdrawals|all] [--unfunded-disputes fail|hold|ignore] \
+    [--uncovered-chargebacks fail|overdraw|review] [--held-limit AMOUNT [--review-held]] \
+    [--threads N] [--history N] \
+    [--chronological] [--check-clients] [--per-client-ids] [--clients ID,...] \
+    [--allow-clients ID,... | --deny-clients ID,...] \
+    [--buffer-size BYTES] [--delimiter CHAR] [--summary] [--summary-row] [--partial-chargebacks] \
+    [--fail-on-lock] [--max-rows N] [--max-errors N] [--explain TX] [--reconcile] \
+    [--report file.jsonl | -] [--snapshot-in state.json] [--snapshot-out state.json] \
+    [--idempotent --snapshot-out state.json] \
+    [--shard-output N --output-dir DIR] \
+    [input files.csv | - | --dir DIR [--skip-non-csv]] > [output file]";
 
 #[repr(u8)]
 pub enum Exit {
     Success = 0,
     Failure = 1,
+    /// Processing stopped early because an account was locked, see `--fail-on-lock`.
+    Locked = 2,
 }
 
 impl Termination for Exit {
@@ -28,11 +49,348 @@ impl Termination for Exit {
     }
 }
 
+/// The command line arguments that we accept.
+#[derive(Default)]
+struct Args {
+    /// The paths to the input csvs, which are processed in order. When this is empty, we read from
+    /// stdin instead, as we do for a path that is `-`.
+    paths: Vec<String>,
+    /// The path to write the report of incidents to. When this is `-`, we write to stderr instead.
+    report: Option<String>,
+    /// The path to a snapshot to resume from, instead of starting with an empty state.
+    snapshot_in: Option<String>,
+    /// The path to write a snapshot of the state to after processing the input.
+    snapshot_out: Option<String>,
+    /// Whether to record the applied rows in the state, so that they are skipped when the input is
+    /// retried. The rows are kept in the snapshot, so this needs `snapshot_out`.
+    idempotent: bool,
+    /// The number of files to spread the output over, see `output_dir`.
+    shards: Option<usize>,
+    /// The directory to write the shards of the output to, as `shard-0.csv` and up, instead of
+    /// writing the output to stdout.
+    output_dir: Option<String>,
+    /// When set, all inputs are gzip compressed. Otherwise only inputs with a `.gz` extension are.
+    gzip: bool,
+    /// When set, we print a summary of the processed rows to stderr.
+    summary: bool,
+    /// When set, we check that the totals of the accounts grew by the net flow of the applied
+    /// mutations, and fail without writing any output when they did not.
+    reconcile: bool,
+    /// When set, we only validate the input and report the problems that we find, without writing
+    /// any output or snapshot.
+    check: bool,
+    /// When set, we print the usage and exit without processing anything.
+    help: bool,
+    /// When set, we print the version and exit without processing anything.
+    version: bool,
+    /// How much we log to stderr, where 0 only logs warnings and every step up logs more detail.
+    verbosity: u8,
+    /// The configuration that we pass to the engine.
+    config: EngineConfig,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let (mut paths, mut report, mut builder) = (Vec::new(), None, EngineConfig::builder());
+        let (mut snapshot_in, mut snapshot_out, mut idempotent) = (None, None, false);
+        let (mut shards, mut output_dir) = (None, None);
+        let (mut dir, mut skip_non_csv) = (None, false);
+        let (mut verbosity, mut overdraft, mut check, mut gzip) = (0_u8, None, false, false);
+        let (mut summary, mut reconcile, mut held_limit) = (false, false, None);
+        let mut precision = DEFAULT_PRECISION;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            // The usage and version are printed regardless of the other arguments, so we do not
+            // validate those.
+            if arg == "--help" || arg == "-h" {
+                return Ok(Self {
+                    help: true,
+                    ..Self::default()
+                });
+            } else if arg == "--version" || arg == "-V" {
+                return Ok(Self {
+                    version: true,
+                    ..Self::default()
+                });
+            } else if arg == "--check" {
+                check = true;
+            } else if arg == "--summary" {
+                summary = true;
+            } else if arg == "--reconcile" {
+                reconcile = true;
+            } else if arg == "--gzip" {
+                gzip = true;
+            } else if arg == "--lenient" {
+                builder = builder.lenient(true);
+            } else if arg == "--stats" {
+                builder = builder.stats(true);
+            } else if arg == "--risk-flags" {
+                builder = builder.risk_flags(true);
+            } else if arg == "--last-row" {
+                builder = builder.last_row(true);
+            } else if arg == "--minor-units" {
+                builder = builder.minor_units(true);
+            } else if arg == "--summary-row" {
+                builder = builder.summary_row(true);
+            } else if arg == "--partial-chargebacks" {
+                builder = builder.partial_chargebacks(true);
+            } else if arg == "--strict-withdrawals" {
+                builder = builder.strict_withdrawals(true);
+            } else if arg == "--strict-disputes" {
+                builder = builder.strict_disputes(true);
+            } else if arg == "--fail-on-lock" {
+                builder = builder.stop_on_lock(true);
+            } else if arg == "--check-clients" {
+                builder = builder.check_clients(true);
+            } else if arg == "--per-client-ids" {
+                builder = builder.per_client_ids(true);
+            } else if arg == "--chronological" {
+                builder = builder.chronological(true);
+            } else if arg == "--no-sort" {
+                builder = builder.sort(false);
+            } else if arg == "--clients" {
+                let clients = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --clients"))?;
+                let clients = (clients.split(',').map(|client| client.trim().parse()))
+                    .collect::<Result<Vec<u32>, _>>()?;
+                builder = builder.clients(clients);
+            } else if arg == "--allow-clients" || arg == "--deny-clients" {
+                let clients = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for {arg}"))?;
+                let clients = (clients.split(',').map(|client| client.trim().parse()))
+                    .collect::<Result<Vec<u32>, _>>()?;
+                builder = match arg.as_str() {
+                    "--allow-clients" => builder.allow_clients(clients),
+                    _ => builder.deny_clients(clients),
+                };
+            } else if arg == "--buffer-size" {
+                let size = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --buffer-size"))?;
+                builder = builder.buffer_size(size.parse()?);
+            } else if arg == "--delimiter" {
+                let delimiter = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --delimiter"))?;
+                match delimiter.as_bytes() {
+                    &[delimiter] => builder = builder.delimiter(delimiter),
+                    _ => return Err(eyre!("The delimiter {delimiter} is not a single character")),
+                }
+            } else if arg == "--format" {
+                let format = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --format"))?;
+                builder = builder.format(format.parse()?);
+            } else if arg == "--layout" {
+                let layout = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --layout"))?;
+                builder = builder.layout(layout.parse()?);
+            } else if arg == "--precision" {
+                let value = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --precision"))?;
+                precision = value.parse()?;
+                builder = builder.precision(precision);
+            } else if arg == "--locked" {
+                let locked = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --locked"))?;
+                builder = builder.locked(locked.parse()?);
+            } else if arg == "--disputable" {
+                let disputable = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --disputable"))?;
+                let disputable = match disputable.as_str() {
+                    "deposits" => Disputable::Deposits,
+                    "deposits-withdrawals" => Disputable::DepositsAndWithdrawals,
+                    "all" => Disputable::All,
+                    _ => return Err(eyre!("Unknown value {disputable} for --disputable")),
+                };
+                builder = builder.disputable(disputable);
+            } else if arg == "--unfunded-disputes" {
+                let unfunded = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --unfunded-disputes"))?;
+                let unfunded = match unfunded.as_str() {
+                    "fail" => UnfundedDispute::Fail,
+                    "hold" => UnfundedDispute::Hold,
+                    "ignore" => UnfundedDispute::Ignore,
+                    _ => return Err(eyre!("Unknown value {unfunded} for --unfunded-disputes")),
+                };
+                builder = builder.unfunded_disputes(unfunded);
+            } else if arg == "--uncovered-chargebacks" {
+                let uncovered = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --uncovered-chargebacks"))?;
+                let uncovered = match uncovered.as_str() {
+                    "fail" => UncoveredChargeback::Fail,
+                    "overdraw" => UncoveredChargeback::Overdraw,
+                    "review" => UncoveredChargeback::Review,
+                    _ => {
+                        return Err(eyre!(
+                            "Unknown value {uncovered} for --uncovered-chargebacks"
+                        ))
+                    }
+                };
+                builder = builder.uncovered_chargebacks(uncovered);
+            } else if arg == "--rounding" {
+                let rounding = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --rounding"))?;
+                builder = builder.rounding(rounding.parse()?);
+            } else if arg == "--input-scale" {
+                let scale = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --input-scale"))?;
+                builder = builder.input_scale(scale.parse()?);
+            } else if arg == "--overdraft" {
+                let limit = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --overdraft"))?;
+                overdraft = Some(limit);
+            } else if arg == "--held-limit" {
+                let limit = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --held-limit"))?;
+                held_limit = Some(limit);
+            } else if arg == "--review-held" {
+                builder = builder.review_excess_held(true);
+            } else if arg == "--threads" {
+                let threads = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --threads"))?;
+                builder = builder.threads(threads.parse()?);
+            } else if arg == "--history" {
+                let limit = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --history"))?;
+                builder = builder.history_limit(Some(limit.parse()?));
+            } else if arg == "--max-rows" {
+                let max = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --max-rows"))?;
+                builder = builder.max_rows(Some(max.parse()?));
+            } else if arg == "--max-errors" {
+                let max = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --max-errors"))?;
+                builder = builder.max_errors(Some(max.parse()?));
+            } else if arg == "--explain" {
+                let tx = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --explain"))?;
+                builder = builder.explain(Some(tx.parse()?));
+            } else if arg == "--report" {
+                let path = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --report"))?;
+                report = Some(path);
+            } else if arg == "--snapshot-in" {
+                let path = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --snapshot-in"))?;
+                snapshot_in = Some(path);
+            } else if arg == "--snapshot-out" {
+                let path = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --snapshot-out"))?;
+                snapshot_out = Some(path);
+            } else if arg == "--idempotent" {
+                idempotent = true;
+            } else if arg == "--dir" {
+                let path = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --dir"))?;
+                dir = Some(path);
+            } else if arg == "--skip-non-csv" {
+                skip_non_csv = true;
+            } else if arg == "--shard-output" {
+                let count = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --shard-output"))?;
+                shards = Some(count.parse()?);
+            } else if arg == "--output-dir" {
+                let path = args
+                    .next()
+                    .ok_or_else(|| eyre!("Missing value for --output-dir"))?;
+                output_dir = Some(path);
+            } else if arg == "--verbose" {
+                verbosity = verbosity.saturating_add(1);
+            } else if arg.starts_with("-v") && arg[1..].chars().all(|c| c == 'v') {
+                // Accept both `-v -v` and `-vv`. Beyond the most detailed level, more `v`s do not
+                // change anything.
+                let count = u8::try_from(arg.len() - 1).unwrap_or(u8::MAX);
+                verbosity = verbosity.saturating_add(count);
+            } else if arg.starts_with("--") {
+                return Err(eyre!("Unknown option {arg}\n{USAGE}"));
+            } else {
+                paths.push(arg);
+            }
+        }
+        // The limits are parsed last, since they depend on the configured precision.
+        if let Some(limit) = overdraft {
+            builder = builder.overdraft_limit(Amount::from_decimal_str(&limit, precision)?);
+        }
+        if let Some(limit) = held_limit {
+            let limit = Amount::from_decimal_str(&limit, precision)?;
+            builder = builder.held_limit(Some(limit));
+        }
+        // The files in the directory are processed in the order of their names, as if they were
+        // passed one after the other.
+        if let Some(dir) = dir {
+            if !paths.is_empty() {
+                return Err(eyre!("--dir can not be combined with input files"));
+            }
+            for path in source::csv_files(Path::new(&dir), skip_non_csv)? {
+                paths.push(path.to_string_lossy().into_owned());
+            }
+            if paths.is_empty() {
+                return Err(eyre!("There are no csv files in {dir}"));
+            }
+        }
+        if paths.is_empty() {
+            paths.push("-".to_string());
+        }
+        if shards.is_some() != output_dir.is_some() {
+            return Err(eyre!(
+                "--shard-output and --output-dir must be used together"
+            ));
+        }
+        // The applied rows have to be saved along with the accounts that they were applied to,
+        // otherwise a retry would skip rows that no saved state has seen.
+        if idempotent && snapshot_out.is_none() {
+            return Err(eyre!(
+                "--idempotent needs --snapshot-out, since the applied rows are kept in the snapshot"
+            ));
+        }
+        Ok(Self {
+            config: builder.build()?,
+            paths,
+            report,
+            snapshot_in,
+            snapshot_out,
+            idempotent,
+            shards,
+            output_dir,
+            gzip,
+            summary,
+            reconcile,
+            check,
+            help: false,
+            version: false,
+            verbosity,
+        })
+    }
+}
+
 /// Our main function offloads to `try_main`, and it itself is only concerned with exit codes and
 /// displaying an eventual failure.
 fn main() -> Exit {
     match try_main() {
-        Ok(_) => Exit::Success,
+        Ok(exit) => exit,
         Err(msg) => {
             eprintln!("The transaction engine failed with message:\n{msg}");
             Exit::Failure
@@ -40,49 +398,180 @@ fn main() -> Exit {
     }
 }
 
-/// The meat of our application. Reads csv data from a csv (indicated by the first arg) and runs it
-/// trough the engine to construct a list of accounts and transactions, then outputs the resulting
-/// account states to stdout.
-fn try_main() -> Result<()> {
-    // Get a csv reader for the indicated file.
-    let mut reader = reader()?;
-
-    // Our state is maintained in these two structs, one contains all the accounts, whereas the
-    // other contains a list of all deposited transactions.
-    let mut accounts = account::Accounts::default();
-    // This data structure will hold all of our transaction state, that is, deposits and
-    // withdrawals. We would have preferred to not need to keep track of this, but since disputes,
-    // resolves and chargebacks don't contain their own amount, we need to be able to look back at
-    // the entire history of deposits and withdrawals.
-    let mut trxs = transaction::Transactions::default();
+/// Opens the csvs indicated by the arguments (or stdin) and hands them to the engine one after the
+/// other, which writes the resulting account states to stdout. When requested, the engine resumes
+/// from a snapshot and the final state is written to a snapshot as well.
+fn try_main() -> Result<Exit> {
+    let mut args = Args::parse()?;
+    if args.help {
+        println!("{USAGE}");
+        return Ok(Exit::Success);
+    }
+    if args.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(Exit::Success);
+    }
+    init_logging(args.verbosity);
+    // A dry run should find all problems in one go, rather than stopping at the first one.
+    if args.check {
+        args.config.lenient = true;
+    }
+    let mut state = match args.snapshot_in.as_deref() {
+        Some(path) => EngineState::read_snapshot(fs::File::open(path)?)?,
+        None => EngineState::default(),
+    };
+    // A snapshot that records its applied rows keeps doing so, otherwise a later retry would apply
+    // the rows of this run twice.
+    let idempotent = args.idempotent || state.applied.is_some();
+    // All files are processed into the same state, so a dispute can reference a deposit from an
+    // earlier file.
+    let (mut incidents, mut summary) = (Vec::new(), RunSummary::default());
+    let opening = state.accounts.sum_of_totals();
+    let max_errors = args.config.max_errors;
+    for path in &args.paths {
+        // The maximum number of rows applies to every input on its own, but the maximum number of
+        // errors applies to all inputs together, so every input may only have the errors that the
+        // inputs before it left over.
+        if let Some(max) = max_errors {
+            args.config.max_errors = Some(max.saturating_sub(summary.failed));
+        }
+        let input = open_input(path, args.gzip, args.config.buffer_size)?;
+        let _span = tracing::info_span!("ingest", path = path.as_str()).entered();
+        let (new_incidents, new_summary, transitions) = match idempotent {
+            true => engine::ingest_with_log(&mut state, input, &args.config)?,
+            false => engine::ingest_with_transitions(&mut state, input, &args.config)?,
+        };
+        // The transitions go to stderr, so that they do not end up in the output.
+        for transition in transitions {
+            eprintln!("{path}: {}", transition.describe(args.config.precision));
+        }
+        incidents.extend(new_incidents);
+        summary.merge(&new_summary);
+        // The remaining inputs are not processed either, but the rows up to the lock are written.
+        if let Some(row) = new_summary.stopped_at_row {
+            eprintln!("Stopped after row {row} of {path}, because a chargeback locked an account");
+            break;
+        }
+    }
+    if args.summary {
+        eprintln!("{summary}");
+    }
+    if args.reconcile {
+        let reconciliation = Reconciliation {
+            opening,
+            net_flow: summary.net_flow,
+            closing: state.accounts.sum_of_totals(),
+        };
+        if !reconciliation.is_balanced() {
+            return Err(eyre!("Reconciliation failed: {reconciliation}"));
+        }
+        eprintln!("{reconciliation}");
+    }
+    if args.check {
+        return Ok(report_problems(&incidents));
+    }
+    if let Some(path) = args.snapshot_out.as_deref() {
+        state.write_snapshot(fs::File::create(path)?)?;
+    }
+    if let (Some(shards), Some(dir)) = (args.shards, args.output_dir.as_deref()) {
+        write_shards(state, shards, dir, &args.config)?;
+        return finish(&args, &incidents, &summary);
+    }
+    let stdout = std::io::stdout().lock();
+    // When the consumer of our output stops reading, for example because it is `head`, there is
+    // nobody left to write the accounts to, but that is not a failure of the engine.
+    match engine::write_accounts(state, stdout, &args.config) {
+        Err(e) if engine::is_broken_pipe(&e) => tracing::debug!("Output closed early"),
+        result => result?,
+    }
+    finish(&args, &incidents, &summary)
+}
 
-    // We iterate over each record in the csv file.
-    for result in reader.deserialize() {
-        let record: parse::CsvRow = result?;
+/// Writes the accounts in `state` to `shards` files in `dir`, which is created when it does not
+/// exist yet.
+fn write_shards(state: EngineState, shards: usize, dir: &str, config: &EngineConfig) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let extension = match config.format {
+        OutputFormat::Csv => "csv",
+        OutputFormat::JsonLines => "jsonl",
+    };
+    let outputs = (0..shards)
+        .map(|shard| {
+            let path = Path::new(dir).join(format!("shard-{shard}.{extension}"));
+            Ok(fs::File::create(path)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    engine::write_shards(state, outputs, config)
+}
 
-        let trx = record.as_mutation()?;
-        // Get the correct account, and mutate it according to this transaction.
-        accounts.account_for_id(trx.client).mutate(trx, &mut trxs)?;
+/// Reports the incidents after the output was written, and picks the exit code.
+fn finish(args: &Args, incidents: &[Incident], summary: &RunSummary) -> Result<Exit> {
+    match args.report.as_deref() {
+        Some("-") => engine::write_incidents(incidents, std::io::stderr().lock())?,
+        Some(path) => engine::write_incidents(incidents, fs::File::create(path)?)?,
+        // Without a report, we only mention the rows that were skipped in lenient mode, and the
+        // rows that were rejected because of a locked account.
+        None => {
+            let skipped = (incidents.iter()).filter(|i| i.kind != IncidentKind::Ignored);
+            for incident in skipped {
+                eprintln!("Skipped row: {}", incident.reason);
+            }
+        }
+    }
+    match summary.stopped_at_row {
+        Some(_) => Ok(Exit::Locked),
+        None => Ok(Exit::Success),
     }
+}
 
-    // Now we are ready to print our data to stdout.
-    let stdout = std::io::stdout().lock();
-    let mut writer = csv::Writer::from_writer(stdout);
-    for account in accounts {
-        // We transform each account from our internal sturct to a struct that matches the csv rows
-        // we need to produce.
-        writer.serialize(present::CsvRow::from_account(account))?;
+/// Opens the input at `path`, where `-` denotes stdin. Inputs with a `.gz` extension, or all inputs
+/// when `gzip` is set, are decompressed while they are read. Files are read in chunks of
+/// `buffer_size` bytes.
+fn open_input(path: &str, gzip: bool, buffer_size: usize) -> Result<Box<dyn Read>> {
+    let input: Box<dyn Read> = match path {
+        "-" => Box::new(std::io::stdin().lock()),
+        path => Box::new(BufReader::with_capacity(buffer_size, fs::File::open(path)?)),
+    };
+    if !gzip && !path.ends_with(".gz") {
+        return Ok(input);
     }
+    // The decoder only notices that the input is not gzip once the csv reader starts reading,
+    // which produces a confusing error, so we check the magic bytes of the gzip header up front.
+    let mut input = BufReader::with_capacity(buffer_size, input);
+    if !input.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        return Err(eyre!("{path} is not gzip compressed"));
+    }
+    Ok(Box::new(GzDecoder::new(input)))
+}
 
-    Ok(())
+/// Reports the result of a dry run. Every incident is a problem, since both rows that fail to
+/// parse and rows that reference an unknown transaction indicate a faulty input file.
+fn report_problems(incidents: &[Incident]) -> Exit {
+    for incident in incidents {
+        match incident.tx {
+            Some(tx) => eprintln!("Problem with trx {tx}: {}", incident.reason),
+            None => eprintln!("Problem: {}", incident.reason),
+        }
+    }
+    if incidents.is_empty() {
+        eprintln!("No problems found");
+        Exit::Success
+    } else {
+        eprintln!("Found {} problems", incidents.len());
+        Exit::Failure
+    }
 }
 
-fn reader() -> Result<csv::Reader<fs::File>> {
-    let name = std::env::args()
-        .nth(1)
-        .ok_or_else(|| eyre!("Usage: cargo run -- [input file].csv > [output file].csv"))?;
-    let reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(name)?;
-    Ok(reader)
+/// Logs to stderr, so the logs don't end up in the csv that we write to stdout.
+fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
 }
@@ -0,0 +1,154 @@
+//! Since every client's account is independent of the other accounts, we can divide the clients
+//! over a number of worker threads, where each worker owns the accounts and transactions of its own
+//! clients. The rows are read and parsed on the calling thread, and then sent to the worker that
+//! owns the client, which means that the rows of a single client are still applied in file order.
+//!
+//! There are two tradeoffs to be aware of. Because each worker only knows about the transactions
+//! of its own clients, reusing a transaction id of another client is not detected, and a dispute
+//! that references a transaction of another client is treated like a dispute for an unknown
+//! transaction. Furthermore, incidents are reported per worker once all rows have been read, so in
+//! strict mode the rows after the first error are still processed before the run is aborted, and
+//! the state of an aborted run is lost rather than partially updated. A transfer between clients
+//! of different workers could not change both accounts at once, so an input with transfers is not
+//! processed here at all.
+
+use crate::{
+    account::Policy,
+    engine::{self, EngineConfig, Incident, RunSummary},
+    source::TransactionSource,
+    state::EngineState,
+    transaction::Mutation,
+};
+use eyre::{eyre, Result};
+use std::{sync::mpsc, thread};
+
+/// The number of mutations we send to a worker at once. Sending each mutation separately would
+/// make the channel the bottleneck.
+const BATCH_SIZE: usize = 1024;
+/// The number of batches that may be queued for a worker, before the reading thread waits for the
+/// worker to catch up. This bounds the memory that is used for in flight mutations.
+const QUEUED_BATCHES: usize = 16;
+
+/// Processes all rows, dividing the clients in `state` over the configured number of worker
+/// threads, and merges the state and the summaries of the workers back into `state` and `summary`
+/// when all rows have been processed.
+pub fn process<F>(
+    source: &mut dyn TransactionSource,
+    state: &mut EngineState,
+    config: &EngineConfig,
+    on_incident: &mut F,
+    summary: &mut RunSummary,
+) -> Result<()>
+where
+    F: FnMut(Incident) -> Result<()>,
+{
+    let threads = config.threads;
+    let per_client = state.trxs.is_per_client();
+    // The workers do not follow the log of applied rows, so it stays with the merged state.
+    let applied = state.applied.take();
+    let mut shards = std::mem::take(state).split(threads);
+    state.applied = applied;
+    // The shards are merged back into an empty state, which has to key the transactions the same.
+    state.trxs.set_per_client(per_client)?;
+    for shard in &mut shards {
+        shard.trxs.set_limit(config.history_limit);
+    }
+    let (senders, workers): (Vec<_>, Vec<_>) = shards
+        .into_iter()
+        .enumerate()
+        .map(|(index, shard)| spawn_worker(index, shard, config.policy.clone(), config.precision))
+        .unzip();
+    let mut batches: Vec<Batch> = (0..threads)
+        .map(|_| Vec::with_capacity(BATCH_SIZE))
+        .collect();
+
+    while let Some(result) = source.next_mutation() {
+        engine::check_row_limit(summary, config)?;
+        summary.rows += 1;
+        let trx = match result {
+            Ok(trx) => trx,
+            Err(incident) => {
+                summary.failed += 1;
+                on_incident(incident)?;
+                continue;
+            }
+        };
+        if config.filters_out(&trx) {
+            summary.filtered += 1;
+            continue;
+        }
+        let shard = trx.client as usize % threads;
+        // A source with transfers is processed on a single thread, so this is a source that said
+        // that it has none. The transfer can not be applied atomically across shards.
+        if let Some(to) = trx.to_client.filter(|&to| to as usize % threads != shard) {
+            summary.failed += 1;
+            let reason = format!(
+                "Row {}: Can't transfer from client {} to client {to}, since the source said that \
+                it has no transfers",
+                summary.rows, trx.client
+            );
+            on_incident(Incident::failed(Some(trx.id), Some(trx.client), reason))?;
+            continue;
+        }
+        batches[shard].push((summary.rows, trx));
+        if batches[shard].len() == BATCH_SIZE {
+            let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+            send(&senders[shard], batch)?;
+        }
+    }
+    for (sender, batch) in senders.iter().zip(batches) {
+        send(sender, batch)?;
+    }
+    // Dropping the senders closes the channels, which tells the workers that they are done.
+    drop(senders);
+
+    for worker in workers {
+        let (shard, incidents, worker_summary) = worker
+            .join()
+            .map_err(|_| eyre!("A worker thread panicked"))?;
+        state.merge(shard);
+        summary.merge(&worker_summary);
+        state.trxs.set_limit(config.history_limit);
+        for incident in incidents {
+            on_incident(incident)?;
+        }
+    }
+    Ok(())
+}
+
+type Worker = thread::JoinHandle<(EngineState, Vec<Incident>, RunSummary)>;
+
+/// Spawns a worker thread, which applies all mutations that it receives to its own shard of the
+/// state, until the channel is closed.
+fn spawn_worker(
+    index: usize,
+    mut shard: EngineState,
+    policy: Policy,
+    precision: u32,
+) -> (mpsc::SyncSender<Batch>, Worker) {
+    let (sender, receiver) = mpsc::sync_channel::<Batch>(QUEUED_BATCHES);
+    let worker = thread::spawn(move || {
+        let _span = tracing::info_span!("worker", index).entered();
+        let mut incidents = Vec::new();
+        let mut summary = RunSummary::default();
+        for (row, trx) in receiver.into_iter().flatten() {
+            let (accounts, trxs) = (&mut shard.accounts, &mut shard.trxs);
+            let incident =
+                engine::apply(trx, row, accounts, trxs, &policy, precision, &mut summary);
+            incidents.extend(incident);
+        }
+        summary.log();
+        (shard, incidents, summary)
+    });
+    (sender, worker)
+}
+
+/// A batch of mutations, along with the number of the row that each of them was read from.
+type Batch = Vec<(u64, Mutation)>;
+
+fn send(sender: &mpsc::SyncSender<Batch>, batch: Batch) -> Result<()> {
+    // Sending only fails when the worker is gone, which can only happen if it panicked.
+    sender
+        .send(batch)
+        .map_err(|_| eyre!("A worker thread stopped unexpectedly"))
+}
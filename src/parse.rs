@@ -1,4 +1,43 @@
-use crate::transaction::{Mutation, TransactionType};
+use crate::{
+    amount::{Amount, Currency, Rounding},
+    error::{HeaderError, TransactionError},
+    transaction::{Mutation, TransactionType},
+};
+
+/// The columns that the input CSV must have. Their order does not matter, since rows are read by
+/// header name, and any other columns are ignored.
+pub const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// The columns that the input CSV may have on top of the [COLUMNS], which we use when present. A
+/// transfer is from the client in the `client` column, so there is no `from_client` column.
+pub const OPTIONAL_COLUMNS: [&str; 3] = ["timestamp", "currency", "to_client"];
+
+/// Checks that the header of the input CSV has all of the [COLUMNS]. Without this check a
+/// misspelled header would only surface as a confusing error for every single row. Other columns,
+/// such as a memo, are accepted, but we mention them when a column is missing, since one of them
+/// is likely the misspelled column. An input without any header is rejected as well, since an
+/// entirely empty file more likely is a failed export than an hour without transactions, which
+/// would still have the header.
+pub fn validate_headers(headers: &csv::StringRecord) -> Result<(), HeaderError> {
+    let missing: Vec<_> = COLUMNS
+        .iter()
+        .filter(|column| !headers.iter().any(|header| header == **column))
+        .map(|column| column.to_string())
+        .collect();
+    let unexpected: Vec<_> = headers
+        .iter()
+        .filter(|header| !COLUMNS.contains(header) && !OPTIONAL_COLUMNS.contains(header))
+        .map(|header| header.to_string())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(HeaderError {
+        missing,
+        unexpected,
+        empty: headers.is_empty(),
+    })
+}
 
 /// This struct is meant only to parse rows from the input CSV. Since we need to track additional
 /// data, we use a separate internal model ([Transaction](crate::transaction::Transaction)) to
@@ -12,36 +51,438 @@ pub struct CsvRow {
     #[serde(rename = "type")]
     kind: TransactionType,
     /// The unique id of the client performing this transaction.
-    client: u16,
+    client: u32,
     /// The unique id of the transaction being performed. Note that this uniquely identifies a
     /// transaction, but there may be multiple CSV rows per transaction as it moves through the
     /// stages of refunding.
     tx: u32,
-    /// The amount of currency that is concerned.
-    amount: Option<f64>,
+    /// The amount of currency that is concerned. We keep this as a string and leave the conversion
+    /// to [Amount](crate::amount::Amount).
+    amount: Option<String>,
+    /// The moment at which the transaction happened, as a unix timestamp. This column is optional,
+    /// and it is only used to sort the rows when they are not delivered in chronological order.
+    #[serde(default)]
+    timestamp: Option<u64>,
+    /// The currency of the amount. This column is optional, and when it is present a client has a
+    /// separate account for every currency. A dispute, resolve or chargeback may leave it empty.
+    #[serde(default)]
+    currency: Option<String>,
+    /// The client that a transfer is for. The client that it is from, the `from_client` of a
+    /// transfer, is read from the `client` column, like the client of every other row. This column
+    /// is optional, and only transfers may fill it in.
+    #[serde(default)]
+    to_client: Option<u32>,
 }
 
 impl CsvRow {
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn client(&self) -> u32 {
+        self.client
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
     /// The silent invariant for our program to operate in a sensible way is that fundamentally,
-    /// deposits and withdrawals have an amount, whereas disputes, resolves and chargebacks do not.
-    /// We perform a check here to make sure that we do not accidentally handle data in an
-    /// unexpected way, and this is the reason that converting a CsvRow to a Mutation may fail.
-    pub fn as_mutation(self) -> eyre::Result<Mutation> {
+    /// deposits, withdrawals, interest payments, fees and transfers have a positive amount,
+    /// adjustments have a positive or negative amount, whereas disputes, resolves, chargebacks and
+    /// unlocks do not have an amount. Only transfers have a `to_client`. We perform a check here
+    /// to make sure that we do not accidentally handle data in an unexpected way, and this is the
+    /// reason that converting a CsvRow to a Mutation may fail. The amount is parsed with
+    /// `precision` decimals, following `rounding` when it has more. When `partial_chargebacks` is
+    /// set, a chargeback may have an amount as well, which is the part of the disputed amount that
+    /// is charged back.
+    pub fn as_mutation(
+        self,
+        precision: u32,
+        partial_chargebacks: bool,
+        rounding: Rounding,
+    ) -> Result<Mutation, TransactionError> {
         use TransactionType::*;
-        let err = |msg| Err(eyre::eyre!("Error parsing transaction {}, {msg}", self.tx));
-        match (self.kind, self.amount) {
-            (Deposit, None) => return err("deposits musts have an amount"),
-            (Withdrawal, None) => return err("withdrawals must have an amount"),
-            (Dispute, Some(_)) => return err("disputes may not have an amount"),
-            (Resolve, Some(_)) => return err("resolves may not have an amount"),
-            (Chargeback, Some(_)) => return err("chargebacks may not have an amount"),
-            _ => {}
+        let (tx, kind) = (self.tx, self.kind);
+        match (kind, &self.amount) {
+            (Deposit | Withdrawal | Interest | Fee | Adjustment | Transfer, None) => {
+                return Err(TransactionError::MissingAmount { tx, kind })
+            }
+            (Deposit | Withdrawal | Interest | Fee | Adjustment | Transfer, Some(_)) => {}
+            (Chargeback, Some(_)) if partial_chargebacks => {}
+            (_, Some(_)) => return Err(TransactionError::UnexpectedAmount { tx, kind }),
+            (_, None) => {}
+        };
+        match (kind, self.to_client) {
+            (Transfer, None) => return Err(TransactionError::MissingRecipient { tx }),
+            (Transfer, Some(_)) | (_, None) => {}
+            (_, Some(_)) => return Err(TransactionError::UnexpectedRecipient { tx, kind }),
+        }
+        let invalid = |reason: String| TransactionError::InvalidAmount { tx, reason };
+        // Only an adjustment may be negative, the sign is parsed separately from the digits.
+        let (amount, negative) = match self.amount.as_deref() {
+            Some(amount) if kind == Adjustment => match amount.strip_prefix('-') {
+                Some(amount) => (Some(amount), true),
+                None => (Some(amount), false),
+            },
+            amount => (amount, false),
+        };
+        let amount = match amount {
+            Some(amount) => match Amount::from_decimal_str_rounded(amount, precision, rounding) {
+                Ok(amount) if negative => Some(Amount(-amount.0)),
+                Ok(amount) => Some(amount),
+                Err(e) => return Err(invalid(e.to_string())),
+            },
+            None => None,
+        };
+        if amount == Some(Amount::ZERO) && kind == Adjustment {
+            return Err(invalid("adjustments can not be zero".to_string()));
+        }
+        if amount == Some(Amount::ZERO) {
+            return Err(invalid("amounts must be positive".to_string()));
+        }
+        let currency = match self.currency.as_deref() {
+            Some(currency) => match currency.parse::<Currency>() {
+                Ok(currency) => Some(currency),
+                Err(e) => {
+                    let reason = e.to_string();
+                    return Err(TransactionError::InvalidCurrency { tx, reason });
+                }
+            },
+            None => None,
         };
         Ok(Mutation {
             id: self.tx,
             kind: self.kind,
             client: self.client,
-            amount: self.amount.map(|a| (a * 10_000.0) as u32),
+            amount,
+            currency,
+            to_client: self.to_client,
         })
     }
 }
+
+/// Collects the rows and sorts them by their timestamp. A row without a timestamp, or one that
+/// could not be read, is taken to happen at the same time as the row before it, so it stays right
+/// behind that row. Rows without a timestamp at the start of the input go first. The sort is
+/// stable, so rows with the same timestamp keep their input order.
+pub fn sort_chronologically<I>(rows: I) -> Vec<csv::Result<CsvRow>>
+where
+    I: Iterator<Item = csv::Result<CsvRow>>,
+{
+    let mut last = 0;
+    let mut rows: Vec<_> = rows
+        .map(|row| {
+            if let Ok(Some(timestamp)) = row.as_ref().map(CsvRow::timestamp) {
+                last = timestamp;
+            }
+            (last, row)
+        })
+        .collect();
+    rows.sort_by_key(|(timestamp, _)| *timestamp);
+    rows.into_iter().map(|(_, row)| row).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(amount: &str) -> CsvRow {
+        CsvRow {
+            kind: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(amount.to_string()),
+            timestamp: None,
+            currency: None,
+            to_client: None,
+        }
+    }
+
+    #[test]
+    fn test_positive_amount() {
+        let mutation = deposit("1.0")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap();
+        assert_eq!(mutation.amount, Some(Amount(10_000)));
+    }
+
+    #[test]
+    fn test_negative_amount() {
+        let err = deposit("-1.0")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, -1.0 is negative, amounts must be positive"
+        );
+    }
+
+    #[test]
+    fn test_adjustment_amount() {
+        let adjustment = |amount: &str| CsvRow {
+            kind: TransactionType::Adjustment,
+            ..deposit(amount)
+        };
+        let mutation = adjustment("-1.5")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap();
+        assert_eq!(mutation.amount, Some(Amount(-15_000)));
+        let mutation = adjustment("1.5")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap();
+        assert_eq!(mutation.amount, Some(Amount(15_000)));
+        let err = adjustment("-0")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, adjustments can not be zero"
+        );
+    }
+
+    #[test]
+    fn test_zero_amount() {
+        let err = deposit("0.0")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, amounts must be positive"
+        );
+    }
+
+    #[test]
+    fn test_non_finite_amount() {
+        for amount in ["NaN", "nan", "inf", "-inf", "+inf", "infinity"] {
+            let err = deposit(amount)
+                .as_mutation(4, false, Rounding::Reject)
+                .unwrap_err();
+            let expected = format!("Error parsing transaction 1, {amount} is not a finite amount");
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_huge_amount() {
+        // An amount that does not fit is an error, rather than being capped at the largest amount.
+        let err = deposit("1000000000000000")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, 1000000000000000 is too large, the largest amount is \
+            922337203685477.5807"
+        );
+        let mutation = deposit("1000000000000")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap();
+        assert_eq!(mutation.amount, Some(Amount(10_000_000_000_000_000)));
+    }
+
+    #[test]
+    fn test_excess_decimals() {
+        // Amounts are parsed from their digits, so an amount with more decimals than we maintain is
+        // flagged rather than truncated.
+        let mutation = deposit("1.2345")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap();
+        assert_eq!(mutation.amount, Some(Amount(12_345)));
+        let err = deposit("1.23456")
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, 1.23456 has more than 4 decimals"
+        );
+    }
+
+    #[test]
+    fn test_amount_presence() {
+        let row = CsvRow {
+            amount: None,
+            ..deposit("1.0")
+        };
+        let kind = TransactionType::Deposit;
+        let err = row.as_mutation(4, false, Rounding::Reject).unwrap_err();
+        assert_eq!(err, TransactionError::MissingAmount { tx: 1, kind });
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, deposits must have an amount"
+        );
+
+        let row = CsvRow {
+            kind: TransactionType::Dispute,
+            ..deposit("1.0")
+        };
+        let kind = TransactionType::Dispute;
+        let err = row.as_mutation(4, false, Rounding::Reject).unwrap_err();
+        assert_eq!(err, TransactionError::UnexpectedAmount { tx: 1, kind });
+
+        let row = || CsvRow {
+            kind: TransactionType::Chargeback,
+            ..deposit("1.0")
+        };
+        assert!(row().as_mutation(4, false, Rounding::Reject).is_err());
+        let mutation = row().as_mutation(4, true, Rounding::Reject).unwrap();
+        assert_eq!(mutation.amount, Some(Amount(10_000)));
+    }
+
+    #[test]
+    fn test_recipient_presence() {
+        let transfer = CsvRow {
+            kind: TransactionType::Transfer,
+            ..deposit("1.0")
+        };
+        let err = transfer
+            .as_mutation(4, false, Rounding::Reject)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, transfers must have a to_client"
+        );
+        let transfer = CsvRow {
+            kind: TransactionType::Transfer,
+            to_client: Some(2),
+            ..deposit("1.0")
+        };
+        let mutation = transfer.as_mutation(4, false, Rounding::Reject).unwrap();
+        assert_eq!(mutation.to_client, Some(2));
+
+        let row = CsvRow {
+            to_client: Some(2),
+            ..deposit("1.0")
+        };
+        let err = row.as_mutation(4, false, Rounding::Reject).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error parsing transaction 1, deposits may not have a to_client"
+        );
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        let valid = csv::StringRecord::from(vec!["tx", "type", "client", "amount"]);
+        assert!(validate_headers(&valid).is_ok());
+        let extra = csv::StringRecord::from(vec!["type", "client", "tx", "amount", "memo"]);
+        assert!(validate_headers(&extra).is_ok());
+        let misspelled = csv::StringRecord::from(vec!["type", "client", "tx", "ammount"]);
+        assert_eq!(
+            validate_headers(&misspelled).unwrap_err().to_string(),
+            "The input has an invalid header, it is missing the columns [\"amount\"] and has the \
+            unexpected columns [\"ammount\"], expected the columns \
+            [\"type\", \"client\", \"tx\", \"amount\"]"
+        );
+        assert_eq!(
+            validate_headers(&csv::StringRecord::new())
+                .unwrap_err()
+                .to_string(),
+            "The input is empty, it is missing a header with the columns \
+            [\"type\", \"client\", \"tx\", \"amount\"]"
+        );
+    }
+
+    #[test]
+    fn test_kind_casing() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.0\n\
+            Deposit,1,2,1.0\n\
+            DEPOSIT,1,3,1.0\n\
+            deposit ,1,4,1.0\n\
+            ChargeBack,1,5,\n";
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        let kinds: Vec<_> = (reader.deserialize::<CsvRow>())
+            .map(|row| row.unwrap().kind)
+            .collect();
+        use TransactionType::*;
+        assert_eq!(kinds, [Deposit, Deposit, Deposit, Deposit, Chargeback]);
+
+        let input = "type,client,tx,amount\nrefund,1,1,1.0\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        assert!(reader.deserialize::<CsvRow>().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_sort_chronologically() {
+        let input = "type,client,tx,amount,timestamp\n\
+            dispute,1,1,,20\n\
+            resolve,1,1,,\n\
+            deposit,1,1,1.0,10\n\
+            deposit,1,2,1.0,20\n\
+            deposit,1,3,1.0,5\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        assert!(validate_headers(reader.headers().unwrap()).is_ok());
+        let txs: Vec<_> = sort_chronologically(reader.deserialize())
+            .into_iter()
+            .map(|row| row.unwrap())
+            .map(|row| (row.tx, row.kind))
+            .collect();
+        use TransactionType::*;
+        assert_eq!(
+            txs,
+            [
+                (3, Deposit),
+                (1, Deposit),
+                (1, Dispute),
+                (1, Resolve),
+                (2, Deposit)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reordered_and_extra_columns() {
+        let input = "tx,type,amount,client,note\n\
+            1,deposit,1.5,3,first deposit\n\
+            1,dispute,,3,\n\
+            2,withdrawal,0.5,3,,unnamed\n";
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        assert!(validate_headers(reader.headers().unwrap()).is_ok());
+        let mutations: Vec<_> = (reader.deserialize::<CsvRow>())
+            .map(|row| {
+                row.unwrap()
+                    .as_mutation(4, false, Rounding::Reject)
+                    .unwrap()
+            })
+            .collect();
+        use TransactionType::*;
+        let expected = [
+            (1, Deposit, 3, Some(Amount(15_000))),
+            (1, Dispute, 3, None),
+            (2, Withdrawal, 3, Some(Amount(5_000))),
+        ];
+        let expected = expected.map(|(id, kind, client, amount)| Mutation {
+            id,
+            kind,
+            client,
+            amount,
+            currency: None,
+            to_client: None,
+        });
+        assert_eq!(mutations, expected);
+    }
+
+    #[test]
+    fn test_currency() {
+        let row = || CsvRow {
+            currency: Some("usd".to_string()),
+            ..deposit("1.0")
+        };
+        let mutation = row().as_mutation(4, false, Rounding::Reject).unwrap();
+        assert_eq!(mutation.currency, Some("USD".parse().unwrap()));
+        let row = CsvRow {
+            currency: Some("dollar".to_string()),
+            ..row()
+        };
+        assert_eq!(
+            row.as_mutation(4, false, Rounding::Reject)
+                .unwrap_err()
+                .to_string(),
+            "Error parsing transaction 1, dollar is not a valid currency code"
+        );
+    }
+}
@@ -1,27 +1,661 @@
-use crate::account::Account;
+use crate::{
+    account::Account,
+    amount::{format_decimal, Currency},
+};
+use eyre::{eyre, Report, Result};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// How the locked state of an account is written in csv output, for consumers that can not parse
+/// boolean literals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockedFormat {
+    /// `true` or `false`, which is the default.
+    Bool,
+    /// `frozen` or `active`.
+    Word,
+    /// `1` or `0`.
+    Digit,
+}
+
+impl LockedFormat {
+    /// Every format along with its name, which is the locked and the unlocked representation.
+    const NAMES: [(LockedFormat, &'static str); 3] = [
+        (LockedFormat::Bool, "true/false"),
+        (LockedFormat::Word, "frozen/active"),
+        (LockedFormat::Digit, "1/0"),
+    ];
+
+    /// Presents the locked state in this format.
+    pub fn format(self, locked: bool) -> &'static str {
+        match (self, locked) {
+            (LockedFormat::Bool, true) => "true",
+            (LockedFormat::Bool, false) => "false",
+            (LockedFormat::Word, true) => "frozen",
+            (LockedFormat::Word, false) => "active",
+            (LockedFormat::Digit, true) => "1",
+            (LockedFormat::Digit, false) => "0",
+        }
+    }
+}
+
+impl Default for LockedFormat {
+    fn default() -> Self {
+        LockedFormat::Bool
+    }
+}
+
+impl FromStr for LockedFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        (LockedFormat::NAMES.iter())
+            .find(|(_, name)| *name == s)
+            .map(|&(format, _)| format)
+            .ok_or_else(|| {
+                eyre!("Unknown locked format {s}, expected true/false, frozen/active or 1/0")
+            })
+    }
+}
 
 #[derive(serde::Serialize)]
 pub struct CsvRow {
-    client: u16,
+    client: u32,
     available: String,
     held: String,
     total: String,
-    locked: bool,
+    locked: &'static str,
+    open_disputes: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ever_disputed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    under_review: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_row: Option<u64>,
 }
 
 impl CsvRow {
-    pub fn from_account(acc: Account) -> Self {
+    /// Presents the balances of the account with `precision` decimals, and the locked state in the
+    /// default [LockedFormat].
+    pub fn from_account(acc: Account, precision: u32) -> Self {
+        // On debug mode, perform a sanity check before printing.
+        debug_assert_eq!(Some(acc.total), acc.available.checked_add(acc.held));
+        Self {
+            client: acc.client,
+            available: acc.available.to_decimal_string(precision),
+            held: acc.held.to_decimal_string(precision),
+            total: acc.total.to_decimal_string(precision),
+            locked: LockedFormat::default().format(acc.locked),
+            open_disputes: acc.open_disputes,
+            ever_disputed: None,
+            under_review: None,
+            last_row: None,
+        }
+    }
+
+    /// Presents the locked state of the account in `format` instead.
+    pub fn with_locked(self, acc: &Account, format: LockedFormat) -> Self {
+        let locked = format.format(acc.locked);
+        Self { locked, ..self }
+    }
+
+    /// Extends the row with the risk flags of the account, see [Account::ever_disputed] and
+    /// [Account::under_review].
+    pub fn with_flags(self, acc: &Account) -> Self {
+        let ever_disputed = Some(acc.ever_disputed);
+        let under_review = Some(acc.under_review);
+        Self {
+            ever_disputed,
+            under_review,
+            ..self
+        }
+    }
+
+    /// Extends the row with the number of the row that last changed the account, see
+    /// [Account::last_row].
+    pub fn with_last_row(self, acc: &Account) -> Self {
+        let last_row = Some(acc.last_row);
+        Self { last_row, ..self }
+    }
+}
+
+/// The JSON counterpart of [CsvRow]. Here we present the balances as numbers rather than formatted
+/// strings, since JSON consumers are expected to work with the values directly.
+#[derive(serde::Serialize)]
+pub struct JsonRow {
+    client: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    open_disputes: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ever_disputed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    under_review: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_row: Option<u64>,
+}
+
+impl JsonRow {
+    /// Presents the balances of the account with `precision` decimals.
+    pub fn from_account(acc: Account, precision: u32) -> Self {
         // On debug mode, perform a sanity check before printing.
-        debug_assert_eq!(acc.total, acc.available + acc.held);
-        let available = acc.available as f64 / 10_000.0;
-        let held = acc.held as f64 / 10_000.0;
-        let total = acc.total as f64 / 10_000.0;
+        debug_assert_eq!(Some(acc.total), acc.available.checked_add(acc.held));
         Self {
             client: acc.client,
-            available: format!("{available:.4}"),
-            held: format!("{held:.4}"),
-            total: format!("{total:.4}"),
+            currency: acc.currency,
+            available: acc.available.to_f64(precision),
+            held: acc.held.to_f64(precision),
+            total: acc.total.to_f64(precision),
             locked: acc.locked,
+            open_disputes: acc.open_disputes,
+            ever_disputed: None,
+            under_review: None,
+            last_row: None,
+        }
+    }
+
+    /// Extends the row with the risk flags of the account, see [Account::ever_disputed] and
+    /// [Account::under_review].
+    pub fn with_flags(self, acc: &Account) -> Self {
+        let ever_disputed = Some(acc.ever_disputed);
+        let under_review = Some(acc.under_review);
+        Self {
+            ever_disputed,
+            under_review,
+            ..self
+        }
+    }
+
+    /// Extends the row with the number of the row that last changed the account, see
+    /// [Account::last_row].
+    pub fn with_last_row(self, acc: &Account) -> Self {
+        let last_row = Some(acc.last_row);
+        Self { last_row, ..self }
+    }
+}
+
+/// A [CsvRow] that is extended with statistics about the activity of the client.
+#[derive(serde::Serialize)]
+pub struct StatsCsvRow {
+    client: u32,
+    available: String,
+    held: String,
+    total: String,
+    locked: &'static str,
+    open_disputes: u32,
+    deposits: u64,
+    withdrawals: u64,
+    deposited: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ever_disputed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    under_review: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_row: Option<u64>,
+}
+
+impl StatsCsvRow {
+    /// Presents the balances and statistics of the account with `precision` decimals.
+    pub fn from_account(acc: Account, precision: u32) -> Self {
+        let (deposits, withdrawals) = (acc.deposits, acc.withdrawals);
+        let deposited = acc.deposited.to_decimal_string(precision);
+        let row = CsvRow::from_account(acc, precision);
+        Self {
+            client: row.client,
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+            open_disputes: row.open_disputes,
+            deposits,
+            withdrawals,
+            deposited,
+            ever_disputed: row.ever_disputed,
+            under_review: row.under_review,
+            last_row: row.last_row,
+        }
+    }
+
+    /// Presents the locked state of the account in `format` instead.
+    pub fn with_locked(self, acc: &Account, format: LockedFormat) -> Self {
+        let locked = format.format(acc.locked);
+        Self { locked, ..self }
+    }
+
+    /// Extends the row with the risk flags of the account, see [Account::ever_disputed] and
+    /// [Account::under_review].
+    pub fn with_flags(self, acc: &Account) -> Self {
+        let ever_disputed = Some(acc.ever_disputed);
+        let under_review = Some(acc.under_review);
+        Self {
+            ever_disputed,
+            under_review,
+            ..self
+        }
+    }
+
+    /// Extends the row with the number of the row that last changed the account, see
+    /// [Account::last_row].
+    pub fn with_last_row(self, acc: &Account) -> Self {
+        let last_row = Some(acc.last_row);
+        Self { last_row, ..self }
+    }
+}
+
+/// A [JsonRow] that is extended with statistics about the activity of the client.
+#[derive(serde::Serialize)]
+pub struct StatsJsonRow {
+    #[serde(flatten)]
+    row: JsonRow,
+    deposits: u64,
+    withdrawals: u64,
+    deposited: f64,
+}
+
+impl StatsJsonRow {
+    /// Presents the balances and statistics of the account with `precision` decimals.
+    pub fn from_account(acc: Account, precision: u32) -> Self {
+        Self {
+            deposits: acc.deposits,
+            withdrawals: acc.withdrawals,
+            deposited: acc.deposited.to_f64(precision),
+            row: JsonRow::from_account(acc, precision),
         }
     }
+
+    /// Extends the row with the risk flags of the account, see [Account::ever_disputed] and
+    /// [Account::under_review].
+    pub fn with_flags(self, acc: &Account) -> Self {
+        let row = self.row.with_flags(acc);
+        Self { row, ..self }
+    }
+
+    /// Extends the row with the number of the row that last changed the account, see
+    /// [Account::last_row].
+    pub fn with_last_row(self, acc: &Account) -> Self {
+        let row = self.row.with_last_row(acc);
+        Self { row, ..self }
+    }
+}
+
+/// The columns that a custom [CsvLayout] can consist of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Client,
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+    OpenDisputes,
+    Deposits,
+    Withdrawals,
+    Deposited,
+    EverDisputed,
+    UnderReview,
+    LastRow,
+}
+
+impl Column {
+    /// Every column along with its name, which is also the header that it gets by default.
+    const NAMES: [(Column, &'static str); 13] = [
+        (Column::Client, "client"),
+        (Column::Currency, "currency"),
+        (Column::Available, "available"),
+        (Column::Held, "held"),
+        (Column::Total, "total"),
+        (Column::Locked, "locked"),
+        (Column::OpenDisputes, "open_disputes"),
+        (Column::Deposits, "deposits"),
+        (Column::Withdrawals, "withdrawals"),
+        (Column::Deposited, "deposited"),
+        (Column::EverDisputed, "ever_disputed"),
+        (Column::UnderReview, "under_review"),
+        (Column::LastRow, "last_row"),
+    ];
+
+    /// The header of this column by default.
+    fn name(self) -> &'static str {
+        (Column::NAMES.iter())
+            .find(|(column, _)| *column == self)
+            .map_or("", |(_, name)| *name)
+    }
+
+    /// Formats the value of this column for the account, presenting amounts with `precision`
+    /// decimals and the locked state in `locked`.
+    fn value(self, acc: &Account, precision: u32, locked: LockedFormat) -> String {
+        match self {
+            Column::Client => acc.client.to_string(),
+            Column::Currency => acc.currency.map(|c| c.to_string()).unwrap_or_default(),
+            Column::Available => acc.available.to_decimal_string(precision),
+            Column::Held => acc.held.to_decimal_string(precision),
+            Column::Total => acc.total.to_decimal_string(precision),
+            Column::Locked => locked.format(acc.locked).to_string(),
+            Column::OpenDisputes => acc.open_disputes.to_string(),
+            Column::Deposits => acc.deposits.to_string(),
+            Column::Withdrawals => acc.withdrawals.to_string(),
+            Column::Deposited => acc.deposited.to_decimal_string(precision),
+            Column::EverDisputed => acc.ever_disputed.to_string(),
+            Column::UnderReview => acc.under_review.to_string(),
+            Column::LastRow => acc.last_row.to_string(),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        (Column::NAMES.iter())
+            .find(|(_, name)| *name == s)
+            .map(|&(column, _)| column)
+            .ok_or_else(|| eyre!("Unknown column {s}"))
+    }
+}
+
+/// A csv layout with a custom order of the columns and custom headers, for consumers that can not
+/// work with the layout of [CsvRow].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvLayout {
+    /// The columns in the order in which they are written, along with their headers.
+    pub columns: Vec<(Column, String)>,
+    /// How the locked column is written.
+    pub locked: LockedFormat,
+}
+
+impl CsvLayout {
+    /// The layout `Client,Total,Available,Held,Locked`, which some ledger systems expect.
+    pub fn ledger() -> Self {
+        let columns = [
+            (Column::Client, "Client"),
+            (Column::Total, "Total"),
+            (Column::Available, "Available"),
+            (Column::Held, "Held"),
+            (Column::Locked, "Locked"),
+        ];
+        let columns = columns.map(|(column, header)| (column, header.to_string()));
+        Self {
+            columns: columns.to_vec(),
+            locked: LockedFormat::default(),
+        }
+    }
+
+    /// The columns of [CsvRow], or of [StatsCsvRow] when `stats` is set.
+    pub fn standard(stats: bool) -> Self {
+        let mut layout = Self::with_currency(stats);
+        layout
+            .columns
+            .retain(|(column, _)| *column != Column::Currency);
+        layout
+    }
+
+    /// The columns of [CsvRow], or of [StatsCsvRow] when `stats` is set, with a currency column
+    /// after the client. This is the layout for accounts that have a currency.
+    pub fn with_currency(stats: bool) -> Self {
+        use Column::*;
+        let mut columns = vec![
+            Client,
+            Currency,
+            Available,
+            Held,
+            Total,
+            Locked,
+            OpenDisputes,
+        ];
+        if stats {
+            columns.extend([Deposits, Withdrawals, Deposited]);
+        }
+        let columns = (columns.into_iter())
+            .map(|column| (column, column.name().to_string()))
+            .collect();
+        let locked = LockedFormat::default();
+        Self { columns, locked }
+    }
+
+    /// Adds the columns of the risk flags to the end of this layout, which is how
+    /// [CsvRow::with_flags] extends the standard layout.
+    pub fn with_flags(mut self) -> Self {
+        for column in [Column::EverDisputed, Column::UnderReview] {
+            self.columns.push((column, column.name().to_string()));
+        }
+        self
+    }
+
+    /// Adds the column of the last row to the end of this layout, which is how
+    /// [CsvRow::with_last_row] extends the standard layout.
+    pub fn with_last_row(mut self) -> Self {
+        let column = Column::LastRow;
+        self.columns.push((column, column.name().to_string()));
+        self
+    }
+
+    /// Writes the locked column in `locked` instead.
+    pub fn with_locked(self, locked: LockedFormat) -> Self {
+        Self { locked, ..self }
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|(_, header)| header.as_str())
+    }
+
+    /// Presents the account in this layout, with `precision` decimals.
+    pub fn row(&self, acc: &Account, precision: u32) -> Vec<String> {
+        (self.columns.iter())
+            .map(|(column, _)| column.value(acc, precision, self.locked))
+            .collect()
+    }
+}
+
+/// The sums of the columns over a number of accounts in the same currency, which is written after
+/// the accounts as a quick check of the output.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Totals {
+    pub currency: Option<Currency>,
+    /// The balances are summed as an `i128`, so that the sum of many large balances can not
+    /// overflow.
+    pub available: i128,
+    pub held: i128,
+    pub total: i128,
+    /// The number of accounts that are locked.
+    pub locked: u64,
+    pub open_disputes: u64,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub deposited: i128,
+    /// The number of accounts that have ever been disputed.
+    pub ever_disputed: u64,
+    /// The number of accounts that are marked for review.
+    pub under_review: u64,
+}
+
+impl Totals {
+    /// Sums the accounts per currency, in the order of the currencies. Adding up the balances of
+    /// different currencies would be meaningless, so there is a separate sum for every currency.
+    pub fn per_currency<'a>(accounts: impl IntoIterator<Item = &'a Account>) -> Vec<Totals> {
+        let mut totals = BTreeMap::new();
+        for acc in accounts {
+            let sum = (totals.entry(acc.currency)).or_insert_with(|| Totals {
+                currency: acc.currency,
+                ..Default::default()
+            });
+            sum.available += i128::from(acc.available.0);
+            sum.held += i128::from(acc.held.0);
+            sum.total += i128::from(acc.total.0);
+            sum.locked += u64::from(acc.locked);
+            sum.open_disputes += u64::from(acc.open_disputes);
+            sum.deposits += acc.deposits;
+            sum.withdrawals += acc.withdrawals;
+            sum.deposited += i128::from(acc.deposited.0);
+            sum.ever_disputed += u64::from(acc.ever_disputed);
+            sum.under_review += u64::from(acc.under_review);
+        }
+        totals.into_values().collect()
+    }
+
+    /// Presents the sums in `layout`, with `precision` decimals. The client column says `total`,
+    /// and the columns of booleans, such as locked, hold the number of accounts that are set.
+    pub fn row(&self, layout: &CsvLayout, precision: u32) -> Vec<String> {
+        let value = |column: Column| match column {
+            Column::Client => "total".to_string(),
+            Column::Currency => self.currency.map(|c| c.to_string()).unwrap_or_default(),
+            Column::Available => format_decimal(self.available, precision),
+            Column::Held => format_decimal(self.held, precision),
+            Column::Total => format_decimal(self.total, precision),
+            Column::Locked => self.locked.to_string(),
+            Column::OpenDisputes => self.open_disputes.to_string(),
+            Column::Deposits => self.deposits.to_string(),
+            Column::Withdrawals => self.withdrawals.to_string(),
+            Column::Deposited => format_decimal(self.deposited, precision),
+            Column::EverDisputed => self.ever_disputed.to_string(),
+            Column::UnderReview => self.under_review.to_string(),
+            // The last row of many accounts does not sum to anything meaningful.
+            Column::LastRow => String::new(),
+        };
+        (layout.columns.iter())
+            .map(|(column, _)| value(*column))
+            .collect()
+    }
+}
+
+/// Parses either the name of a preset, which is `ledger`, or a comma separated list of columns.
+/// Each column may be followed by a colon and the header to use for it, such as `total:Balance`.
+impl FromStr for CsvLayout {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "ledger" {
+            return Ok(Self::ledger());
+        }
+        let columns = s
+            .split(',')
+            .map(|column| {
+                let (name, header) = column.split_once(':').unwrap_or((column, column));
+                Ok((name.parse()?, header.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let locked = LockedFormat::default();
+        Ok(Self { columns, locked })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    #[test]
+    fn test_layout() {
+        let layout: CsvLayout = "total:Balance,client,locked".parse().unwrap();
+        assert_eq!(
+            layout.headers().collect::<Vec<_>>(),
+            ["Balance", "client", "locked"]
+        );
+        let account = Account {
+            available: Amount(15_000),
+            total: Amount(15_000),
+            ..Account::new(3)
+        };
+        assert_eq!(layout.row(&account, 4), ["1.5000", "3", "false"]);
+
+        assert_eq!("ledger".parse::<CsvLayout>().unwrap(), CsvLayout::ledger());
+        assert!("client,balance".parse::<CsvLayout>().is_err());
+        assert!("".parse::<CsvLayout>().is_err());
+    }
+
+    #[test]
+    fn test_locked_format() {
+        let account = Account {
+            locked: true,
+            ..Account::new(1)
+        };
+        let formats = [
+            ("true/false", "true", "false"),
+            ("frozen/active", "frozen", "active"),
+            ("1/0", "1", "0"),
+        ];
+        for (name, locked, unlocked) in formats {
+            let format: LockedFormat = name.parse().unwrap();
+            let row = CsvRow::from_account(account, 4).with_locked(&account, format);
+            assert_eq!(row.locked, locked);
+            let row =
+                CsvRow::from_account(Account::new(2), 4).with_locked(&Account::new(2), format);
+            assert_eq!(row.locked, unlocked);
+
+            let layout = CsvLayout::ledger().with_locked(format);
+            assert_eq!(layout.row(&account, 4)[4], locked);
+            assert_eq!(layout.row(&Account::new(2), 4)[4], unlocked);
+        }
+        assert_eq!(LockedFormat::default(), LockedFormat::Bool);
+        assert!("yes/no".parse::<LockedFormat>().is_err());
+    }
+
+    #[test]
+    fn test_totals() {
+        let account = |client, available, held, locked| Account {
+            available: Amount(available),
+            held: Amount(held),
+            total: Amount(available + held),
+            locked,
+            open_disputes: u32::from(held > 0),
+            ..Account::new(client)
+        };
+        let accounts = [
+            account(1, 15_000, 5_000, false),
+            account(2, -2_500, 0, true),
+            account(3, i64::MAX, 0, false),
+            account(4, 1, 2, true),
+        ];
+        let totals = Totals::per_currency(&accounts);
+        assert_eq!(totals.len(), 1);
+        let layout = CsvLayout::standard(false);
+        assert_eq!(
+            totals[0].row(&layout, 4),
+            [
+                "total",
+                "922337203685478.8308",
+                "0.5002",
+                "922337203685479.3310",
+                "2",
+                "2"
+            ]
+        );
+
+        let usd = "USD".parse().unwrap();
+        let accounts = [accounts[0], accounts[1].in_currency(Some(usd))];
+        let totals = Totals::per_currency(&accounts);
+        let layout = CsvLayout::with_currency(false);
+        assert_eq!(totals[0].row(&layout, 2)[..3], ["total", "", "150.00"]);
+        assert_eq!(totals[1].row(&layout, 2)[..3], ["total", "USD", "-25.00"]);
+    }
+
+    #[test]
+    fn test_negative_balances() {
+        // With an overdraft, `available` and `total` can be drawn below zero.
+        let row = |available, held| {
+            let account = Account {
+                available: Amount(available),
+                held: Amount(held),
+                total: Amount(available + held),
+                ..Account::new(1)
+            };
+            let row = CsvRow::from_account(account, 4);
+            (row.available, row.held, row.total)
+        };
+        assert_eq!(
+            row(-1, 0),
+            ("-0.0001".into(), "0.0000".into(), "-0.0001".into())
+        );
+        assert_eq!(
+            row(-123_400, 0),
+            ("-12.3400".into(), "0.0000".into(), "-12.3400".into())
+        );
+        // A total just below zero keeps its sign, even though its integer part is zero.
+        assert_eq!(
+            row(-20_000, 19_999),
+            ("-2.0000".into(), "1.9999".into(), "-0.0001".into())
+        );
+    }
 }
@@ -0,0 +1,230 @@
+//! The engine does not read csv itself, it consumes a [TransactionSource], which yields one
+//! [Mutation] at a time. The csv input is read by a [CsvSource], but other formats, such as a
+//! binary transaction log, only need to implement the trait to be fed into the engine with
+//! [ingest_source](crate::engine::ingest_source).
+
+use crate::{
+    amount::Rounding,
+    engine::{EngineConfig, Incident},
+    parse::{self, CsvRow},
+    transaction::Mutation,
+};
+use eyre::{eyre, Result};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// A source of mutations for the engine.
+pub trait TransactionSource {
+    /// Returns the next mutation, or `None` when the source is exhausted. A row that can not be
+    /// turned into a mutation is returned as a failed [Incident] instead, with the transaction and
+    /// client when they are known, after which the source is expected to continue with the next
+    /// row, so that a lenient run can skip it.
+    fn next_mutation(&mut self) -> Option<Result<Mutation, Incident>>;
+
+    /// Whether the source may return transfers. A transfer debits one client and credits another
+    /// in a single step, so the mutations of a source with transfers are processed on a single
+    /// thread, regardless of [EngineConfig::threads]. A source that can not tell says it may.
+    fn has_transfers(&self) -> bool {
+        true
+    }
+}
+
+/// Reads the mutations from csv data.
+pub struct CsvSource<'a> {
+    rows: Box<dyn Iterator<Item = csv::Result<CsvRow>> + 'a>,
+    precision: u32,
+    partial_chargebacks: bool,
+    rounding: Rounding,
+    /// Whether the header has a `to_client` column, without which there are no transfers.
+    transfers: bool,
+}
+
+impl<'a> CsvSource<'a> {
+    /// Reads the header of `input` and checks that it has all of the [parse::COLUMNS]. The
+    /// amounts are parsed following `config`, and when [EngineConfig::chronological] is set all
+    /// rows are read and sorted up front.
+    pub fn new<R: Read + 'a>(input: R, config: &EngineConfig) -> Result<Self> {
+        // Get a csv reader for the provided input. It is flexible, so that a row may have trailing
+        // columns beyond the header, like a memo that the header does not name.
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .delimiter(config.delimiter)
+            .buffer_capacity(config.buffer_size)
+            .from_reader(input);
+        parse::validate_headers(reader.headers()?)?;
+        let transfers = reader.headers()?.iter().any(|header| header == "to_client");
+        // We never need to read more than one row beyond the maximum to know that it is exceeded,
+        // which also bounds the memory that sorting the rows takes.
+        let max_rows = (config.max_rows.map(|max| max.saturating_add(1)))
+            .map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(usize::MAX));
+        let rows = reader.into_deserialize().take(max_rows);
+        let rows: Box<dyn Iterator<Item = _>> = match config.chronological {
+            true => Box::new(parse::sort_chronologically(rows).into_iter()),
+            false => Box::new(rows),
+        };
+        Ok(Self {
+            rows,
+            precision: config.input_precision(),
+            partial_chargebacks: config.policy.partial_chargebacks,
+            rounding: config.rounding,
+            transfers,
+        })
+    }
+}
+
+impl TransactionSource for CsvSource<'_> {
+    fn next_mutation(&mut self) -> Option<Result<Mutation, Incident>> {
+        let record = match self.rows.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(Incident::failed(None, None, describe(&e)))),
+        };
+        let (tx, client) = (record.tx(), record.client());
+        let mutation = record
+            .as_mutation(self.precision, self.partial_chargebacks, self.rounding)
+            .map_err(|e| Incident::failed(Some(tx), Some(client), e));
+        Some(mutation)
+    }
+
+    fn has_transfers(&self) -> bool {
+        self.transfers
+    }
+}
+
+/// Describes a row that could not be read. For a row with an invalid value, such as an unknown
+/// transaction type, this is the number of the row and the problem with the value, which is more
+/// to the point than the error of the csv reader. The header is not counted, so the first row
+/// after the header is row 1.
+fn describe(error: &csv::Error) -> String {
+    match error.kind() {
+        csv::ErrorKind::Deserialize {
+            pos: Some(pos),
+            err,
+        } => {
+            format!("Row {}: {}", pos.record(), err.kind())
+        }
+        _ => error.to_string(),
+    }
+}
+
+/// Lists the csv files in `dir` sorted by their name, so that they can be processed one after the
+/// other as a single input. For files that are named after a zero-padded timestamp, such as
+/// `2022-06-01T09.csv`, this is chronological order. Compressed files ending in `.csv.gz` count as
+/// csv files as well. Any other entry, including a directory, is skipped when `skip_other` is set,
+/// and an error otherwise.
+pub fn csv_files(dir: &Path, skip_other: bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_csv = name.ends_with(".csv") || name.ends_with(".csv.gz");
+        if is_csv && entry.path().is_file() {
+            files.push(entry.path());
+        } else if skip_other {
+            tracing::warn!(name = %name, "Skipping an entry that is not a csv file");
+        } else {
+            return Err(eyre!("{} is not a csv file", entry.path().display()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Yields mutations that are already in memory, which is mostly useful in tests.
+impl TransactionSource for std::vec::IntoIter<Mutation> {
+    fn next_mutation(&mut self) -> Option<Result<Mutation, Incident>> {
+        self.next().map(Ok)
+    }
+
+    fn has_transfers(&self) -> bool {
+        self.as_slice().iter().any(|trx| trx.to_client.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        amount::Amount,
+        engine::{self, IncidentKind},
+        state::EngineState,
+        transaction::TransactionType,
+    };
+
+    #[test]
+    fn test_csv_source() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.5\n\
+            deposit,1,2,\n\
+            dispute,1,1,\n";
+        let config = EngineConfig::default();
+        let mut source = CsvSource::new(input.as_bytes(), &config).unwrap();
+        let mutation = source.next_mutation().unwrap().unwrap();
+        assert_eq!(mutation.kind, TransactionType::Deposit);
+        assert_eq!(mutation.amount, Some(Amount(25_000)));
+        // A row without an amount does not end the source.
+        let incident = source.next_mutation().unwrap().unwrap_err();
+        assert_eq!(incident.kind, IncidentKind::Failed);
+        assert_eq!((incident.tx, incident.client), (Some(2), Some(1)));
+        let mutation = source.next_mutation().unwrap().unwrap();
+        assert_eq!(mutation.kind, TransactionType::Dispute);
+        assert!(source.next_mutation().is_none());
+
+        assert!(CsvSource::new("type,client,id\n".as_bytes(), &config).is_err());
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.5\n\
+            refund,1,2,1.0\n\
+            deposit,1,3,1.0\n";
+        let expected = "Row 2: Unknown transaction type refund, expected one of deposit, \
+            withdrawal, dispute, resolve, chargeback, unlock, interest, fee, adjustment, transfer";
+        let mut state = EngineState::default();
+        let err = engine::ingest(&mut state, input.as_bytes(), &EngineConfig::default());
+        assert_eq!(err.unwrap_err().to_string(), expected);
+
+        // In lenient mode the row is skipped, and reported as an incident.
+        let config = EngineConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let mut state = EngineState::default();
+        let incidents = engine::ingest(&mut state, input.as_bytes(), &config).unwrap();
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].reason, expected);
+        assert_eq!(state.accounts.get(1, None).unwrap().total, Amount(35_000));
+    }
+
+    #[test]
+    fn test_vec_source() {
+        let mutation = |id, kind, amount| Mutation {
+            id,
+            kind,
+            client: 1,
+            amount,
+            currency: None,
+            to_client: None,
+        };
+        let source = vec![
+            mutation(1, TransactionType::Deposit, Some(Amount(25_000))),
+            mutation(2, TransactionType::Deposit, Some(Amount(10_000))),
+            mutation(1, TransactionType::Dispute, None),
+        ];
+        let mut state = EngineState::default();
+        let config = EngineConfig::default();
+        let (incidents, summary) =
+            engine::ingest_source(&mut state, source.into_iter(), &config).unwrap();
+        assert!(incidents.is_empty());
+        assert_eq!(summary.rows, 3);
+        let account = state.accounts.get(1, None).unwrap();
+        assert_eq!(account.available, Amount(10_000));
+        assert_eq!(account.held, Amount(25_000));
+        assert_eq!(account.total, Amount(35_000));
+    }
+}
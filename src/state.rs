@@ -0,0 +1,80 @@
+use crate::{account::Accounts, idempotency::IdempotencyLog, transaction::Transactions};
+use eyre::Result;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// All state that the engine maintains while processing transactions. The state can be written to
+/// a snapshot and read back later, so that a long running ingestion can be resumed without
+/// replaying the whole history.
+///
+/// The snapshot contains every recorded deposit and withdrawal together with its
+/// [TransactionStatus](crate::transaction::TransactionStatus). This means that the dispute flow
+/// carries over the snapshot boundary: a transaction that was disputed before the snapshot was
+/// taken can be resolved after resuming, and a transaction that was refunded before the snapshot
+/// can not be disputed again.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct EngineState {
+    pub accounts: Accounts,
+    pub trxs: Transactions,
+    /// The rows that were applied, when the state was processed with
+    /// [ingest_with_log](crate::engine::ingest_with_log). This is kept in the snapshot along with
+    /// the accounts, so that the two always match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied: Option<IdempotencyLog>,
+}
+
+impl EngineState {
+    /// Reads a snapshot that was written by [EngineState::write_snapshot].
+    pub fn read_snapshot<R: Read>(reader: R) -> Result<Self> {
+        let state = serde_json::from_reader(BufReader::new(reader))?;
+        Ok(state)
+    }
+
+    /// Writes the state as a JSON snapshot.
+    pub fn write_snapshot<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Removes all accounts and transactions, but keeps the allocated memory, so that one state can
+    /// process a number of independent inputs in turn. Nothing carries over from before the
+    /// reset: transaction ids may be used again and the accounts start out empty. Note that with
+    /// multiple [threads](crate::engine::EngineConfig::threads) the state is divided over the
+    /// workers and put back together, so it does not keep its memory in that case.
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+        self.trxs.clear();
+        self.applied = None;
+    }
+
+    /// Divides the state into `count` shards, where each client and its transactions end up in
+    /// the shard with index `client % count`. The log of applied rows is not part of any shard, so
+    /// it has to be taken out before.
+    pub(crate) fn split(self, count: usize) -> Vec<EngineState> {
+        let per_client = self.trxs.is_per_client();
+        let mut shards: Vec<_> = (0..count).map(|_| EngineState::default()).collect();
+        for shard in &mut shards {
+            // The shards are empty, so this can not fail on shared ids.
+            let _ = shard.trxs.set_per_client(per_client);
+        }
+        for account in self.accounts {
+            shards[account.client as usize % count]
+                .accounts
+                .extend(Some(account));
+        }
+        let mut trxs = self.trxs;
+        for trx in trxs.take_ordered() {
+            shards[trx.client as usize % count].trxs.record(trx);
+        }
+        shards
+    }
+
+    /// Moves all accounts and transactions of `other` into this state.
+    pub(crate) fn merge(&mut self, mut other: EngineState) {
+        self.accounts.merge(other.accounts);
+        for trx in other.trxs.take_ordered() {
+            self.trxs.record(trx);
+        }
+    }
+}
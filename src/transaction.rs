@@ -1,25 +1,189 @@
-use eyre::{eyre, Result};
+use crate::{
+    amount::{Amount, Currency},
+    error::{TransactionError, UnknownTransactionType},
+};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 
+/// Identifies a recorded transaction. When transaction ids are unique across all clients, this is
+/// `(None, id)`, but when every client has its own ids, it is `(Some(client), id)`.
+pub type Key = (Option<u32>, u32);
+
 /// A full collection of all transactions that we have visisted so far. It is sad that we need to
 /// maintain this data, but since Disputes, Resolves and Chargebacks do not actually contain
 /// information about the amounts that are involved, we are forced to. This facilitates looking up
 /// the previously ingested transaction by the transaction id.
 #[derive(Default)]
 pub struct Transactions {
-    /// A map from transaction id to the amount that that transaction contained. We use a HashMap
-    /// because we need to do many random lookups by id, so this gets us O(1) time for that
+    /// A map from transaction [Key] to the amount that that transaction contained. We use a
+    /// HashMap because we need to do many random lookups by id, so this gets us O(1) time for that
     /// operation.
-    trxs: HashMap<u32, Transaction>,
+    trxs: HashMap<Key, Transaction>,
+    /// The keys of the recorded transactions, from oldest to newest, which tells us which
+    /// transactions to evict first when the history is bounded.
+    order: VecDeque<Key>,
+    /// The maximum number of transactions that we retain, or `None` to retain all of them. This
+    /// is configuration rather than state, so it is not part of a snapshot.
+    limit: Option<usize>,
+    /// Whether transaction ids are only unique per client, in which case the same id may be used
+    /// by several clients. Unlike the limit, this is part of a snapshot, since it decides what a
+    /// dispute that is read after resuming refers to.
+    per_client: bool,
+}
+
+impl Transactions {
+    /// The key under which the transaction `id` of `client` is recorded.
+    fn key(&self, client: u32, id: u32) -> Key {
+        (self.per_client.then(|| client), id)
+    }
+
+    /// Looks up the transaction `id` that `client` references. When transaction ids are unique
+    /// across clients, this may return a transaction of another client, which the caller has to
+    /// reject.
+    pub fn get(&self, client: u32, id: u32) -> Option<&Transaction> {
+        self.trxs.get(&self.key(client, id))
+    }
+
+    /// Like [Transactions::get], but allows changing the status of the transaction.
+    pub fn get_mut(&mut self, client: u32, id: u32) -> Option<&mut Transaction> {
+        let key = self.key(client, id);
+        self.trxs.get_mut(&key)
+    }
+
+    /// Checks whether `trx` may be recorded. Pipelines that retry may deliver the same row twice,
+    /// so a transaction with the same id, kind, client, amount and currency as a recorded one is a
+    /// replay, for which this returns `true` and which should be skipped. A transaction that only
+    /// shares its id with a recorded one is a [DuplicateId](TransactionError::DuplicateId) error,
+    /// since recording it would clobber the original transaction and its dispute status.
+    pub fn check_replay(&self, trx: &Transaction) -> Result<bool, TransactionError> {
+        match self.get(trx.client, trx.id) {
+            None => Ok(false),
+            Some(recorded) => {
+                let same = |t: &Transaction| (t.id, t.kind, t.client, t.amount, t.currency);
+                if same(recorded) != same(trx) {
+                    return Err(TransactionError::DuplicateId { tx: trx.id });
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// Records a deposit or withdrawal, so that it can be disputed later on. When the history is
+    /// bounded, this evicts the oldest transactions that exceed the limit.
+    pub fn record(&mut self, trx: Transaction) {
+        let key = self.key(trx.client, trx.id);
+        self.order.push_back(key);
+        self.trxs.insert(key, trx);
+        self.evict();
+    }
+
+    /// Bounds the number of transactions that we retain to `limit`, evicting the oldest ones when
+    /// there are more. A dispute that references an evicted transaction is ignored, like a dispute
+    /// for an unknown transaction, and the id of an evicted transaction can be reused without
+    /// being detected. Disputed transactions are never evicted, since their held amount could
+    /// never be released otherwise, so the limit is exceeded by the number of open disputes at
+    /// most.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        self.evict();
+    }
+
+    /// Sets whether transaction ids are only unique per client. Some partners number the
+    /// transactions of every client separately, so the same id can legitimately appear for
+    /// different clients, and a dispute references the transaction of its own client. The recorded
+    /// transactions are keyed anew, which fails with a
+    /// [DuplicateId](TransactionError::DuplicateId) error when going back to unique ids while two
+    /// clients share an id.
+    pub fn set_per_client(&mut self, per_client: bool) -> Result<(), TransactionError> {
+        if self.per_client == per_client {
+            return Ok(());
+        }
+        // Check for shared ids up front, so that the transactions are left as they are on failure.
+        if !per_client {
+            let mut ids = HashSet::new();
+            if let Some(trx) = self.trxs.values().find(|trx| !ids.insert(trx.id)) {
+                return Err(TransactionError::DuplicateId { tx: trx.id });
+            }
+        }
+        // The transactions were within the limit before, so none are evicted.
+        let ordered = self.take_ordered();
+        self.per_client = per_client;
+        for trx in ordered {
+            self.record(trx);
+        }
+        Ok(())
+    }
+
+    /// Whether transaction ids are only unique per client, see [Transactions::set_per_client].
+    pub fn is_per_client(&self) -> bool {
+        self.per_client
+    }
+
+    fn evict(&mut self) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        // Disputed transactions are moved to the back of the queue, so we give up once we have
+        // seen every remaining transaction.
+        let mut skipped = 0;
+        while self.trxs.len() > limit && skipped < self.order.len() {
+            let key = match self.order.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            match self.trxs.get(&key) {
+                Some(trx) if trx.status == TransactionStatus::Disputed => {
+                    self.order.push_back(key);
+                    skipped += 1;
+                }
+                _ => {
+                    self.trxs.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Removes all transactions, but keeps the allocated memory and the limit, so that the
+    /// collection can be reused for an unrelated input. Unlike `clear` on the map that this derefs
+    /// to, this forgets the order of the transactions as well. The ids of the removed transactions
+    /// can be used again, and disputes that reference them are ignored as unknown.
+    pub fn clear(&mut self) {
+        self.trxs.clear();
+        self.order.clear();
+    }
+
+    /// Removes all transactions, from oldest to newest.
+    pub(crate) fn take_ordered(&mut self) -> Vec<Transaction> {
+        let mut ordered: Vec<_> = (self.order.drain(..))
+            .filter_map(|key| self.trxs.remove(&key))
+            .collect();
+        // Transactions that were inserted through `DerefMut` are not in the queue, so they go last.
+        ordered.extend(self.trxs.drain().map(|(_, trx)| trx));
+        ordered
+    }
+
+    /// The transactions from oldest to newest, like [Transactions::take_ordered], but without
+    /// removing them.
+    fn ordered(&self) -> Vec<&Transaction> {
+        let queued: HashSet<&Key> = self.order.iter().collect();
+        let mut ordered: Vec<_> = (self.order.iter())
+            .filter_map(|key| self.trxs.get(key))
+            .collect();
+        let unqueued = self.trxs.iter().filter(|(key, _)| !queued.contains(key));
+        ordered.extend(unqueued.map(|(_, trx)| trx));
+        ordered
+    }
 }
 
 /// We allow our dataset to be accessed as though it were a specially typed HashMap. For this reason
 /// we implement Deref and DerefMut for `Transactions`.
 impl Deref for Transactions {
-    type Target = HashMap<u32, Transaction>;
+    type Target = HashMap<Key, Transaction>;
 
     fn deref(&self) -> &Self::Target {
         &self.trxs
@@ -32,58 +196,256 @@ impl DerefMut for Transactions {
     }
 }
 
+/// In a snapshot the transactions are a list from oldest to newest, since a [Key] can not be a
+/// JSON object key, and the order in the list replaces the separate queue for the eviction order.
+impl serde::Serialize for Transactions {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        struct Ordered<'a>(&'a Transactions);
+
+        impl serde::Serialize for Ordered<'_> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.ordered())
+            }
+        }
+
+        let mut state = serializer.serialize_struct("Transactions", 2)?;
+        state.serialize_field("trxs", &Ordered(self))?;
+        state.serialize_field("per_client", &self.per_client)?;
+        state.end()
+    }
+}
+
+/// Older snapshots keep the transactions in a map by id, along with a queue of the ids from oldest
+/// to newest, which can still be read.
+impl<'de> serde::Deserialize<'de> for Transactions {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Recorded {
+            List(Vec<Transaction>),
+            ById(HashMap<String, Transaction>),
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Snapshot {
+            trxs: Recorded,
+            #[serde(default)]
+            order: Vec<u32>,
+            #[serde(default)]
+            per_client: bool,
+        }
+
+        let snapshot = <Snapshot as serde::Deserialize>::deserialize(deserializer)?;
+        let ordered = match snapshot.trxs {
+            Recorded::List(trxs) => trxs,
+            Recorded::ById(mut trxs) => {
+                let mut ordered: Vec<_> = (snapshot.order.iter())
+                    .filter_map(|id| trxs.remove(&id.to_string()))
+                    .collect();
+                ordered.extend(trxs.into_values());
+                ordered
+            }
+        };
+        let mut trxs = Transactions {
+            per_client: snapshot.per_client,
+            ..Default::default()
+        };
+        for trx in ordered {
+            trxs.record(trx);
+        }
+        Ok(trxs)
+    }
+}
+
 /// A transaction that has been performed.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     pub id: u32,
     pub kind: TransactionType,
-    pub client: u16,
-    pub amount: u32,
+    pub client: u32,
+    pub amount: Amount,
     pub status: TransactionStatus,
+    /// The currency of the amount, or `None` when the input does not have currencies.
+    #[serde(default)]
+    pub currency: Option<Currency>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Mutation {
     pub id: u32,
     pub kind: TransactionType,
-    pub client: u16,
-    pub amount: Option<u32>,
+    pub client: u32,
+    pub amount: Option<Amount>,
+    pub currency: Option<Currency>,
+    /// The client whose account a transfer credits, or `None` for every other kind.
+    pub to_client: Option<u32>,
+}
+
+impl Mutation {
+    /// The currency of the account that this mutation applies to. A dispute, resolve or chargeback
+    /// without a currency applies to the account of the transaction that it references, since
+    /// partners usually leave every column of these rows empty that they can.
+    pub fn account_currency(&self, trxs: &Transactions) -> Option<Currency> {
+        use TransactionType::*;
+        match (self.kind, self.currency) {
+            (Dispute | Resolve | Chargeback, None) => trxs.get(self.client, self.id)?.currency,
+            (_, currency) => currency,
+        }
+    }
 }
 
 impl TryInto<Transaction> for Mutation {
-    type Error = eyre::Report;
+    type Error = TransactionError;
 
-    fn try_into(self) -> Result<Transaction> {
-        let id = self.id;
+    fn try_into(self) -> Result<Transaction, TransactionError> {
+        let (id, kind) = (self.id, self.kind);
         // In our parsing logic we have made sure that this should never happen, but this sanity
         // check is still worthwhile, because someone could remove the verification that happens
         // during parsing.
-        let err = || eyre!("Err for trx {id}, using transactions require an amount!");
+        let err = || TransactionError::MissingAmount { tx: id, kind };
         let trx = Transaction {
             id,
             kind: self.kind,
             client: self.client,
             amount: self.amount.ok_or_else(err)?,
             status: TransactionStatus::Ok,
+            currency: self.currency,
         };
         Ok(trx)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
-#[serde(rename_all = "lowercase")]
+/// The kind of a transaction. It is serialized and displayed as its lowercase name, and
+/// deserialized and parsed from its name in any casing, since our partners are not consistent
+/// about the casing of the `type` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// Reactivates an account that was locked by a chargeback.
+    Unlock,
+    /// Credits the account with accrued interest. Unlike a deposit, this is not initiated by the
+    /// client, so by default it can not be disputed, see [Disputable](crate::account::Disputable).
+    Interest,
+    /// Debits the account with a service fee. Unlike a withdrawal, this may never overdraw the
+    /// account, and by default it can not be disputed.
+    Fee,
+    /// Corrects the balance of the account by hand, for example after a reconciliation error. This
+    /// is the only kind with a signed amount, which is credited to or debited from the account,
+    /// and by default it can not be disputed.
+    Adjustment,
+    /// Moves the amount from the account of the client to the account of another client, see
+    /// [Mutation::to_client]. Both accounts change at once or neither does, and since a transfer
+    /// concerns two clients, it can not be disputed.
+    Transfer,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl TransactionType {
+    /// Every transaction type along with its name, which is the single source of truth for
+    /// converting between the two.
+    pub(crate) const NAMES: [(TransactionType, &'static str); 10] = [
+        (TransactionType::Deposit, "deposit"),
+        (TransactionType::Withdrawal, "withdrawal"),
+        (TransactionType::Dispute, "dispute"),
+        (TransactionType::Resolve, "resolve"),
+        (TransactionType::Chargeback, "chargeback"),
+        (TransactionType::Unlock, "unlock"),
+        (TransactionType::Interest, "interest"),
+        (TransactionType::Fee, "fee"),
+        (TransactionType::Adjustment, "adjustment"),
+        (TransactionType::Transfer, "transfer"),
+    ];
+
+    /// Looks up the transaction type by its name, ignoring the casing.
+    fn from_name(name: &str) -> Option<Self> {
+        (Self::NAMES.iter())
+            .find(|(_, known)| known.eq_ignore_ascii_case(name))
+            .map(|&(kind, _)| kind)
+    }
+
+    /// The lowercase name of the transaction type.
+    pub fn name(self) -> &'static str {
+        (Self::NAMES.iter())
+            .find(|(kind, _)| *kind == self)
+            .map_or("", |(_, name)| *name)
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = UnknownTransactionType;
+
+    fn from_str(s: &str) -> Result<Self, UnknownTransactionType> {
+        Self::from_name(s).ok_or_else(|| UnknownTransactionType(s.to_string()))
+    }
+}
+
+impl serde::Serialize for TransactionType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// We deserialize through a visitor rather than through an owned `String`, so that parsing a row
+/// does not allocate. An unknown name is reported as an [UnknownTransactionType], which names the
+/// offending value, rather than as serde's generic invalid value.
+impl<'de> serde::Deserialize<'de> for TransactionType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let names: Vec<_> = TransactionType::NAMES.iter().map(|(_, n)| *n).collect();
+                write!(f, "one of {}", names.join(", "))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                TransactionType::from_name(v)
+                    .ok_or_else(|| E::custom(UnknownTransactionType(v.to_string())))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum TransactionStatus {
     Ok,
     Disputed,
     Resolved,
     Refunded,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_round_trip() {
+        for (kind, name) in TransactionType::NAMES {
+            assert_eq!(name.parse::<TransactionType>().unwrap().to_string(), name);
+            assert_eq!(name.parse::<TransactionType>(), Ok(kind));
+            // Serde agrees with the names.
+            assert_eq!(serde_json::to_string(&kind).unwrap(), format!("\"{name}\""));
+        }
+        assert_eq!("ChargeBack".parse(), Ok(TransactionType::Chargeback));
+        assert_eq!(
+            "refund".parse::<TransactionType>().unwrap_err().to_string(),
+            "Unknown transaction type refund, expected one of deposit, withdrawal, dispute, \
+            resolve, chargeback, unlock, interest, fee, adjustment, transfer"
+        );
+    }
+}
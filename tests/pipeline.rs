@@ -0,0 +1,232 @@
+//! End to end tests of the engine, which feed a csv to the same pipeline that the binary uses, and
+//! assert on the csv that it writes.
+
+use payments::{
+    engine::{self, EngineConfig, Incident, IncidentKind},
+    source,
+    state::EngineState,
+};
+use std::fs;
+
+/// Runs `input` through the engine with `config`, and returns the written csv along with the
+/// incidents.
+fn run(input: &str, config: &EngineConfig) -> (String, Vec<Incident>) {
+    let mut output = Vec::new();
+    let incidents = engine::process(input.as_bytes(), &mut output, config).unwrap();
+    (String::from_utf8(output).unwrap(), incidents)
+}
+
+#[test]
+fn test_full_lifecycle() {
+    let input = "type, client, tx, amount\n\
+        deposit, 1, 1, 10.0\n\
+        deposit, 2, 2, 5.0\n\
+        withdrawal, 1, 3, 2.5\n\
+        deposit, 1, 4, 1.25\n\
+        dispute, 1, 4,\n\
+        dispute, 2, 2,\n\
+        resolve, 2, 2,\n\
+        withdrawal, 2, 5, 1.0\n\
+        chargeback, 1, 4,\n\
+        deposit, 1, 6, 3.0\n";
+    let config = EngineConfig {
+        lenient: true,
+        ..Default::default()
+    };
+    let (output, incidents) = run(input, &config);
+    // The chargeback locks client 1, so the final deposit is rejected.
+    assert_eq!(incidents.len(), 1);
+    assert_eq!(incidents[0].tx, Some(6));
+    assert_eq!(
+        output,
+        "client,available,held,total,locked,open_disputes\n\
+        1,7.5000,0.0000,7.5000,true,0\n\
+        2,4.0000,0.0000,4.0000,false,0\n"
+    );
+}
+
+#[test]
+fn test_ignored_rows() {
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,1.0\n\
+        dispute,1,9,\n\
+        resolve,1,1,\n\
+        withdrawal,1,2,2.0\n";
+    let (output, incidents) = run(input, &EngineConfig::default());
+    let ignored: Vec<_> = incidents.iter().map(|i| i.tx).collect();
+    assert_eq!(ignored, [Some(9), Some(1), Some(2)]);
+    assert_eq!(
+        output,
+        "client,available,held,total,locked,open_disputes\n\
+        1,1.0000,0.0000,1.0000,false,0\n"
+    );
+}
+
+#[test]
+fn test_strict_failure() {
+    // An identical row would be skipped as a replay, but a different amount is a duplicate id.
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,1.0\n\
+        deposit,1,1,2.0\n";
+    let mut output = Vec::new();
+    let result = engine::process(input.as_bytes(), &mut output, &EngineConfig::default());
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_directory() {
+    // The files are listed out of order, and a dispute in the last file references a deposit in
+    // the first.
+    let dir = std::env::temp_dir().join(format!("payments-test-dir-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let files = [
+        ("2022-06-01T11.csv", "type,client,tx,amount\ndispute,1,1,\n"),
+        (
+            "2022-06-01T09.csv",
+            "type,client,tx,amount\ndeposit,1,1,3.0\n",
+        ),
+        (
+            "2022-06-01T10.csv",
+            "type,client,tx,amount\ndeposit,1,2,1.0\n",
+        ),
+        ("notes.txt", "not a csv"),
+    ];
+    for (name, contents) in files {
+        fs::write(dir.join(name), contents).unwrap();
+    }
+
+    assert!(source::csv_files(&dir, false).is_err());
+    let paths = source::csv_files(&dir, true).unwrap();
+    let names: Vec<_> = (paths.iter())
+        .map(|path| path.file_name().unwrap().to_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "2022-06-01T09.csv",
+            "2022-06-01T10.csv",
+            "2022-06-01T11.csv"
+        ]
+    );
+
+    let config = EngineConfig::default();
+    let mut state = EngineState::default();
+    for path in &paths {
+        let incidents = engine::ingest(&mut state, fs::File::open(path).unwrap(), &config).unwrap();
+        assert!(incidents.is_empty());
+    }
+    let mut output = Vec::new();
+    engine::write_accounts(state, &mut output, &config).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked,open_disputes\n\
+        1,1.0000,3.0000,4.0000,false,1\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_header_only_input() {
+    // An input without rows is valid, and results in an output without rows.
+    let (output, incidents) = run("type,client,tx,amount\n", &EngineConfig::default());
+    assert!(incidents.is_empty());
+    assert_eq!(output, "client,available,held,total,locked,open_disputes\n");
+
+    let config = EngineConfig {
+        stats: true,
+        ..Default::default()
+    };
+    let (output, _) = run("type,client,tx,amount", &config);
+    assert_eq!(
+        output,
+        "client,available,held,total,locked,open_disputes,deposits,withdrawals,deposited\n"
+    );
+}
+
+#[test]
+fn test_empty_input() {
+    // An input without even a header is rejected, since it likely is a failed export.
+    let mut output = Vec::new();
+    let err = engine::process("".as_bytes(), &mut output, &EngineConfig::default()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The input is empty, it is missing a header with the columns \
+        [\"type\", \"client\", \"tx\", \"amount\"]"
+    );
+    assert!(output.is_empty());
+}
+
+#[test]
+fn test_resume_after_crash() {
+    let rows = [
+        "deposit,1,1,10.0",
+        "deposit,1,2,2.0",
+        "dispute,1,1,",
+        "resolve,1,1,",
+        "dispute,1,2,",
+        "deposit,2,3,5.0",
+    ];
+    let input = |rows: &[&str]| format!("type,client,tx,amount\n{}\n", rows.join("\n"));
+    let config = EngineConfig::default();
+    let write = |state: &EngineState| {
+        let mut snapshot = Vec::new();
+        state.write_snapshot(&mut snapshot).unwrap();
+        snapshot
+    };
+    let before = write(&EngineState::default());
+
+    // The process crashes after the first four rows, before it writes its snapshot, so the state
+    // that it applied them to is lost.
+    let mut state = EngineState::read_snapshot(before.as_slice()).unwrap();
+    engine::ingest_with_log(&mut state, input(&rows[..4]).as_bytes(), &config).unwrap();
+    drop(state);
+
+    // The retry resumes from the snapshot that was taken before the crash, which does not know of
+    // the rows either, so all of them are applied.
+    let mut state = EngineState::read_snapshot(before.as_slice()).unwrap();
+    let (incidents, summary, _) =
+        engine::ingest_with_log(&mut state, input(&rows).as_bytes(), &config).unwrap();
+    assert!(incidents.is_empty());
+    assert_eq!(summary.ignored, 0);
+    let after = write(&state);
+
+    // A pipeline that delivers the input again after the snapshot was written has every row
+    // skipped. Without the log, the resolved deposit would be disputed a second time.
+    let mut state = EngineState::read_snapshot(after.as_slice()).unwrap();
+    let (incidents, summary, _) =
+        engine::ingest_with_log(&mut state, input(&rows).as_bytes(), &config).unwrap();
+    assert_eq!(summary.ignored, rows.len() as u64);
+    assert!(incidents.iter().all(|i| i.kind == IncidentKind::Ignored));
+    let skipped: Vec<_> = incidents.iter().map(|i| i.tx).collect();
+    assert_eq!(skipped, [1, 2, 1, 1, 2, 3].map(Some));
+
+    let mut output = Vec::new();
+    engine::write_accounts(state, &mut output, &config).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked,open_disputes\n\
+        1,10.0000,2.0000,12.0000,false,1\n\
+        2,5.0000,0.0000,5.0000,false,0\n"
+    );
+}
+
+#[test]
+fn test_large_client_ids() {
+    let input = "type,client,tx,amount\n\
+        deposit,70000,1,1.0\n\
+        deposit,4294967295,2,2.0\n\
+        dispute,70000,1,\n";
+    let (output, incidents) = run(input, &EngineConfig::default());
+    assert!(incidents.is_empty());
+    assert_eq!(
+        output,
+        "client,available,held,total,locked,open_disputes\n\
+        70000,0.0000,1.0000,1.0000,false,1\n\
+        4294967295,2.0000,0.0000,2.0000,false,0\n"
+    );
+
+    // Beyond the range of a client id the row can not be read.
+    let input = "type,client,tx,amount\ndeposit,4294967296,1,1.0\n";
+    let mut output = Vec::new();
+    assert!(engine::process(input.as_bytes(), &mut output, &EngineConfig::default()).is_err());
+}